@@ -1,91 +1,59 @@
-use crate::{send_command, ArchivedDay, Config, FromClient, Stats};
-use chrono::{prelude::*, Duration};
+use crate::{
+    events, send_command, verify, Aggregate, ArchivedDay, Config, ControllerStats,
+    FromClient, Stats,
+};
+use anyhow::{Error, Result};
+use chrono::{prelude::*, Duration, LocalResult};
 use libflate::{
     gzip::{Decoder, EncodeOptions, Encoder},
     lz77::DefaultLz77Encoder,
 };
 use morningstar::prostar_mppt as ps;
 use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    convert::TryFrom,
     error,
     ffi::OsStr,
+    fmt,
     fs::{self, OpenOptions},
     io::{self, BufRead, BufReader, LineWriter, Read, Write},
     iter::{self, Iterator},
+    mem,
     path::{Path, PathBuf},
+    str::FromStr,
 };
 
-macro_rules! avg {
-    ($r0:ident, $r1:ident, $( $fld:ident ),*) => {
-        $(
-            $r0.$fld = ($r0.$fld + $r1.$fld) / 2.;
-        )*
-    }
-}
-
-macro_rules! max {
-    ($r0:ident, $r1:ident, $( $fld: ident ),*) => {
+// the fields that are averaged over a window, they also have a
+// minimum and maximum. Everything else, counters, daily values,
+// states, and faults, is taken from the latest sample.
+macro_rules! mean_min_max {
+    ($acc:ident, $prev:ident, $s:ident, $w:ident, $( $fld:ident ),*) => {
         $(
-            $r0.$fld = max($r0.$fld, $r1.$fld);
+            $acc.mean.$fld.value =
+                $prev.mean.$fld.value + ($s.mean.$fld.value - $prev.mean.$fld.value) * $w;
+            $acc.min.$fld = $prev.min.$fld.min($s.min.$fld);
+            $acc.max.$fld = $prev.max.$fld.max($s.max.$fld);
         )*
     }
 }
 
-macro_rules! maxf {
-    ($r0:ident, $r1:ident, $( $fld: ident ),*) => {
-        $(
-            $r0.$fld = $r0.$fld.max($r1.$fld);
-        )*
-    }
+#[derive(Clone, Copy)]
+struct PsWindow {
+    mean: ps::Stats,
+    min: ps::Stats,
+    max: ps::Stats,
 }
 
-fn ps_stats_accum(acc: &mut ps::Stats, s: &ps::Stats) {
-    use std::cmp::max;
-    acc.battery_v_min_daily = acc.battery_v_min_daily.min(s.battery_v_min_daily);
-    acc.rts_temperature = match (acc.rts_temperature, s.rts_temperature) {
-        (None, None) => None,
-        (Some(t), None) => Some(t),
-        (None, Some(t)) => Some(t),
-        (Some(t0), Some(t1)) => Some(t0.max(t1)),
-    };
-    maxf!(
-        acc,
-        s,
-        heatsink_temperature,
-        battery_temperature,
-        ambient_temperature,
-        u_inductor_temperature,
-        v_inductor_temperature,
-        w_inductor_temperature,
-        ah_charge_resettable,
-        ah_charge_total,
-        kwh_charge_resettable,
-        kwh_charge_total,
-        ah_load_resettable,
-        ah_load_total,
-        battery_v_max_daily,
-        ah_charge_daily,
-        ah_load_daily,
-        array_voltage_max_daily,
-        hourmeter
-    );
-    max!(
-        acc,
-        s,
-        timestamp,
-        software_version,
-        battery_voltage_settings_multiplier,
-        array_faults,
-        load_faults,
-        alarms,
-        array_faults_daily,
-        load_faults_daily,
-        alarms_daily,
-        charge_state,
-        load_state
-    );
-    avg!(
+// merge the later window s into acc, w is the share of the samples
+// that are in s
+fn ps_stats_accum(acc: &mut PsWindow, s: &PsWindow, w: f32) {
+    let prev = *acc;
+    *acc = *s;
+    mean_min_max!(
         acc,
+        prev,
         s,
+        w,
         supply_3v3,
         supply_12v,
         supply_5v,
@@ -99,6 +67,12 @@ fn ps_stats_accum(acc: &mut ps::Stats, s: &ps::Stats) {
         battery_current_net,
         battery_sense_voltage,
         meterbus_voltage,
+        heatsink_temperature,
+        battery_temperature,
+        ambient_temperature,
+        u_inductor_temperature,
+        v_inductor_temperature,
+        w_inductor_temperature,
         battery_voltage_slow,
         target_voltage,
         lvd_setpoint,
@@ -106,40 +80,262 @@ fn ps_stats_accum(acc: &mut ps::Stats, s: &ps::Stats) {
         array_vmp,
         array_max_power_sweep,
         array_voc,
-        array_voltage_fixed,
-        array_voc_percent_fixed
+        array_voltage_fixed
     );
+    let (m0, m1) = (prev.mean.array_voc_percent_fixed, s.mean.array_voc_percent_fixed);
+    acc.mean.array_voc_percent_fixed = m0 + (m1 - m0) * w;
+    acc.min.array_voc_percent_fixed =
+        prev.min.array_voc_percent_fixed.min(s.min.array_voc_percent_fixed);
+    acc.max.array_voc_percent_fixed =
+        prev.max.array_voc_percent_fixed.max(s.max.array_voc_percent_fixed);
+    acc.mean.rts_temperature = match (prev.mean.rts_temperature, s.mean.rts_temperature) {
+        (Some(mut t0), Some(t1)) => {
+            t0.value += (t1.value - t0.value) * w;
+            Some(t0)
+        }
+        (t0, t1) => t1.or(t0),
+    };
+    acc.min.rts_temperature = match (prev.min.rts_temperature, s.min.rts_temperature) {
+        (Some(t0), Some(t1)) => Some(t0.min(t1)),
+        (t0, t1) => t1.or(t0),
+    };
+    acc.max.rts_temperature = match (prev.max.rts_temperature, s.max.rts_temperature) {
+        (Some(t0), Some(t1)) => Some(t0.max(t1)),
+        (t0, t1) => t1.or(t0),
+    };
+}
+
+fn controller_stats_accum(
+    acc: &mut ControllerStats,
+    agg: &mut Aggregate,
+    s: &ControllerStats,
+    s_agg: &Aggregate,
+) {
+    let w = s_agg.samples as f32 / (agg.samples + s_agg.samples) as f32;
+    match (acc, &mut agg.min, &mut agg.max, s, &s_agg.min, &s_agg.max) {
+        (
+            ControllerStats::ProstarMppt(mean),
+            ControllerStats::ProstarMppt(min),
+            ControllerStats::ProstarMppt(max),
+            ControllerStats::ProstarMppt(s_mean),
+            ControllerStats::ProstarMppt(s_min),
+            ControllerStats::ProstarMppt(s_max),
+        ) => {
+            let mut window = PsWindow { mean: *mean, min: *min, max: *max };
+            let s = PsWindow { mean: *s_mean, min: *s_min, max: *s_max };
+            ps_stats_accum(&mut window, &s, w);
+            *mean = window.mean;
+            *min = window.min;
+            *max = window.max;
+        }
+    }
+    agg.samples += s_agg.samples;
 }
 
+/// Accumulate s, a sample or an aggregate, into the aggregate acc.
+/// Samples where the controller couldn't be read are skipped.
 pub fn stats_accum(acc: &mut Stats, s: &Stats) {
-    match s {
-        Stats::V2 { controller: None, .. } | Stats::V3 {controller: None, ..} => (),
-        Stats::V0(ref cs)
-        | Stats::V1 { controller: ref cs, .. }
-        | Stats::V2 { controller: Some(ref cs), .. }
-        | Stats::V3 { controller: Some(ref cs), .. } => {
-            let ps_acc = match acc {
-                Stats::V0(ref mut a)
-                | Stats::V1 { controller: ref mut a, .. }
-                | Stats::V2 { controller: Some(ref mut a), .. }
-                | Stats::V3 { controller: Some(ref mut a), .. } => a,
-                Stats::V2 { ref mut timestamp, ref mut controller, .. }
-                | Stats::V3 { ref mut timestamp, ref mut controller } => {
-                    // there are no accumulated stats yet, so no need to aggregate them
-                    *controller = Some(*cs);
-                    *timestamp = s.timestamp();
-                    return;
+    if !matches!(acc, Stats::V6 { .. }) {
+        *acc = acc.clone().upgrade();
+    }
+    match (acc, s.clone().upgrade()) {
+        (
+            Stats::V6 { timestamp, controller, aggregate, .. },
+            Stats::V6 { timestamp: ts, controller: Some(cs), aggregate: s_agg, .. },
+        ) => {
+            let s_agg = s_agg.unwrap_or_else(|| Aggregate::new(&cs));
+            match controller {
+                Some(a) => {
+                    let agg = aggregate.get_or_insert_with(|| Aggregate::new(a));
+                    controller_stats_accum(a, agg, &cs, &s_agg)
+                }
+                // there are no accumulated stats yet, so no need to aggregate them
+                None => {
+                    *controller = Some(cs);
+                    *aggregate = Some(s_agg);
+                }
+            }
+            *timestamp = ts;
+        }
+        (_, _) => (),
+    }
+}
+
+// a window holding only s
+fn window(s: Stats) -> Stats {
+    match s.upgrade() {
+        Stats::V6 { timestamp, name, controller: Some(cs), aggregate: None } => {
+            let aggregate = Some(Aggregate::new(&cs));
+            Stats::V6 { timestamp, name, controller: Some(cs), aggregate }
+        }
+        s => s,
+    }
+}
+
+/// The size of the buckets stats are aggregated into, written as a
+/// number followed by s, m, h, or d, e.g. 10m. A bucket is at most a
+/// day long.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Bucket(Duration);
+
+impl Bucket {
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl FromStr for Bucket {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (n, unit) =
+            s.split_at(s.len() - s.chars().last().map_or(0, |c| c.len_utf8()));
+        let n = n.parse::<i64>().map_err(|_| anyhow!("invalid bucket size {}", s))?;
+        let d = match unit {
+            "s" => Duration::seconds(n),
+            "m" => Duration::minutes(n),
+            "h" => Duration::hours(n),
+            "d" => Duration::days(n),
+            _ => bail!("invalid bucket size {}, expected e.g. 30s, 10m, 1h, or 1d", s),
+        };
+        if d <= Duration::zero() || d > Duration::days(1) {
+            bail!("bucket size {} must be more than zero and at most a day", s)
+        }
+        Ok(Bucket(d))
+    }
+}
+
+impl TryFrom<String> for Bucket {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Bucket> for String {
+    fn from(b: Bucket) -> String {
+        b.to_string()
+    }
+}
+
+// the largest whole unit, this is also the suffix of the archive file
+impl fmt::Display for Bucket {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.num_seconds();
+        if secs % 86400 == 0 {
+            write!(fmt, "{}d", secs / 86400)
+        } else if secs % 3600 == 0 {
+            write!(fmt, "{}h", secs / 3600)
+        } else if secs % 60 == 0 {
+            write!(fmt, "{}m", secs / 60)
+        } else {
+            write!(fmt, "{}s", secs)
+        }
+    }
+}
+
+// a gap marker, there were no stats in the bucket starting at timestamp
+fn gap(name: String, timestamp: DateTime<Local>) -> Stats {
+    Stats::V6 { timestamp, name, controller: None, aggregate: None }
+}
+
+/// Aggregates stats into buckets aligned to the wall clock. Buckets
+/// start at local midnight and are laid out on the local time of day,
+/// so sizes that divide a day give evenly spaced buckets, e.g. 00:00,
+/// 00:10, 00:20 for 10 minutes, on either side of a daylight savings
+/// change. Each controller is aggregated separately, an aggregate is
+/// stamped with the start of it's bucket. Every bucket with no stats
+/// gets a gap marker, stats with no controller, stamped with the start
+/// of the bucket.
+struct Buckets {
+    size: Duration,
+    windows: Vec<(DateTime<Local>, Stats)>,
+}
+
+impl Buckets {
+    fn new(size: Duration) -> Self {
+        Buckets { size, windows: Vec::new() }
+    }
+
+    // the start of the bucket holding ts
+    fn bucket(&self, ts: DateTime<Local>) -> DateTime<Local> {
+        let t = ts.naive_local();
+        let midnight = t.date().and_hms(0, 0, 0);
+        let size = self.size.num_seconds().max(1);
+        let offset = (t - midnight).num_seconds();
+        let start = midnight + Duration::seconds(offset - offset % size);
+        match Local.from_local_datetime(&start) {
+            LocalResult::Single(start) => start,
+            // the clock went back, the bucket started at the last
+            // time the clock read start
+            LocalResult::Ambiguous(a, b) => {
+                if b <= ts {
+                    b
+                } else {
+                    a
+                }
+            }
+            // the clock skipped start, the bucket starts where it would
+            // have been
+            LocalResult::None => match ts.offset().from_local_datetime(&start) {
+                LocalResult::Single(start) => start.with_timezone(&Local),
+                _ => ts,
+            },
+        }
+    }
+
+    // the start of the bucket after the one starting at start
+    fn succ(&self, start: DateTime<Local>) -> DateTime<Local> {
+        let next = self.bucket(start + self.size);
+        if next > start {
+            next
+        } else {
+            // a day long bucket on the day the clock went back
+            self.bucket(start + self.size + Duration::hours(1))
+        }
+    }
+
+    // add s to the window for it's controller, finished windows and
+    // gap markers are pushed to ready
+    fn add(&mut self, s: Stats, ready: &mut VecDeque<Stats>) {
+        let start = self.bucket(s.timestamp());
+        match self.windows.iter().position(|(_, acc)| acc.name() == s.name()) {
+            None => self.windows.push((start, window(s))),
+            // out of order stats go in the current window
+            Some(i) if start <= self.windows[i].0 => {
+                stats_accum(&mut self.windows[i].1, &s)
+            }
+            Some(i) => {
+                let (cur, mut acc) =
+                    mem::replace(&mut self.windows[i], (start, window(s)));
+                *acc.timestamp_mut() = cur;
+                let name = String::from(acc.name());
+                ready.push_back(acc);
+                let mut next = self.succ(cur);
+                while next < start {
+                    ready.push_back(gap(name.clone(), next));
+                    next = self.succ(next)
                 }
-            };
-            ps_stats_accum(ps_acc, cs);
-            *acc.timestamp_mut() = s.timestamp();
+            }
+        }
+    }
+
+    // flush the windows that are still open
+    fn finish(&mut self, ready: &mut VecDeque<Stats>) {
+        for (start, mut acc) in self.windows.drain(..) {
+            *acc.timestamp_mut() = start;
+            ready.push_back(acc)
         }
     }
 }
 
 pub struct Decimate<I> {
-    acc: Option<(DateTime<Local>, Stats)>,
-    cutoff: Duration,
+    buckets: Buckets,
+    ready: VecDeque<Stats>,
+    done: bool,
     iter: I,
 }
 
@@ -151,80 +347,170 @@ where
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
+            if let Some(s) = self.ready.pop_front() {
+                return Some(s);
+            }
+            if self.done {
+                return None;
+            }
             match self.iter.next() {
-                None => return self.acc.take().map(|(_, s)| s),
-                Some(st) => {
-                    self.acc = match self.acc {
-                        None => Some((st.timestamp(), st)),
-                        Some((ts, mut acc)) => {
-                            stats_accum(&mut acc, &st);
-                            Some((ts, acc))
-                        }
-                    };
-                    let (ts, acc) = self.acc.unwrap();
-                    if acc.timestamp() - ts >= self.cutoff {
-                        self.acc = None;
-                        return Some(acc);
-                    }
+                None => {
+                    self.done = true;
+                    self.buckets.finish(&mut self.ready)
                 }
+                Some(st) => self.buckets.add(st, &mut self.ready),
             }
         }
     }
 }
 
-pub fn decimate<I>(cutoff: Duration, iter: I) -> Decimate<I>
+/// Aggregate stats into wall clock aligned buckets of the given size,
+/// with gap markers where there are no stats, see `Buckets`.
+pub fn decimate<I>(bucket: Bucket, iter: I) -> Decimate<I>
 where
     I: Iterator<Item = Stats>,
 {
-    Decimate { cutoff, iter, acc: None }
+    let buckets = Buckets::new(bucket.duration());
+    Decimate { buckets, ready: VecDeque::new(), done: false, iter }
+}
+
+/// The temporary file an archive is written to before it's renamed
+/// into place
+pub fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    PathBuf::from(tmp)
+}
+
+// an archive file is written under a temporary name and renamed into
+// place when it is complete, so a crash never leaves a partial archive
+pub(crate) struct ArchiveWriter {
+    path: PathBuf,
+    tmp: PathBuf,
+    enc: LineWriter<Encoder<fs::File>>,
 }
 
-fn open_archive(path: &Path) -> LineWriter<Encoder<fs::File>> {
-    LineWriter::new(
-        Encoder::with_options(
-            OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(path)
-                .expect("failed to open archive file"),
+impl ArchiveWriter {
+    pub(crate) fn create(path: &Path) -> io::Result<Self> {
+        let tmp = tmp_path(path);
+        let file =
+            OpenOptions::new().write(true).create(true).truncate(true).open(&tmp)?;
+        let enc = Encoder::with_options(
+            file,
             EncodeOptions::with_lz77(DefaultLz77Encoder::with_window_size(65534)),
-        )
-        .expect("failed to create gzip encoder {}"),
-    )
-}
-
-fn update_accum(
-    acc: Option<(DateTime<Local>, Stats)>,
-    s: Stats,
-    cutoff: Duration,
-    writer: &mut LineWriter<Encoder<fs::File>>,
-) -> Option<(DateTime<Local>, Stats)> {
-    match acc {
-        None => Some((s.timestamp(), s)),
-        Some((ts, mut acc)) => {
-            stats_accum(&mut acc, &s);
-            if acc.timestamp() - ts < cutoff {
-                Some((ts, acc))
-            } else {
-                serde_json::to_writer(writer.by_ref(), &acc)
-                    .expect("failed to write stats");
-                write!(writer, "\n").expect("failed to write newline");
-                None
+        )?;
+        Ok(ArchiveWriter { path: path.to_path_buf(), tmp, enc: LineWriter::new(enc) })
+    }
+
+    pub(crate) fn write<T: serde::Serialize>(&mut self, v: &T) -> io::Result<()> {
+        serde_json::to_writer(self.enc.by_ref(), v)?;
+        self.enc.write_all(b"\n")
+    }
+
+    // complete the archive under its temporary name, returns the
+    // temporary name and the name to rename it to
+    pub(crate) fn close(self) -> io::Result<(PathBuf, PathBuf)> {
+        let enc = self
+            .enc
+            .into_inner()
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+        enc.finish().into_result()?.sync_all()?;
+        Ok((self.tmp, self.path))
+    }
+
+    pub(crate) fn finish(self) -> io::Result<()> {
+        let (tmp, path) = self.close()?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+fn write_ready(
+    ready: &mut VecDeque<Stats>,
+    writer: &mut ArchiveWriter,
+) -> io::Result<()> {
+    for s in ready.drain(..) {
+        writer.write(&s)?
+    }
+    Ok(())
+}
+
+// write the raw stats to all, if specified, and their aggregates.
+// Nothing is renamed into place until every archive is complete, if
+// any of them fails the temporary files are removed.
+pub(crate) fn write_archive(
+    stats: impl Iterator<Item = Stats>,
+    all: Option<&Path>,
+    aggregates: &[(Bucket, PathBuf)],
+) -> io::Result<()> {
+    match write_archive_tmp(stats, all, aggregates) {
+        Ok(closed) => {
+            for (tmp, path) in closed {
+                fs::rename(&tmp, &path)?
             }
+            Ok(())
+        }
+        Err(e) => {
+            let paths =
+                all.into_iter().chain(aggregates.iter().map(|(_, p)| p.as_path()));
+            for path in paths {
+                let _ = fs::remove_file(tmp_path(path));
+            }
+            Err(e)
         }
     }
 }
 
-fn close_encoder(enc: LineWriter<Encoder<fs::File>>) {
-    match enc.into_inner() {
-        Ok(e) => {
-            e.finish().into_result().expect("failed to close gzip stream");
+fn write_archive_tmp(
+    stats: impl Iterator<Item = Stats>,
+    all: Option<&Path>,
+    aggregates: &[(Bucket, PathBuf)],
+) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut all = all.map(ArchiveWriter::create).transpose()?;
+    let mut aggregates = aggregates
+        .iter()
+        .map(|(b, path)| Ok((Buckets::new(b.duration()), ArchiveWriter::create(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut ready = VecDeque::new();
+    for s in stats {
+        if let Some(w) = &mut all {
+            w.write(&s)?
+        }
+        for (buckets, w) in &mut aggregates {
+            buckets.add(s.clone(), &mut ready);
+            write_ready(&mut ready, w)?
         }
-        Err(_) => panic!("close encoder failed"),
     }
+    for (buckets, w) in &mut aggregates {
+        buckets.finish(&mut ready);
+        write_ready(&mut ready, w)?
+    }
+    let mut closed = Vec::new();
+    if let Some(w) = all {
+        closed.push(w.close()?)
+    }
+    for (_, w) in aggregates {
+        closed.push(w.close()?)
+    }
+    Ok(closed)
 }
 
-fn read_history_file(
+// a line of a log, stats of any version, or the bare stats written
+// before versioning, upgraded to the latest version
+pub(crate) fn parse_stats(line: &str) -> serde_json::Result<Stats> {
+    match serde_json::from_str::<Stats>(line) {
+        Ok(s) => Ok(s.upgrade()),
+        Err(e) => match serde_json::from_str::<ps::Stats>(line) {
+            Ok(s) => Ok(Stats::V0(s).upgrade()),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+/// Read the stats in a log file, an archive if the file ends in .gz,
+/// or stdin if the file is -. Older versions of `Stats` are upgraded
+/// to the latest one. Reading stops at the first line that can't be
+/// parsed.
+pub fn read_history_file(
     file: PathBuf,
 ) -> Result<impl Iterator<Item = Stats>, Box<dyn error::Error>> {
     use serde_json::error::Category;
@@ -249,52 +535,85 @@ fn read_history_file(
                 return None;
             }
         }
-        match serde_json::from_str::<Stats>(&sbuf) {
+        match parse_stats(&sbuf) {
             Ok(o) => {
                 sbuf.clear();
-                Some(o.upgrade())
+                Some(o)
+            }
+            Err(e) => {
+                match e.classify() {
+                    Category::Io | Category::Eof => (),
+                    Category::Syntax => error!(
+                        "syntax error in log archive, parsing terminated: {:?}, {}",
+                        file, e
+                    ),
+                    Category::Data => error!(
+                        "semantic error in log archive, parsing terminated: {:?}, {}",
+                        file, e
+                    ),
+                };
+                None
             }
-            Err(e) => match serde_json::from_str::<ps::Stats>(&sbuf) {
-                Ok(o) => {
-                    sbuf.clear();
-                    Some(Stats::V0(o).upgrade())
-                }
-                Err(_) => {
-                    match e.classify() {
-                        Category::Io | Category::Eof => (),
-                        Category::Syntax => error!(
-                            "syntax error in log archive, parsing terminated: {:?}, {}",
-                            file, e
-                        ),
-                        Category::Data => error!(
-                            "semantic error in log archive, parsing terminated: {:?}, {}",
-                            file, e
-                        ),
-                    };
-                    None
-                }
-            },
         }
     }))
 }
 
 fn do_archive_log_file(file: PathBuf, archive: &ArchivedDay) {
-    let one_minute = Duration::seconds(60);
-    let ten_minutes = Duration::seconds(600);
-    let mut enc = open_archive(&archive.all);
-    let mut enc_1m = open_archive(&archive.one_minute_averages);
-    let mut enc_10m = open_archive(&archive.ten_minute_averages);
-    let mut acc_1m: Option<(DateTime<Local>, Stats)> = None;
-    let mut acc_10m: Option<(DateTime<Local>, Stats)> = None;
-    for s in read_history_file(file).expect("failed to open archive file") {
-        acc_1m = update_accum(acc_1m, s, one_minute, &mut enc_1m);
-        acc_10m = update_accum(acc_10m, s, ten_minutes, &mut enc_10m);
-        serde_json::to_writer(enc.by_ref(), &s).expect("failed to encode");
-        write!(&mut enc, "\n").expect("failed to write newline");
-    }
-    close_encoder(enc);
-    close_encoder(enc_1m);
-    close_encoder(enc_10m);
+    let stats = read_history_file(file).expect("failed to open archive file");
+    write_archive(stats, Some(&archive.all), &archive.aggregates)
+        .expect("failed to write archive")
+}
+
+/// Derive the aggregates at the given resolutions again from the raw
+/// archives of the days between from and to inclusive, replacing the
+/// aggregates that exist. Returns the days with no raw archive. Fails
+/// on a raw archive that can't be read to the end, it must be repaired
+/// first.
+pub fn rebuild(
+    cfg: &Config,
+    from: NaiveDate,
+    to: NaiveDate,
+    resolutions: &[Bucket],
+) -> Result<Vec<NaiveDate>> {
+    let mut missing = Vec::new();
+    let mut day = from;
+    while day <= to {
+        match Local.from_local_date(&day).earliest() {
+            None => missing.push(day),
+            Some(d) => {
+                let all = cfg.archive_for_date(d).all;
+                if !ArchivedDay::file_exists(&all)? {
+                    missing.push(day)
+                } else {
+                    // aggregates of the stats before the damage would
+                    // look complete
+                    if let Some(p) = verify::stats_unreadable(&all)? {
+                        bail!(
+                            "{} is damaged, {}, repair it with solar archive verify --repair",
+                            all.display(),
+                            p
+                        )
+                    }
+                    info!("rebuilding the aggregates for {}", day);
+                    let aggregates = resolutions
+                        .iter()
+                        .map(|b| (*b, cfg.aggregate_for_date(d, *b)))
+                        .collect::<Vec<_>>();
+                    let stats = read_history_file(all.clone())
+                        .map_err(|e| anyhow!("failed to open {:?}, {}", all, e))?;
+                    write_archive(stats, None, &aggregates)?
+                }
+            }
+        }
+        day = day.succ();
+    }
+    Ok(missing)
+}
+
+fn events_tmp(cfg: &Config) -> PathBuf {
+    let mut tmp = cfg.event_log();
+    tmp.set_extension("tmp");
+    tmp
 }
 
 pub fn archive_log(cfg: &Config, file: Option<PathBuf>, date: Option<Date<Local>>) {
@@ -306,7 +625,9 @@ pub fn archive_log(cfg: &Config, file: Option<PathBuf>, date: Option<Date<Local>
             (f, is_current_log)
         }
     };
-    if archive.exists().expect("failed to test archive") {
+    if is_current_log && cfg.auto_archive {
+        println!("the daemon archives the log at midnight, see solar archive status");
+    } else if archive.exists().expect("failed to test archive") {
         println!("one or more archive files already exist for today");
     } else {
         let file = {
@@ -318,52 +639,801 @@ pub fn archive_log(cfg: &Config, file: Option<PathBuf>, date: Option<Date<Local>
                 tmp.set_extension("tmp");
                 fs::hard_link(&current, &tmp).expect("failed to create tmp file");
                 fs::remove_file(&current).expect("failed to unlink current file");
-                send_command(&cfg, iter::once(FromClient::LogRotated))
-                    .expect("failed to reopen log file");
+                // the event log rotates with the stats log
+                let events = cfg.event_log();
+                if events.exists() {
+                    fs::hard_link(&events, &events_tmp(cfg))
+                        .expect("failed to create tmp event file");
+                    fs::remove_file(&events).expect("failed to unlink event log");
+                }
+                // if the daemon isn't running there is nothing to reopen
+                if let Err(e) = send_command(&cfg, iter::once(FromClient::LogRotated)) {
+                    println!("failed to tell the daemon to reopen the log {}", e)
+                }
                 tmp
             }
         };
         do_archive_log_file(file.clone(), &archive);
         if is_current_log {
             fs::remove_file(&file).expect("failed to remove tmp file");
+            let tmp = events_tmp(cfg);
+            if tmp.exists() {
+                events::archive(&tmp, &archive.events).expect("failed to archive events");
+                fs::remove_file(&tmp).expect("failed to remove tmp event file");
+            }
         }
     }
 }
 
-pub fn read_history(cfg: &Config, mut days: i64) -> impl Iterator<Item = Stats> + '_ {
-    info!("read history going back {} days", days);
-    let today = Local::today();
-    iter::from_fn(move || {
-        if days <= 0 {
+/// The days with logs the daemon rotated that are not yet archived
+pub fn rotated(cfg: &Config) -> Result<Vec<NaiveDate>> {
+    let mut days = BTreeSet::new();
+    for ent in fs::read_dir(&cfg.run_directory)? {
+        let name = ent?.file_name();
+        let date = name
+            .to_str()
+            .and_then(|n| n.strip_prefix("solar.log-").or(n.strip_prefix("events.log-")))
+            .and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok());
+        if let Some(date) = date {
+            days.insert(date);
+        }
+    }
+    Ok(days.into_iter().collect())
+}
+
+/// Archive the logs of a day the daemon rotated, removing each log
+/// once its archives are written. It's safe to call this again after
+/// it fails. Archives a previous attempt completed are kept, and the
+/// missing or unreadable ones are written again from the log.
+pub fn archive_rotated(cfg: &Config, date: NaiveDate) -> Result<()> {
+    let day = match Local.from_local_date(&date).earliest() {
+        Some(day) => day,
+        None => bail!("invalid local date {}", date),
+    };
+    let archive = cfg.archive_for_date(day);
+    let log = cfg.rotated_log(date);
+    if ArchivedDay::file_exists(&log)? {
+        let all = if verify::stats_intact(&archive.all)? {
             None
         } else {
-            let d = Duration::days(days);
-            days -= 1;
-            Some(
-                today
-                    .checked_sub_signed(d)
-                    .map(|d| cfg.archive_for_date(d).ten_minute_averages)
-                    .and_then(|f| match read_history_file(f.clone()) {
-                        Ok(i) => Some(i),
-                        Err(e) => {
-                            error!("error opening log archive, skipping: {:?}, {}", f, e);
-                            None
-                        }
-                    }),
-            )
+            Some(archive.all.as_path())
+        };
+        let mut aggregates = Vec::new();
+        for (b, path) in &archive.aggregates {
+            if !verify::stats_intact(path)? {
+                aggregates.push((*b, path.clone()))
+            }
         }
-    })
-    .flatten()
-    .flatten()
-    .chain(
-        match read_history_file(cfg.log_file()) {
-            Ok(i) => Some(decimate(Duration::seconds(600), i)),
+        if all.is_some() || !aggregates.is_empty() {
+            let stats = read_history_file(log.clone())
+                .map_err(|e| anyhow!("failed to open {:?}, {}", log, e))?;
+            write_archive(stats, all, &aggregates)?
+        }
+        fs::remove_file(&log)?
+    }
+    let log = cfg.rotated_event_log(date);
+    if ArchivedDay::file_exists(&log)? {
+        if !verify::events_intact(&archive.events)? {
+            if ArchivedDay::file_exists(&archive.events)? {
+                fs::remove_file(&archive.events)?
+            }
+            events::archive(&log, &archive.events)?
+        }
+        fs::remove_file(&log)?
+    }
+    Ok(())
+}
+
+/// A day waiting to be archived
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingDay {
+    pub date: NaiveDate,
+    pub attempts: u32,
+    /// why the last attempt failed
+    pub error: Option<String>,
+    pub next_attempt: DateTime<Local>,
+}
+
+impl fmt::Display for PendingDay {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} pending, next attempt at {}", self.date, self.next_attempt)?;
+        match &self.error {
+            None => Ok(()),
+            Some(e) => {
+                write!(fmt, ", {} attempts failed, the last with {}", self.attempts, e)
+            }
+        }
+    }
+}
+
+/// The state of the daemon's archiver
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ArchiveStatus {
+    pub auto_archive: bool,
+    pub pending: Vec<PendingDay>,
+    /// the last day archived, and when
+    pub last_archived: Option<(NaiveDate, DateTime<Local>)>,
+}
+
+impl fmt::Display for ArchiveStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        if !self.auto_archive {
+            writeln!(fmt, "automatic archiving is off")?
+        }
+        match self.last_archived {
+            None => writeln!(fmt, "nothing archived since the daemon started")?,
+            Some((d, t)) => writeln!(fmt, "{} archived at {}", d, t)?,
+        }
+        for p in &self.pending {
+            writeln!(fmt, "{}", p)?
+        }
+        Ok(())
+    }
+}
+
+/// What an archive file holds. Kinds order from the finest, raw
+/// stats, through the aggregates from the smallest bucket to the
+/// largest, to the events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum ArchiveKind {
+    Raw,
+    Aggregate(Bucket),
+    Events,
+}
+
+impl fmt::Display for ArchiveKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveKind::Raw => write!(fmt, "raw"),
+            ArchiveKind::Aggregate(b) => write!(fmt, "{}", b),
+            ArchiveKind::Events => write!(fmt, "events"),
+        }
+    }
+}
+
+/// A file in the archive directory
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveFile {
+    pub date: NaiveDate,
+    pub kind: ArchiveKind,
+    pub path: PathBuf,
+    pub size: u64,
+    /// not an archive but a file left next to one, see parse_leftover
+    #[serde(default)]
+    pub leftover: bool,
+}
+
+impl fmt::Display for ArchiveFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {} {} bytes", self.path.display(), self.kind, self.size)
+    }
+}
+
+// the date and kind of an archive from its file name, see
+// Config::archive_for_date
+fn parse_name(name: &str) -> Option<(NaiveDate, ArchiveKind)> {
+    let name = name.strip_suffix(".gz")?;
+    if let Some(d) = name.strip_prefix("solar.events-") {
+        let date = NaiveDate::parse_from_str(d, "%Y%m%d").ok()?;
+        return Some((date, ArchiveKind::Events));
+    }
+    let rest = name.strip_prefix("solar.log-")?;
+    if !rest.is_char_boundary(8) {
+        return None;
+    }
+    let (d, b) = rest.split_at(8);
+    let date = NaiveDate::parse_from_str(d, "%Y%m%d").ok()?;
+    let kind = match b {
+        "" => ArchiveKind::Raw,
+        b => ArchiveKind::Aggregate(b.parse().ok()?),
+    };
+    Some((date, kind))
+}
+
+// the date and kind of the archive a leftover file was left next to,
+// the .tmp of a write that didn't finish, or a damaged archive kept by
+// a repair, see verify::damaged_path
+fn parse_leftover(name: &str) -> Option<(NaiveDate, ArchiveKind)> {
+    if let Some(name) = name.strip_suffix(".tmp") {
+        return parse_name(name);
+    }
+    let (name, n) = match name.rfind(".damaged") {
+        None => return None,
+        Some(i) => name.split_at(i),
+    };
+    match &n[".damaged".len()..] {
+        "" => parse_name(name),
+        n => {
+            n.strip_prefix('.')?.parse::<u32>().ok()?;
+            parse_name(name)
+        }
+    }
+}
+
+/// All the archives in the archive directory, and the files left next
+/// to them, oldest first
+pub fn list(cfg: &Config) -> Result<Vec<ArchiveFile>> {
+    let mut res = Vec::new();
+    for ent in fs::read_dir(&cfg.archive_directory)? {
+        let ent = ent?;
+        let name = ent.file_name();
+        let (date, kind, leftover) = match name.to_str() {
+            None => continue,
+            Some(name) => match parse_name(name) {
+                Some((date, kind)) => (date, kind, false),
+                None => match parse_leftover(name) {
+                    Some((date, kind)) => (date, kind, true),
+                    None => continue,
+                },
+            },
+        };
+        let md = ent.metadata()?;
+        if md.is_file() {
+            let path = ent.path();
+            res.push(ArchiveFile { date, kind, path, size: md.len(), leftover })
+        }
+    }
+    res.sort_by_key(|f| (f.date, f.kind, f.leftover));
+    Ok(res)
+}
+
+/// How long archives are kept and how much space they may use.
+/// Anything without a limit is kept forever.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// days to keep the raw stats
+    #[serde(default)]
+    pub raw_days: Option<u32>,
+    /// days to keep the aggregates at each resolution, e.g. {"1m": 730}
+    #[serde(default)]
+    pub aggregate_days: BTreeMap<Bucket, u32>,
+    /// days to keep the events
+    #[serde(default)]
+    pub events_days: Option<u32>,
+    /// the most bytes the archives may use. Over budget the files left
+    /// next to archives go first, then the oldest archives of the
+    /// finest kind, raw stats, then the aggregates from the smallest
+    /// bucket up, then the events.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// the daemon prunes the archive after it archives each day
+    #[serde(default)]
+    pub auto_prune: bool,
+}
+
+impl RetentionConfig {
+    /// the archive of the given kind and date is past its limit
+    pub fn expired(&self, kind: ArchiveKind, date: NaiveDate, today: NaiveDate) -> bool {
+        let days = match kind {
+            ArchiveKind::Raw => self.raw_days,
+            ArchiveKind::Aggregate(b) => self.aggregate_days.get(&b).copied(),
+            ArchiveKind::Events => self.events_days,
+        };
+        match days {
+            None => false,
+            Some(days) => date < today - Duration::days(days as i64),
+        }
+    }
+}
+
+/// Remove the archives the retention policy doesn't keep, first the
+/// ones older than the limit for their kind, then as many more as it
+/// takes to fit the budget. Files left next to an archive age with it,
+/// count toward the budget, and are removed before any archive. Returns the archives removed, with
+/// dry_run the ones that would be, and nothing is removed.
+pub fn prune(cfg: &Config, dry_run: bool) -> Result<Vec<ArchiveFile>> {
+    let retention = match &cfg.retention {
+        None => return Ok(Vec::new()),
+        Some(r) => r,
+    };
+    let today = Local::today().naive_local();
+    let expired = |f: &ArchiveFile| retention.expired(f.kind, f.date, today);
+    let (mut remove, mut keep): (Vec<_>, Vec<_>) =
+        list(cfg)?.into_iter().partition(expired);
+    if let Some(max) = retention.max_bytes {
+        let mut total = keep.iter().map(|f| f.size).sum::<u64>();
+        keep.sort_by_key(|f| (!f.leftover, f.kind, f.date));
+        for f in keep {
+            if total <= max {
+                break;
+            }
+            total -= f.size;
+            remove.push(f)
+        }
+    }
+    remove.sort_by_key(|f| (!f.leftover, f.date, f.kind));
+    if !dry_run {
+        for f in &remove {
+            info!("pruning {}", f);
+            fs::remove_file(&f.path)?
+        }
+    }
+    Ok(remove)
+}
+
+/// The resolutions stats can be read at, raw stats, or aggregated
+/// into buckets. Any bucket size can be read, but only the configured
+/// `archive_resolutions` are archived, others are aggregated from the
+/// raw archives as they are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    Raw,
+    Bucket(Bucket),
+}
+
+impl FromStr for Resolution {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "raw" => Ok(Resolution::Raw),
+            s => Ok(Resolution::Bucket(s.parse()?)),
+        }
+    }
+}
+
+impl Resolution {
+    /// the archive file holding this resolution on the given day
+    pub fn path(&self, cfg: &Config, date: Date<Local>) -> PathBuf {
+        match self {
+            Resolution::Raw => cfg.archive_for_date(date).all,
+            Resolution::Bucket(b) => cfg.aggregate_for_date(date, *b),
+        }
+    }
+}
+
+/// The stats in a range of days, see `read_range`
+pub struct Range {
+    missing: Vec<NaiveDate>,
+    iter: Box<dyn Iterator<Item = Stats>>,
+}
+
+impl Range {
+    /// the days in the range that are neither archived nor in the
+    /// live log
+    pub fn missing(&self) -> &[NaiveDate] {
+        &self.missing
+    }
+}
+
+impl Iterator for Range {
+    type Item = Stats;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.iter.next()
+    }
+}
+
+/// The stats logged between the from and to dates inclusive at the
+/// given resolution, oldest first. Archived days are combined with
+/// the logs rotated but not yet archived and the live log, which are
+/// decimated to match the archives, as is a day without an archive at
+/// the requested resolution. Each day's archive is opened when the
+/// iterator reaches it.
+pub fn read_range(
+    cfg: &Config,
+    from: NaiveDate,
+    to: NaiveDate,
+    resolution: Resolution,
+) -> Result<Range> {
+    if to < from {
+        bail!("the range ends before it starts, {} - {}", from, to)
+    }
+    let log = cfg.log_file();
+    let mut live = if ArchivedDay::file_exists(&log)? {
+        Some(read_history_file(log).map_err(|e| anyhow!("{}", e))?.peekable())
+    } else {
+        None
+    };
+    let live_since =
+        live.as_mut().and_then(|i| i.peek()).map(|s| s.timestamp().date().naive_local());
+    let mut files = Vec::new();
+    let mut missing = Vec::new();
+    let mut day = from;
+    while day <= to {
+        let date = Local.from_local_date(&day).earliest();
+        let path = date.map(|d| resolution.path(cfg, d));
+        // a bucket that isn't archived is aggregated from the raw stats
+        let raw = match resolution {
+            Resolution::Raw => None,
+            Resolution::Bucket(_) => date.map(|d| cfg.archive_for_date(d).all),
+        };
+        // a day the daemon rotated may not be archived yet
+        let rotated = cfg.rotated_log(day);
+        match (path, raw) {
+            (Some(path), _) if ArchivedDay::file_exists(&path)? => {
+                files.push((path, false))
+            }
+            (_, Some(raw)) if ArchivedDay::file_exists(&raw)? => files.push((raw, true)),
+            (_, _) if ArchivedDay::file_exists(&rotated)? => files.push((rotated, true)),
+            (_, _) => {
+                if live_since.map(|since| day < since).unwrap_or(true) {
+                    missing.push(day)
+                }
+            }
+        }
+        day = day.succ();
+    }
+    let archived = files.into_iter().flat_map(move |(file, raw)| {
+        let stats = match read_history_file(file.clone()) {
+            Ok(i) => Some(i),
             Err(e) => {
-                error!("error opening todays log file, skipping: {}", e);
+                error!("error opening log archive, skipping: {:?}, {}", file, e);
                 None
             }
         }
         .into_iter()
-        .flatten(),
-    )
+        .flatten();
+        let stats: Box<dyn Iterator<Item = Stats>> = match resolution {
+            Resolution::Bucket(b) if raw => Box::new(decimate(b, stats)),
+            Resolution::Raw | Resolution::Bucket(_) => Box::new(stats),
+        };
+        stats
+    });
+    let live = live.into_iter().flatten();
+    let live: Box<dyn Iterator<Item = Stats>> = match resolution {
+        Resolution::Raw => Box::new(live),
+        Resolution::Bucket(b) => Box::new(decimate(b, live)),
+    };
+    let iter = archived.chain(live).filter(move |s| {
+        let d = s.timestamp().date().naive_local();
+        from <= d && d <= to
+    });
+    Ok(Range { missing, iter: Box::new(iter) })
+}
+
+/// Ten minute averages going back the given number of days, and today
+pub fn read_history(cfg: &Config, days: i64) -> impl Iterator<Item = Stats> + '_ {
+    info!("read history going back {} days", days);
+    let today = Local::today().naive_local();
+    let from = today - Duration::days(days);
+    let ten_minutes = Resolution::Bucket(Bucket(Duration::minutes(10)));
+    match read_range(cfg, from, today, ten_minutes) {
+        Ok(range) => {
+            for d in range.missing() {
+                warn!("no stats archived for {}", d)
+            }
+            Some(range)
+        }
+        Err(e) => {
+            error!("failed to read history {}", e);
+            None
+        }
+    }
+    .into_iter()
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+    use uom::si::{electric_charge::ampere_hour, electric_potential::volt, f32::*};
+
+    fn at(h: u32, m: u32, s: u32) -> DateTime<Local> {
+        let t = NaiveDate::from_ymd(2021, 6, 15).and_hms(h, m, s);
+        Local.from_local_datetime(&t).unwrap()
+    }
+
+    fn sample(name: &str, ts: DateTime<Local>, v: f32, ah: f32) -> Stats {
+        let mut st = ps::Stats::default();
+        st.battery_terminal_voltage = ElectricPotential::new::<volt>(v);
+        st.ah_charge_total = ElectricCharge::new::<ampere_hour>(ah);
+        Stats::V6 {
+            timestamp: ts,
+            name: String::from(name),
+            controller: Some(ControllerStats::ProstarMppt(st)),
+            aggregate: None,
+        }
+    }
+
+    fn prostar(s: &Stats) -> (ps::Stats, u32, ps::Stats, ps::Stats) {
+        match s {
+            Stats::V6 {
+                controller: Some(ControllerStats::ProstarMppt(mean)),
+                aggregate:
+                    Some(Aggregate {
+                        samples,
+                        min: ControllerStats::ProstarMppt(min),
+                        max: ControllerStats::ProstarMppt(max),
+                    }),
+                ..
+            } => (*mean, *samples, *min, *max),
+            s => panic!("not an aggregate {:?}", s),
+        }
+    }
+
+    fn is_gap(s: &Stats) -> bool {
+        matches!(s, Stats::V6 { controller: None, .. })
+    }
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("solar-archive-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path, retention: serde_json::Value) -> Config {
+        let cfg = serde_json::json!({
+            "run_directory": dir,
+            "archive_directory": dir,
+            "stats_interval": 1,
+            "log_level": "Info",
+            "netidx_base": "/solar",
+            "netidx_bind": "local",
+            "retention": retention,
+        });
+        serde_json::from_value(cfg).unwrap()
+    }
+
+    // an archive of the given kind days ago with size bytes in it
+    fn archive(cfg: &Config, kind: ArchiveKind, days_ago: i64, size: usize) {
+        let date = Local::today() - Duration::days(days_ago);
+        let path = match kind {
+            ArchiveKind::Raw => cfg.archive_for_date(date).all,
+            ArchiveKind::Aggregate(b) => cfg.aggregate_for_date(date, b),
+            ArchiveKind::Events => cfg.archive_for_date(date).events,
+        };
+        fs::write(path, vec![0u8; size]).unwrap()
+    }
+
+    fn pruned(files: &[ArchiveFile]) -> Vec<(i64, ArchiveKind)> {
+        let today = Local::today().naive_local();
+        files.iter().map(|f| ((today - f.date).num_days(), f.kind)).collect()
+    }
+
+    #[test]
+    fn parse_bucket() {
+        for (s, d) in &[
+            ("30s", Duration::seconds(30)),
+            ("10m", Duration::minutes(10)),
+            ("1h", Duration::hours(1)),
+            ("1d", Duration::days(1)),
+        ] {
+            assert_eq!(s.parse::<Bucket>().unwrap().duration(), *d)
+        }
+        for s in &["", "m", "10", "10x", "0m", "-5m", "2d", "25h"] {
+            assert!(s.parse::<Bucket>().is_err(), "{:?}", s)
+        }
+    }
+
+    #[test]
+    fn display_bucket() {
+        for (s, d) in &[("60m", "1h"), ("90s", "90s"), ("120s", "2m"), ("24h", "1d")] {
+            assert_eq!(s.parse::<Bucket>().unwrap().to_string(), *d)
+        }
+    }
+
+    #[test]
+    fn bucket_alignment() {
+        let b = Buckets::new(Duration::minutes(10));
+        assert_eq!(b.bucket(at(12, 34, 56)), at(12, 30, 0));
+        assert_eq!(b.bucket(at(12, 30, 0)), at(12, 30, 0));
+        assert_eq!(b.succ(at(23, 50, 0)), at(23, 50, 0) + Duration::minutes(10));
+        // sizes that don't divide an hour are still laid out from midnight
+        let b = Buckets::new(Duration::minutes(7));
+        assert_eq!(b.bucket(at(12, 34, 56)), at(12, 29, 0));
+        assert_eq!(b.bucket(at(0, 6, 59)), at(0, 0, 0));
+        let b = Buckets::new(Duration::days(1));
+        assert_eq!(b.bucket(at(23, 59, 59)), at(0, 0, 0));
+    }
+
+    #[test]
+    fn aggregate() {
+        let stats = vec![
+            sample("east", at(12, 0, 10), 12., 100.),
+            sample("east", at(12, 0, 30), 13., 101.),
+            sample("east", at(12, 0, 50), 14., 102.),
+            sample("east", at(12, 1, 10), 12.5, 103.),
+        ];
+        let bucket = "1m".parse().unwrap();
+        let agg = decimate(bucket, stats.into_iter()).collect::<Vec<_>>();
+        assert_eq!(agg.len(), 2);
+        assert_eq!(agg[0].timestamp(), at(12, 0, 0));
+        let (mean, samples, min, max) = prostar(&agg[0]);
+        assert_eq!(samples, 3);
+        assert!((mean.battery_terminal_voltage.get::<volt>() - 13.).abs() < 1e-4);
+        assert_eq!(min.battery_terminal_voltage.get::<volt>(), 12.);
+        assert_eq!(max.battery_terminal_voltage.get::<volt>(), 14.);
+        // counters are taken from the latest sample
+        assert_eq!(mean.ah_charge_total.get::<ampere_hour>(), 102.);
+        assert_eq!(agg[1].timestamp(), at(12, 1, 0));
+        assert_eq!(prostar(&agg[1]).1, 1);
+    }
+
+    #[test]
+    fn aggregate_aggregates() {
+        let stats = vec![
+            sample("east", at(12, 0, 10), 12., 100.),
+            sample("east", at(12, 0, 50), 14., 101.),
+            sample("east", at(12, 1, 10), 15., 102.),
+        ];
+        let one = decimate("1m".parse().unwrap(), stats.into_iter());
+        let two = decimate("10m".parse().unwrap(), one).collect::<Vec<_>>();
+        assert_eq!(two.len(), 1);
+        let (mean, samples, min, max) = prostar(&two[0]);
+        // weighted by the samples in each window
+        assert_eq!(samples, 3);
+        assert!((mean.battery_terminal_voltage.get::<volt>() - 41. / 3.).abs() < 1e-4);
+        assert_eq!(min.battery_terminal_voltage.get::<volt>(), 12.);
+        assert_eq!(max.battery_terminal_voltage.get::<volt>(), 15.);
+    }
+
+    #[test]
+    fn gap_per_empty_bucket() {
+        let stats = vec![
+            sample("east", at(12, 0, 10), 12., 100.),
+            sample("west", at(12, 0, 20), 12., 100.),
+            sample("east", at(12, 3, 10), 12., 100.),
+            sample("west", at(12, 1, 20), 12., 100.),
+        ];
+        let agg = decimate("1m".parse().unwrap(), stats.into_iter()).collect::<Vec<_>>();
+        let east = agg.iter().filter(|s| s.name() == "east").collect::<Vec<_>>();
+        let ts = east.iter().map(|s| (s.timestamp(), is_gap(s))).collect::<Vec<_>>();
+        assert_eq!(
+            ts,
+            vec![
+                (at(12, 0, 0), false),
+                (at(12, 1, 0), true),
+                (at(12, 2, 0), true),
+                (at(12, 3, 0), false)
+            ]
+        );
+        let west = agg.iter().filter(|s| s.name() == "west").collect::<Vec<_>>();
+        assert_eq!(west.len(), 2);
+        assert!(west.iter().all(|s| !is_gap(s)));
+    }
+
+    #[test]
+    fn out_of_order_stats() {
+        let stats = vec![
+            sample("east", at(12, 1, 10), 12., 100.),
+            sample("east", at(12, 0, 50), 14., 101.),
+        ];
+        let agg = decimate("1m".parse().unwrap(), stats.into_iter()).collect::<Vec<_>>();
+        assert_eq!(agg.len(), 1);
+        assert_eq!(agg[0].timestamp(), at(12, 1, 0));
+        assert_eq!(prostar(&agg[0]).1, 2);
+    }
+
+    #[test]
+    fn read_unarchived_bucket() {
+        let dir = tmp_dir("unarchived");
+        let cfg = config(&dir, serde_json::Value::Null);
+        let date = at(0, 0, 0).date();
+        let mut w = ArchiveWriter::create(&cfg.archive_for_date(date).all).unwrap();
+        for (i, m) in [10, 20, 70, 80].iter().enumerate() {
+            w.write(&sample("east", at(12, *m, 0), 12. + i as f32, 100.)).unwrap();
+        }
+        w.finish().unwrap();
+        let day = date.naive_local();
+        let hour = Resolution::Bucket("1h".parse().unwrap());
+        let range = read_range(&cfg, day, day, hour).unwrap();
+        assert!(range.missing().is_empty());
+        let stats = range.map(|s| (s.timestamp(), prostar(&s).1)).collect::<Vec<_>>();
+        assert_eq!(stats, vec![(at(12, 0, 0), 2), (at(13, 0, 0), 2)]);
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn parse_names() {
+        let d = NaiveDate::from_ymd(2021, 6, 15);
+        let b = "1h".parse().unwrap();
+        assert_eq!(parse_name("solar.log-20210615.gz"), Some((d, ArchiveKind::Raw)));
+        let agg = parse_name("solar.log-202106151h.gz");
+        assert_eq!(agg, Some((d, ArchiveKind::Aggregate(b))));
+        let events = parse_name("solar.events-20210615.gz");
+        assert_eq!(events, Some((d, ArchiveKind::Events)));
+        assert_eq!(parse_name("solar.log-20210615.gz.damaged"), None);
+        assert_eq!(parse_name("solar.log-2021.gz"), None);
+        assert_eq!(parse_name("solar.log"), None);
+        let tmp = parse_leftover("solar.log-202106151h.gz.tmp");
+        assert_eq!(tmp, Some((d, ArchiveKind::Aggregate(b))));
+        assert_eq!(parse_leftover("solar.log-20210615.gz"), None);
+        assert_eq!(parse_leftover("solar.log.tmp"), None);
+        let damaged = parse_leftover("solar.events-20210615.gz.damaged");
+        assert_eq!(damaged, Some((d, ArchiveKind::Events)));
+        let damaged = parse_leftover("solar.log-20210615.gz.damaged.2");
+        assert_eq!(damaged, Some((d, ArchiveKind::Raw)));
+        assert_eq!(parse_leftover("solar.log-20210615.gz.damaged.x"), None);
+    }
+
+    #[test]
+    fn expired() {
+        let r = RetentionConfig {
+            raw_days: Some(7),
+            aggregate_days: vec![("1h".parse().unwrap(), 30)].into_iter().collect(),
+            ..RetentionConfig::default()
+        };
+        let today = NaiveDate::from_ymd(2021, 6, 15);
+        let ago = |n| today - Duration::days(n);
+        assert!(!r.expired(ArchiveKind::Raw, ago(7), today));
+        assert!(r.expired(ArchiveKind::Raw, ago(8), today));
+        let hour = ArchiveKind::Aggregate("1h".parse().unwrap());
+        assert!(!r.expired(hour, ago(30), today));
+        assert!(r.expired(hour, ago(31), today));
+        // no limit, kept forever
+        let minute = ArchiveKind::Aggregate("1m".parse().unwrap());
+        assert!(!r.expired(minute, ago(1000), today));
+        assert!(!r.expired(ArchiveKind::Events, ago(1000), today));
+    }
+
+    #[test]
+    fn prune_by_age() {
+        let dir = tmp_dir("age");
+        let cfg = config(&dir, serde_json::json!({"raw_days": 2, "events_days": 5}));
+        for d in 1..=4 {
+            archive(&cfg, ArchiveKind::Raw, d, 10);
+            archive(&cfg, ArchiveKind::Events, d, 10);
+        }
+        let removed = prune(&cfg, false).unwrap();
+        let raw = ArchiveKind::Raw;
+        assert_eq!(pruned(&removed), vec![(4, raw), (3, raw)]);
+        assert_eq!(list(&cfg).unwrap().len(), 6);
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn prune_to_budget() {
+        let dir = tmp_dir("budget");
+        let cfg = config(&dir, serde_json::json!({"max_bytes": 45}));
+        let hour = ArchiveKind::Aggregate("1h".parse().unwrap());
+        let minute = ArchiveKind::Aggregate("1m".parse().unwrap());
+        for d in 1..=2 {
+            archive(&cfg, ArchiveKind::Raw, d, 10);
+            archive(&cfg, minute, d, 10);
+            archive(&cfg, hour, d, 10);
+            archive(&cfg, ArchiveKind::Events, d, 10);
+        }
+        // the raw stats go first, then the smallest bucket, oldest first
+        let removed = prune(&cfg, true).unwrap();
+        let raw = ArchiveKind::Raw;
+        assert_eq!(pruned(&removed), vec![(2, raw), (2, minute), (1, raw), (1, minute)]);
+        // a dry run removes nothing
+        assert_eq!(list(&cfg).unwrap().len(), 8);
+        prune(&cfg, false).unwrap();
+        let left = list(&cfg).unwrap();
+        assert_eq!(left.iter().map(|f| f.size).sum::<u64>(), 40);
+        assert!(left.iter().all(|f| f.kind == hour || f.kind == ArchiveKind::Events));
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn prune_leftovers() {
+        let dir = tmp_dir("leftovers");
+        let cfg = config(&dir, serde_json::json!({"raw_days": 2, "max_bytes": 25}));
+        for d in 1..=3 {
+            archive(&cfg, ArchiveKind::Raw, d, 10);
+        }
+        let date = Local::today() - Duration::days(1);
+        let path = tmp_path(&cfg.archive_for_date(date).all);
+        fs::write(&path, vec![0u8; 10]).unwrap();
+        let old = Local::today() - Duration::days(3);
+        fs::write(tmp_path(&cfg.archive_for_date(old).all), vec![0u8; 10]).unwrap();
+        let files = list(&cfg).unwrap();
+        assert_eq!(files.len(), 5);
+        assert_eq!(files.iter().filter(|f| f.leftover).count(), 2);
+        // the old leftover ages out with it's archive, the other is
+        // over budget and goes before any archive
+        let removed = prune(&cfg, false).unwrap();
+        assert!(removed[0].leftover && removed[1].leftover);
+        assert_eq!(removed[2].path, cfg.archive_for_date(old).all);
+        assert!(!path.exists());
+        let left = list(&cfg).unwrap();
+        assert_eq!(left.len(), 2);
+        assert!(left.iter().all(|f| !f.leftover));
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn no_retention() {
+        let dir = tmp_dir("none");
+        let cfg = config(&dir, serde_json::Value::Null);
+        archive(&cfg, ArchiveKind::Raw, 1000, 10);
+        assert!(prune(&cfg, false).unwrap().is_empty());
+        assert_eq!(list(&cfg).unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap()
+    }
 }
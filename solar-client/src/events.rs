@@ -0,0 +1,361 @@
+//! The event log. The daemon records transitions, charge and load
+//! state changes, faults raised and cleared, controllers coming and
+//! going, settings changes and commands, as json lines in a separate
+//! log that is archived next to the daily stats files.
+use crate::{archive, faults::Faults, Config, FromClient, Source};
+use anyhow::{Error, Result};
+use chrono::{prelude::*, Duration};
+use libflate::gzip::{Decoder, EncodeOptions, Encoder};
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Kind {
+    ChargeState,
+    LoadState,
+    Fault,
+    Reachable,
+    Settings,
+    Command,
+}
+
+impl FromStr for Kind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "charge-state" => Kind::ChargeState,
+            "load-state" => Kind::LoadState,
+            "fault" => Kind::Fault,
+            "reachable" => Kind::Reachable,
+            "settings" => Kind::Settings,
+            "command" => Kind::Command,
+            s => bail!(
+                "unknown event type {}, expected one of charge-state, load-state, \
+                 fault, reachable, settings, command",
+                s
+            ),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Detail {
+    /// from is None for the first state seen after the daemon starts
+    ChargeState {
+        from: Option<String>,
+        to: String,
+    },
+    LoadState {
+        from: Option<String>,
+        to: String,
+    },
+    /// a bit in one of the fault or alarm fields was set or cleared
+    Fault {
+        field: String,
+        flag: String,
+        active: bool,
+    },
+    Reachable(bool),
+    /// settings were written, the details are in the settings journal
+    Settings(Source),
+    Command {
+        source: Source,
+        command: FromClient,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub timestamp: DateTime<Local>,
+    pub controller: String,
+    pub detail: Detail,
+}
+
+impl Event {
+    pub fn new(controller: &str, detail: Detail) -> Self {
+        Event { timestamp: Local::now(), controller: String::from(controller), detail }
+    }
+
+    pub fn kind(&self) -> Kind {
+        match self.detail {
+            Detail::ChargeState { .. } => Kind::ChargeState,
+            Detail::LoadState { .. } => Kind::LoadState,
+            Detail::Fault { .. } => Kind::Fault,
+            Detail::Reachable(_) => Kind::Reachable,
+            Detail::Settings(_) => Kind::Settings,
+            Detail::Command { .. } => Kind::Command,
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} {} ", self.timestamp, self.controller)?;
+        let state = |fmt: &mut fmt::Formatter, name: &str, from: &Option<String>, to| {
+            match from {
+                None => write!(fmt, "{} {}", name, to),
+                Some(from) => write!(fmt, "{} {} -> {}", name, from, to),
+            }
+        };
+        match &self.detail {
+            Detail::ChargeState { from, to } => state(fmt, "charge state", from, to),
+            Detail::LoadState { from, to } => state(fmt, "load state", from, to),
+            Detail::Fault { field, flag, active: true } => {
+                write!(fmt, "{} {} raised", field, flag)
+            }
+            Detail::Fault { field, flag, active: false } => {
+                write!(fmt, "{} {} cleared", field, flag)
+            }
+            Detail::Reachable(true) => write!(fmt, "reachable"),
+            Detail::Reachable(false) => write!(fmt, "unreachable"),
+            Detail::Settings(source) => write!(fmt, "settings written from {}", source),
+            Detail::Command { source, command } => {
+                write!(fmt, "command from {} {:?}", source, command)
+            }
+        }
+    }
+}
+
+/// The fault events between two sets of faults. Only the live fields
+/// are compared, the daily fields just accumulate the live ones.
+pub fn fault_changes(controller: &str, old: &Faults, new: &Faults) -> Vec<Event> {
+    let fields = [
+        ("array_faults", &old.array_faults, &new.array_faults),
+        ("load_faults", &old.load_faults, &new.load_faults),
+        ("alarms", &old.alarms, &new.alarms),
+    ];
+    let mut events = Vec::new();
+    for (field, old, new) in fields.iter() {
+        let mut changed = |flags: &[String], other: &[String], active: bool| {
+            for flag in flags {
+                if !other.contains(flag) {
+                    let (field, flag) = (String::from(*field), flag.clone());
+                    let detail = Detail::Fault { field, flag, active };
+                    events.push(Event::new(controller, detail))
+                }
+            }
+        };
+        changed(new, old, true);
+        changed(old, new, false);
+    }
+    events
+}
+
+fn read_file(path: &Path, gz: bool, events: &mut Vec<Event>) -> Result<()> {
+    let file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    let reader: Box<dyn Read> =
+        if gz { Box::new(Decoder::new(file)?) } else { Box::new(file) };
+    for line in BufReader::new(reader).lines() {
+        match serde_json::from_str(&line?) {
+            Ok(e) => events.push(e),
+            Err(e) => warn!("skipping unreadable event {:?} {}", path, e),
+        }
+    }
+    Ok(())
+}
+
+/// The events that happened between the from and to dates inclusive,
+/// of the given kinds, or all kinds if kinds is empty, oldest first.
+pub fn read(
+    cfg: &Config,
+    from: NaiveDate,
+    to: NaiveDate,
+    kinds: &[Kind],
+) -> Result<Vec<Event>> {
+    let mut events = Vec::new();
+    // an archive holds the events up to the time it was made, so the
+    // day after the range may hold events from the end of it
+    let mut day = from;
+    while day <= to + Duration::days(1) {
+        if let Some(d) = Local.from_local_date(&day).earliest() {
+            let archived = cfg.archive_for_date(d).events;
+            if archived.exists() {
+                read_file(&archived, true, &mut events)?
+            } else if day <= to {
+                // the daemon rotated the day but hasn't archived it yet
+                read_file(&cfg.rotated_event_log(day), false, &mut events)?
+            }
+        }
+        day = day.succ();
+    }
+    read_file(&cfg.event_log(), false, &mut events)?;
+    events.retain(|e| {
+        let d = e.timestamp.date().naive_local();
+        from <= d && d <= to && (kinds.is_empty() || kinds.contains(&e.kind()))
+    });
+    events.sort_by_key(|e| e.timestamp);
+    Ok(events)
+}
+
+/// compress an event log into the archive, the archive is written
+/// under a temporary name and renamed into place when it's complete
+pub(crate) fn archive(from: &Path, to: &Path) -> Result<()> {
+    if to.exists() {
+        bail!("{:?} already exists", to)
+    }
+    let tmp = archive::tmp_path(to);
+    match compress(from, &tmp) {
+        Ok(()) => Ok(fs::rename(&tmp, to)?),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
+}
+
+fn compress(from: &Path, to: &Path) -> Result<()> {
+    let mut enc = Encoder::with_options(
+        OpenOptions::new().write(true).create(true).truncate(true).open(to)?,
+        EncodeOptions::new(),
+    )?;
+    match fs::File::open(from) {
+        Ok(mut f) => {
+            io::copy(&mut f, &mut enc)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    enc.flush()?;
+    enc.finish().into_result()?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, path::PathBuf, process};
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("solar-events-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path) -> Config {
+        let cfg = serde_json::json!({
+            "run_directory": dir,
+            "archive_directory": dir,
+            "stats_interval": 1,
+            "log_level": "Info",
+            "netidx_base": "/solar",
+            "netidx_bind": "local",
+        });
+        serde_json::from_value(cfg).unwrap()
+    }
+
+    fn day(d: u32) -> NaiveDate {
+        NaiveDate::from_ymd(2021, 6, d)
+    }
+
+    fn event(d: u32, detail: Detail) -> Event {
+        let timestamp = Local.from_local_datetime(&day(d).and_hms(12, 0, 0)).unwrap();
+        Event { timestamp, controller: String::from("east"), detail }
+    }
+
+    fn write_log(path: &Path, events: &[Event]) {
+        let mut f = fs::File::create(path).unwrap();
+        for e in events {
+            writeln!(f, "{}", serde_json::to_string(e).unwrap()).unwrap()
+        }
+    }
+
+    fn faults(array: &[&str], alarms: &[&str]) -> Faults {
+        Faults {
+            array_faults: array.iter().map(|s| String::from(*s)).collect(),
+            alarms: alarms.iter().map(|s| String::from(*s)).collect(),
+            ..Faults::default()
+        }
+    }
+
+    #[test]
+    fn parse_kind() {
+        assert_eq!("load-state".parse::<Kind>().unwrap(), Kind::LoadState);
+        assert_eq!("command".parse::<Kind>().unwrap(), Kind::Command);
+        assert!("load".parse::<Kind>().is_err());
+    }
+
+    #[test]
+    fn display() {
+        let to = String::from("Float");
+        let first = event(1, Detail::ChargeState { from: None, to: to.clone() });
+        assert!(first.to_string().ends_with("east charge state Float"));
+        let from = Some(String::from("Absorption"));
+        let change = event(1, Detail::ChargeState { from, to });
+        assert!(change.to_string().ends_with("east charge state Absorption -> Float"));
+        let (field, flag) = (String::from("alarms"), String::from("HeatsinkHot"));
+        let fault = event(1, Detail::Fault { field, flag, active: false });
+        assert!(fault.to_string().ends_with("east alarms HeatsinkHot cleared"));
+    }
+
+    #[test]
+    fn faults_raised_and_cleared() {
+        let old = faults(&[], &["HeatsinkHot"]);
+        let mut new = faults(&["Overcurrent"], &["LowBattery"]);
+        // only the live fields are compared
+        new.alarms_daily = vec![String::from("HeatsinkHot")];
+        let changes = fault_changes("east", &old, &new)
+            .into_iter()
+            .map(|e| match e.detail {
+                Detail::Fault { field, flag, active } => (field, flag, active),
+                d => panic!("not a fault {:?}", d),
+            })
+            .collect::<Vec<_>>();
+        let change = |field: &str, flag: &str, active| {
+            (String::from(field), String::from(flag), active)
+        };
+        assert_eq!(
+            changes,
+            vec![
+                change("array_faults", "Overcurrent", true),
+                change("alarms", "LowBattery", true),
+                change("alarms", "HeatsinkHot", false),
+            ]
+        );
+        assert!(fault_changes("east", &new, &new).is_empty());
+    }
+
+    #[test]
+    fn read_archived_rotated_and_live() {
+        let dir = tmp_dir("read");
+        let cfg = config(&dir);
+        let reachable = event(1, Detail::Reachable(true));
+        let to = String::from("Normal");
+        let load = event(2, Detail::LoadState { from: None, to });
+        let settings = event(3, Detail::Settings(Source::Cli));
+        // the first day is archived, the second rotated, the third live
+        let log = dir.join("events.log-20210601");
+        write_log(&log, &[reachable]);
+        let archived =
+            cfg.archive_for_date(Local.from_local_date(&day(1)).unwrap()).events;
+        archive(&log, &archived).unwrap();
+        write_log(&cfg.rotated_event_log(day(2)), &[load]);
+        write_log(&cfg.event_log(), &[settings]);
+        let mut f = OpenOptions::new().append(true).open(cfg.event_log()).unwrap();
+        writeln!(f, "not an event").unwrap();
+        let kinds =
+            |events: Vec<Event>| events.iter().map(Event::kind).collect::<Vec<_>>();
+        let all = read(&cfg, day(1), day(3), &[]).unwrap();
+        assert_eq!(kinds(all), vec![Kind::Reachable, Kind::LoadState, Kind::Settings]);
+        assert_eq!(
+            kinds(read(&cfg, day(2), day(2), &[]).unwrap()),
+            vec![Kind::LoadState]
+        );
+        let settings = read(&cfg, day(1), day(3), &[Kind::Settings]).unwrap();
+        assert_eq!(kinds(settings), vec![Kind::Settings]);
+        // an archive is never overwritten
+        assert!(archive(&log, &archived).is_err());
+        fs::remove_dir_all(&dir).unwrap()
+    }
+}
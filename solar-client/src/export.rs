@@ -0,0 +1,234 @@
+//! Export logged stats for analysis as csv, InfluxDB line protocol or
+//! newline delimited json. Every exported record has the controller's
+//! name, and an up flag that is false when the controller couldn't be
+//! read, in which case all of it's fields are empty.
+use crate::{rules, Stats};
+use anyhow::{Error, Result};
+use serde_json::json;
+use std::{borrow::Cow, io::Write, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Csv,
+    Influx,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "csv" => Format::Csv,
+            "influx" => Format::Influx,
+            "json" => Format::Json,
+            s => bail!("unknown format {}, expected one of csv, influx, json", s),
+        })
+    }
+}
+
+/// The named fields, all the fields if names is empty
+pub fn fields(names: &[String]) -> Result<Vec<&'static str>> {
+    if names.is_empty() {
+        return Ok(rules::FIELDS.to_vec());
+    }
+    names
+        .iter()
+        .map(|n| match rules::FIELDS.iter().find(|f| **f == n.as_str()) {
+            Some(f) => Ok(*f),
+            None => bail!("unknown field {}", n),
+        })
+        .collect()
+}
+
+fn csv_field(s: &str) -> Cow<str> {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn influx_tag(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == ',' || c == '=' || c == ' ' {
+            res.push('\\')
+        }
+        res.push(c)
+    }
+    res
+}
+
+/// Write the stats in the given format, with only the given fields
+pub fn write(
+    w: &mut impl Write,
+    format: Format,
+    fields: &[&str],
+    stats: impl Iterator<Item = Stats>,
+) -> Result<()> {
+    if format == Format::Csv {
+        write!(w, "timestamp,controller,up")?;
+        for f in fields {
+            write!(w, ",{}", f)?
+        }
+        writeln!(w)?
+    }
+    for s in stats {
+        let st = s.controller();
+        let values = fields
+            .iter()
+            .map(|f| st.as_ref().and_then(|st| rules::field(st, f)))
+            .map(|v| v.filter(|v| v.is_finite()));
+        match format {
+            Format::Csv => {
+                let ts = s.timestamp().to_rfc3339();
+                write!(w, "{},{},{}", ts, csv_field(s.name()), st.is_some() as u8)?;
+                for v in values {
+                    match v {
+                        None => write!(w, ",")?,
+                        Some(v) => write!(w, ",{}", v)?,
+                    }
+                }
+                writeln!(w)?
+            }
+            Format::Influx => {
+                let name = influx_tag(s.name());
+                write!(w, "solar,controller={} up={}", name, st.is_some())?;
+                for (f, v) in fields.iter().zip(values) {
+                    if let Some(v) = v {
+                        write!(w, ",{}={}", f, v)?
+                    }
+                }
+                writeln!(w, " {}", s.timestamp().timestamp_nanos())?
+            }
+            Format::Json => {
+                let mut obj = serde_json::Map::new();
+                obj.insert("timestamp".into(), json!(s.timestamp()));
+                obj.insert("controller".into(), json!(s.name()));
+                obj.insert("up".into(), json!(st.is_some()));
+                for (f, v) in fields.iter().zip(values) {
+                    obj.insert(String::from(*f), json!(v));
+                }
+                serde_json::to_writer(&mut *w, &obj)?;
+                writeln!(w)?
+            }
+        }
+    }
+    w.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ControllerStats;
+    use chrono::prelude::*;
+    use morningstar::prostar_mppt as ps;
+    use uom::si::{electric_charge::ampere_hour, electric_potential::volt, f32::*};
+
+    static FIELDS: &[&str] = &["battery_terminal_voltage", "ah_charge_total"];
+
+    fn at() -> DateTime<Local> {
+        let t = NaiveDate::from_ymd(2021, 6, 15).and_hms(12, 0, 0);
+        Local.from_local_datetime(&t).unwrap()
+    }
+
+    fn sample(name: &str) -> Stats {
+        let mut st = ps::Stats::default();
+        st.battery_terminal_voltage = ElectricPotential::new::<volt>(12.5);
+        st.ah_charge_total = ElectricCharge::new::<ampere_hour>(100.);
+        Stats::V6 {
+            timestamp: at(),
+            name: String::from(name),
+            controller: Some(ControllerStats::ProstarMppt(st)),
+            aggregate: None,
+        }
+    }
+
+    fn gap(name: &str) -> Stats {
+        Stats::V6 {
+            timestamp: at(),
+            name: String::from(name),
+            controller: None,
+            aggregate: None,
+        }
+    }
+
+    fn export(format: Format, stats: Vec<Stats>) -> Vec<String> {
+        let mut buf = Vec::new();
+        write(&mut buf, format, FIELDS, stats.into_iter()).unwrap();
+        String::from_utf8(buf).unwrap().lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn parse_fields() {
+        assert_eq!(fields(&[]).unwrap(), rules::FIELDS.to_vec());
+        let names = vec![String::from("ah_charge_total")];
+        assert_eq!(fields(&names).unwrap(), vec!["ah_charge_total"]);
+        assert!(fields(&[String::from("bogus")]).is_err());
+        assert!("xml".parse::<Format>().is_err());
+    }
+
+    #[test]
+    fn csv_quoting() {
+        assert!(matches!(csv_field("east"), Cow::Borrowed("east")));
+        assert_eq!(csv_field("east,roof"), "\"east,roof\"");
+        assert_eq!(csv_field("the \"east\""), "\"the \"\"east\"\"\"");
+        assert_eq!(csv_field("east\nroof"), "\"east\nroof\"");
+    }
+
+    #[test]
+    fn csv() {
+        let lines = export(Format::Csv, vec![sample("east,roof"), gap("east,roof")]);
+        let ts = at().to_rfc3339();
+        assert_eq!(
+            lines,
+            vec![
+                String::from(
+                    "timestamp,controller,up,battery_terminal_voltage,ah_charge_total"
+                ),
+                format!("{},\"east,roof\",1,12.5,100", ts),
+                // a gap has every field empty
+                format!("{},\"east,roof\",0,,", ts),
+            ]
+        );
+    }
+
+    #[test]
+    fn influx_escaping() {
+        assert_eq!(influx_tag("east"), "east");
+        assert_eq!(influx_tag("east roof,a=b"), "east\\ roof\\,a\\=b");
+    }
+
+    #[test]
+    fn influx() {
+        let lines = export(Format::Influx, vec![sample("east roof"), gap("east roof")]);
+        let ns = at().timestamp_nanos();
+        assert_eq!(
+            lines,
+            vec![
+                format!(
+                    "solar,controller=east\\ roof up=true,battery_terminal_voltage=12.5,\
+                     ah_charge_total=100 {}",
+                    ns
+                ),
+                // a gap has no fields
+                format!("solar,controller=east\\ roof up=false {}", ns),
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let lines = export(Format::Json, vec![sample("east"), gap("east")]);
+        let up: serde_json::Value = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(up["controller"], "east");
+        assert_eq!(up["up"], true);
+        assert_eq!(up["battery_terminal_voltage"], 12.5);
+        let down: serde_json::Value = serde_json::from_str(&lines[1]).unwrap();
+        assert_eq!(down["up"], false);
+        assert!(down["battery_terminal_voltage"].is_null());
+        assert!(down["ah_charge_total"].is_null());
+    }
+}
@@ -0,0 +1,201 @@
+//! Names for the fault and alarm bits the controller reports, taken
+//! from the ProStar MPPT MODBUS specification. Bits the specification
+//! leaves reserved are named by their position, e.g. bit13.
+use crate::ControllerStats;
+use morningstar::prostar_mppt as ps;
+use std::fmt;
+
+/// array faults by bit position
+pub static ARRAY_FAULTS: &[&str] = &[
+    "overcurrent",
+    "fets_shorted",
+    "software_bug",
+    "battery_hvd",
+    "array_hvd",
+    "settings_switch_changed",
+    "custom_settings_edit",
+    "rts_shorted",
+    "rts_disconnected",
+    "eeprom_retry_limit",
+    "",
+    "slave_control_timeout",
+];
+
+/// load faults by bit position
+pub static LOAD_FAULTS: &[&str] = &[
+    "external_short",
+    "overcurrent",
+    "fets_shorted",
+    "software_bug",
+    "hvd",
+    "heatsink_over_temperature",
+    "settings_switch_changed",
+    "custom_settings_edit",
+];
+
+/// alarms by bit position
+pub static ALARMS: &[&str] = &[
+    "rts_open",
+    "rts_shorted",
+    "rts_disconnected",
+    "heatsink_sensor_open",
+    "heatsink_sensor_shorted",
+    "high_temperature_current_limit",
+    "current_limit",
+    "current_offset",
+    "battery_sense_out_of_range",
+    "battery_sense_disconnected",
+    "uncalibrated",
+    "rts_miswire",
+    "high_voltage_disconnect",
+    "",
+    "system_miswire",
+    "mosfet_open",
+    "p12_voltage_off",
+    "high_input_voltage_current_limit",
+    "adc_input_max",
+    "controller_was_reset",
+];
+
+/// The name of every bit in a bitfield with the given table, in bit
+/// order
+pub fn all(table: &[&str], width: usize) -> Vec<String> {
+    (0..width)
+        .map(|i| match table.get(i) {
+            Some(n) if !n.is_empty() => String::from(*n),
+            Some(_) | None => format!("bit{}", i),
+        })
+        .collect()
+}
+
+/// The names of the bits that are set
+pub fn decode(table: &[&str], bits: u32) -> Vec<String> {
+    all(table, 32)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, n)| if bits & (1 << i) != 0 { Some(n) } else { None })
+        .collect()
+}
+
+/// The active faults and alarms, and those seen today
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Faults {
+    pub array_faults: Vec<String>,
+    pub load_faults: Vec<String>,
+    pub alarms: Vec<String>,
+    pub array_faults_daily: Vec<String>,
+    pub load_faults_daily: Vec<String>,
+    pub alarms_daily: Vec<String>,
+}
+
+impl Faults {
+    fn prostar_mppt(st: &ps::Stats) -> Self {
+        Faults {
+            array_faults: decode(ARRAY_FAULTS, st.array_faults.bits() as u32),
+            load_faults: decode(LOAD_FAULTS, st.load_faults.bits() as u32),
+            alarms: decode(ALARMS, st.alarms.bits()),
+            array_faults_daily: decode(ARRAY_FAULTS, st.array_faults_daily.bits() as u32),
+            load_faults_daily: decode(LOAD_FAULTS, st.load_faults_daily.bits() as u32),
+            alarms_daily: decode(ALARMS, st.alarms_daily.bits()),
+        }
+    }
+
+    pub fn new(st: &ControllerStats) -> Self {
+        match st {
+            ControllerStats::ProstarMppt(s) => Faults::prostar_mppt(s),
+        }
+    }
+
+    /// true if nothing is active now or was today
+    pub fn is_empty(&self) -> bool {
+        self.array_faults.is_empty()
+            && self.load_faults.is_empty()
+            && self.alarms.is_empty()
+            && self.array_faults_daily.is_empty()
+            && self.load_faults_daily.is_empty()
+            && self.alarms_daily.is_empty()
+    }
+}
+
+impl fmt::Display for Faults {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let groups = [
+            ("array faults", &self.array_faults),
+            ("load faults", &self.load_faults),
+            ("alarms", &self.alarms),
+            ("array faults today", &self.array_faults_daily),
+            ("load faults today", &self.load_faults_daily),
+            ("alarms today", &self.alarms_daily),
+        ];
+        let mut first = true;
+        for (name, flags) in groups.iter() {
+            if !flags.is_empty() {
+                if !first {
+                    write!(fmt, "; ")?;
+                }
+                first = false;
+                write!(fmt, "{}: {}", name, flags.join(", "))?;
+            }
+        }
+        if first {
+            write!(fmt, "no faults")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Stats;
+
+    #[test]
+    fn decode_bits() {
+        assert!(decode(LOAD_FAULTS, 0).is_empty());
+        assert_eq!(decode(LOAD_FAULTS, 0b1), vec!["external_short"]);
+        assert_eq!(decode(ARRAY_FAULTS, 0b1001), vec!["overcurrent", "battery_hvd"]);
+        assert_eq!(
+            decode(ALARMS, 1 << 19 | 1 << 6),
+            vec!["current_limit", "controller_was_reset"]
+        );
+    }
+
+    #[test]
+    fn reserved_bits() {
+        // reserved and undocumented bits are named by position
+        assert_eq!(decode(ARRAY_FAULTS, 1 << 10), vec!["bit10"]);
+        assert_eq!(decode(ALARMS, 1 << 13 | 1 << 31), vec!["bit13", "bit31"]);
+        assert_eq!(all(LOAD_FAULTS, 10)[8..], ["bit8", "bit9"]);
+    }
+
+    #[test]
+    fn display() {
+        let mut f = Faults::default();
+        assert!(f.is_empty());
+        assert_eq!(f.to_string(), "no faults");
+        f.load_faults = decode(LOAD_FAULTS, 0b11);
+        f.alarms_daily = decode(ALARMS, 1 << 6);
+        assert!(!f.is_empty());
+        assert_eq!(
+            f.to_string(),
+            "load faults: external_short, overcurrent; alarms today: current_limit"
+        );
+    }
+
+    #[test]
+    fn stats_json() {
+        let st = ControllerStats::ProstarMppt(ps::Stats::default());
+        let s = Stats::V6 {
+            timestamp: chrono::Local::now(),
+            name: String::from("east"),
+            controller: Some(st),
+            aggregate: None,
+        };
+        let v = s.to_json_with_faults().unwrap();
+        let faults: Faults = serde_json::from_value(v["V6"]["faults"].clone()).unwrap();
+        assert_eq!(faults, st.faults());
+        // the extra field doesn't stop it parsing as stats
+        let s: Stats = serde_json::from_value(v).unwrap();
+        assert_eq!(s.name(), "east");
+    }
+}
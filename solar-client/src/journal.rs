@@ -0,0 +1,99 @@
+//! The settings journal. Every settings change the daemon applies is
+//! appended as a json line holding the settings before and after the
+//! change, so any change can be reviewed or undone later.
+use crate::{profile, Config, ControllerSettings, Source};
+use anyhow::Result;
+use chrono::prelude::*;
+use serde_json::Value;
+use std::{
+    fmt,
+    fs::{self, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub timestamp: DateTime<Local>,
+    pub controller: String,
+    pub source: Source,
+    /// None if the settings could not be read before the write
+    pub old: Option<ControllerSettings>,
+    /// the settings read back from the controller after the write, or
+    /// the settings sent if they could not be read back
+    pub new: ControllerSettings,
+    /// false if the settings could not be read back after the write
+    #[serde(default = "default_verified")]
+    pub verified: bool,
+}
+
+fn default_verified() -> bool {
+    true
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(fmt, "{} {} from {}", self.timestamp, self.controller, self.source)?;
+        if !self.verified {
+            writeln!(fmt, "  unverified, reading the settings back failed")?
+        }
+        match &self.old {
+            None => writeln!(fmt, "  previous settings unknown"),
+            Some(old) => match profile::diff(old, &self.new) {
+                Err(e) => writeln!(fmt, "  {}", e),
+                Ok(d) if d.is_empty() => writeln!(fmt, "  no changes"),
+                Ok(d) => {
+                    for (field, old, new) in d {
+                        writeln!(fmt, "  {}: {} -> {}", field, old, new)?
+                    }
+                    Ok(())
+                }
+            },
+        }
+    }
+}
+
+pub fn append(cfg: &Config, entry: &Entry) -> Result<()> {
+    let mut buf = serde_json::to_vec(entry)?;
+    buf.push(b'\n');
+    let mut file =
+        OpenOptions::new().append(true).create(true).open(cfg.settings_journal())?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// All the journal entries, oldest first
+pub fn read(cfg: &Config) -> Result<Vec<Entry>> {
+    let file = match fs::File::open(cfg.settings_journal()) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(e) => entries.push(e),
+            Err(e) => warn!("skipping unreadable journal entry {}", e),
+        }
+    }
+    Ok(entries)
+}
+
+fn close(a: &Value, b: &Value) -> bool {
+    match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => (a - b).abs() <= 1e-3 * a.abs().max(b.abs()).max(1.),
+        (_, _) => a == b,
+    }
+}
+
+/// The fields where the settings read back from a controller differ
+/// from the settings written. The controller stores some values at
+/// reduced precision, so small numeric differences are ignored.
+pub fn mismatches(
+    written: &ControllerSettings,
+    read: &ControllerSettings,
+) -> Result<Vec<(String, Value, Value)>> {
+    let mut d = profile::diff(written, read)?;
+    d.retain(|(_, a, b)| !close(a, b));
+    Ok(d)
+}
@@ -18,8 +18,118 @@ use std::{
 };
 
 pub mod archive;
+pub mod events;
+pub mod export;
+pub mod faults;
+pub mod journal;
+pub mod notify;
+pub mod profile;
+pub mod rules;
+pub mod schedule;
+pub mod validate;
+pub mod verify;
+
+pub static DEFAULT_CONTROLLER: &str = "default";
+
+/// The charge controller models the daemon knows how to talk to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Model {
+    ProstarMppt,
+}
+
+impl Default for Model {
+    fn default() -> Self {
+        Model::ProstarMppt
+    }
+}
+
+/// What a controller supports, so clients only offer what will work
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Capabilities {
+    pub charging_control: bool,
+    pub load_control: bool,
+    pub reset: bool,
+    pub settings: bool,
+    pub register_access: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControllerInfo {
+    pub name: String,
+    pub model: Model,
+    pub capabilities: Capabilities,
+}
+
+impl fmt::Display for ControllerInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let c = &self.capabilities;
+        write!(
+            f,
+            "{}: {:?} charging: {} load: {} reset: {} settings: {} registers: {}",
+            self.name,
+            self.model,
+            c.charging_control,
+            c.load_control,
+            c.reset,
+            c.settings,
+            c.register_access
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ControllerStats {
+    ProstarMppt(ps::Stats),
+}
+
+impl ControllerStats {
+    pub fn model(&self) -> Model {
+        match self {
+            ControllerStats::ProstarMppt(_) => Model::ProstarMppt,
+        }
+    }
+
+    /// the fault and alarm bits decoded into names
+    pub fn faults(&self) -> faults::Faults {
+        faults::Faults::new(self)
+    }
+}
+
+impl fmt::Display for ControllerStats {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControllerStats::ProstarMppt(s) => s.fmt(fmt)?,
+        }
+        let faults = self.faults();
+        if !faults.is_empty() {
+            write!(fmt, "\n{}", faults)?
+        }
+        Ok(())
+    }
+}
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum ControllerSettings {
+    ProstarMppt(ps::Settings),
+}
+
+impl ControllerSettings {
+    pub fn model(&self) -> Model {
+        match self {
+            ControllerSettings::ProstarMppt(_) => Model::ProstarMppt,
+        }
+    }
+}
+
+impl fmt::Display for ControllerSettings {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ControllerSettings::ProstarMppt(s) => s.fmt(fmt),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FromClient {
     SetCharging(bool),
     SetLoad(bool),
@@ -28,7 +138,120 @@ pub enum FromClient {
     Stop,
     TailStats,
     ReadSettings,
-    WriteSettings(ps::Settings),
+    WriteSettings(ControllerSettings),
+    ListControllers,
+    /// Write the named settings profile to the controller
+    ApplyProfile(String),
+    /// The state of the scheduled switches
+    ScheduleStatus,
+    /// The state of the automation rules
+    RuleStatus,
+    /// The state of the daemon's archiver
+    ArchiveStatus,
+    /// Direct the wrapped command at the named controller. Commands
+    /// without a target go to the first configured controller.
+    Target(String, Box<FromClient>),
+    /// Record where the wrapped command came from. Commands arriving on
+    /// the control socket without a source are from the cli.
+    Source(Source, Box<FromClient>),
+    /// Run the steps in order on one controller with nothing else
+    /// interleaved. If a step fails the undo actions of the steps that
+    /// already succeeded are run in reverse order.
+    Transaction(Vec<Step>),
+}
+
+/// Where a command came from, recorded in the settings journal
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Source {
+    Cli,
+    Netidx,
+    Gui,
+    Schedule,
+    Rules,
+    Mqtt,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Cli => write!(fmt, "cli"),
+            Source::Netidx => write!(fmt, "netidx"),
+            Source::Gui => write!(fmt, "gui"),
+            Source::Schedule => write!(fmt, "schedule"),
+            Source::Rules => write!(fmt, "rules"),
+            Source::Mqtt => write!(fmt, "mqtt"),
+        }
+    }
+}
+
+/// One step of a transaction, and the action that compensates for it
+/// if a later step fails.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Step {
+    pub action: FromClient,
+    pub undo: Option<FromClient>,
+}
+
+impl Step {
+    pub fn new(action: FromClient) -> Self {
+        Step { action, undo: None }
+    }
+
+    pub fn undo(mut self, undo: FromClient) -> Self {
+        self.undo = Some(undo);
+        self
+    }
+}
+
+impl FromClient {
+    pub fn target(self, name: Option<&str>) -> Self {
+        match name {
+            None => self,
+            Some(name) => FromClient::Target(name.into(), Box::new(self)),
+        }
+    }
+
+    /// split a command into it's target controller, if any, and the
+    /// command itself. The innermost target wins.
+    pub fn untarget(self) -> (Option<String>, FromClient) {
+        match self {
+            FromClient::Target(name, cmd) => {
+                let (inner, cmd) = cmd.untarget();
+                (inner.or(Some(name)), cmd)
+            }
+            cmd => (None, cmd),
+        }
+    }
+
+    /// Switch the load with charging suspended. Charging is resumed
+    /// even if switching the load fails.
+    pub fn set_load(on: bool) -> Self {
+        FromClient::Transaction(vec![
+            Step::new(FromClient::SetCharging(false)).undo(FromClient::SetCharging(true)),
+            Step::new(FromClient::SetLoad(on)),
+            Step::new(FromClient::SetCharging(true)),
+        ])
+    }
+
+    pub fn source(self, source: Source) -> Self {
+        FromClient::Source(source, Box::new(self))
+    }
+
+    /// remove the source from a command, keeping any target. The
+    /// innermost source wins.
+    pub fn unsource(self) -> (Option<Source>, FromClient) {
+        match self {
+            FromClient::Source(source, cmd) => {
+                let (inner, cmd) = cmd.unsource();
+                (inner.or(Some(source)), cmd)
+            }
+            FromClient::Target(name, cmd) => {
+                let (source, cmd) = cmd.unsource();
+                (source, FromClient::Target(name, Box::new(cmd)))
+            }
+            cmd => (None, cmd),
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -48,7 +271,7 @@ impl fmt::Display for Phy {
     }
 }
 
-#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Stats {
     V0(ps::Stats),
     V1 {
@@ -63,33 +286,120 @@ pub enum Stats {
     V3 {
         timestamp: chrono::DateTime<chrono::offset::Local>,
         controller: Option<ps::Stats>,
+    },
+    V4 {
+        timestamp: chrono::DateTime<chrono::offset::Local>,
+        name: String,
+        controller: Option<ps::Stats>,
+    },
+    V5 {
+        timestamp: chrono::DateTime<chrono::offset::Local>,
+        name: String,
+        controller: Option<ControllerStats>,
+    },
+    /// Aggregate records hold the mean of a window of samples in
+    /// controller, counters and states are from the latest sample.
+    V6 {
+        timestamp: chrono::DateTime<chrono::offset::Local>,
+        name: String,
+        controller: Option<ControllerStats>,
+        aggregate: Option<Aggregate>,
+    },
+}
+
+/// How an aggregate record was computed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Aggregate {
+    /// the number of samples the controller could be read in
+    pub samples: u32,
+    /// the minimum of each averaged field
+    pub min: ControllerStats,
+    /// the maximum of each averaged field
+    pub max: ControllerStats,
+}
+
+impl Aggregate {
+    /// a single sample
+    pub fn new(st: &ControllerStats) -> Self {
+        Aggregate { samples: 1, min: *st, max: *st }
     }
 }
 
 impl Stats {
     fn upgrade(self) -> Self {
+        let name = String::from(DEFAULT_CONTROLLER);
+        let prostar = ControllerStats::ProstarMppt;
         match self {
-            Stats::V3 { .. } => self,
-            Stats::V2 { timestamp, controller, phy: _ } => Stats::V3 {
-                timestamp, controller
+            Stats::V6 { .. } => self,
+            Stats::V5 { timestamp, name, controller } => {
+                Stats::V6 { timestamp, name, controller, aggregate: None }
+            }
+            Stats::V4 { timestamp, name, controller } => Stats::V6 {
+                timestamp,
+                name,
+                controller: controller.map(prostar),
+                aggregate: None,
+            },
+            Stats::V3 { timestamp, controller } => Stats::V6 {
+                timestamp,
+                name,
+                controller: controller.map(prostar),
+                aggregate: None,
+            },
+            Stats::V2 { timestamp, controller, phy: _ } => Stats::V6 {
+                timestamp,
+                name,
+                controller: controller.map(prostar),
+                aggregate: None,
             },
-            Stats::V1 { controller, phy: _ } => Stats::V3 {
+            Stats::V1 { controller, phy: _ } => Stats::V6 {
                 timestamp: controller.timestamp,
-                controller: Some(controller),
+                name,
+                controller: Some(prostar(controller)),
+                aggregate: None,
             },
-            Stats::V0(st) => Stats::V3 {
+            Stats::V0(st) => Stats::V6 {
                 timestamp: st.timestamp,
-                controller: Some(st),
+                name,
+                controller: Some(prostar(st)),
+                aggregate: None,
             },
         }
     }
 
+    /// the name of the controller these stats came from
+    pub fn name(&self) -> &str {
+        match self {
+            Stats::V4 { ref name, .. }
+            | Stats::V5 { ref name, .. }
+            | Stats::V6 { ref name, .. } => name.as_str(),
+            Stats::V0(_) | Stats::V1 { .. } | Stats::V2 { .. } | Stats::V3 { .. } => {
+                DEFAULT_CONTROLLER
+            }
+        }
+    }
+
     pub fn timestamp(&self) -> chrono::DateTime<chrono::offset::Local> {
         match self {
             Stats::V0(ref s) => s.timestamp,
             Stats::V1 { controller: ref c, .. } => c.timestamp,
             Stats::V2 { ref timestamp, .. } => *timestamp,
             Stats::V3 { ref timestamp, .. } => *timestamp,
+            Stats::V4 { ref timestamp, .. } => *timestamp,
+            Stats::V5 { ref timestamp, .. } => *timestamp,
+            Stats::V6 { ref timestamp, .. } => *timestamp,
+        }
+    }
+
+    /// the controller's stats, None if it couldn't be read
+    pub fn controller(&self) -> Option<ControllerStats> {
+        let prostar = ControllerStats::ProstarMppt;
+        match self {
+            Stats::V0(s) | Stats::V1 { controller: s, .. } => Some(prostar(*s)),
+            Stats::V2 { controller, .. }
+            | Stats::V3 { controller, .. }
+            | Stats::V4 { controller, .. } => controller.map(prostar),
+            Stats::V5 { controller, .. } | Stats::V6 { controller, .. } => *controller,
         }
     }
 
@@ -99,8 +409,28 @@ impl Stats {
             Stats::V1 { controller: ref mut c, .. } => &mut c.timestamp,
             Stats::V2 { ref mut timestamp, .. } => timestamp,
             Stats::V3 { ref mut timestamp, .. } => timestamp,
+            Stats::V4 { ref mut timestamp, .. } => timestamp,
+            Stats::V5 { ref mut timestamp, .. } => timestamp,
+            Stats::V6 { ref mut timestamp, .. } => timestamp,
         }
     }
+
+    /// The stats as json with the fault and alarm bits also decoded
+    /// into names, under "faults" next to the other fields. It still
+    /// parses as `Stats`.
+    pub fn to_json_with_faults(&self) -> serde_json::Result<serde_json::Value> {
+        let mut v = serde_json::to_value(self)?;
+        if let Some(st) = self.controller() {
+            let fields = v
+                .as_object_mut()
+                .and_then(|m| m.values_mut().next())
+                .and_then(|v| v.as_object_mut());
+            if let Some(fields) = fields {
+                fields.insert(String::from("faults"), serde_json::to_value(st.faults())?);
+            }
+        }
+        Ok(v)
+    }
 }
 
 impl fmt::Display for Stats {
@@ -126,6 +456,33 @@ impl fmt::Display for Stats {
                     None => write!(fmt, "controller off"),
                 }
             }
+            Stats::V4 { timestamp, name, controller } => {
+                write!(fmt, "{} ", name)?;
+                timestamp.fmt(fmt)?;
+                match controller {
+                    Some(s) => s.fmt(fmt),
+                    None => write!(fmt, "controller off"),
+                }
+            }
+            Stats::V5 { timestamp, name, controller } => {
+                write!(fmt, "{} ", name)?;
+                timestamp.fmt(fmt)?;
+                match controller {
+                    Some(s) => s.fmt(fmt),
+                    None => write!(fmt, "controller off"),
+                }
+            }
+            Stats::V6 { timestamp, name, controller, aggregate } => {
+                write!(fmt, "{} ", name)?;
+                timestamp.fmt(fmt)?;
+                if let Some(a) = aggregate {
+                    write!(fmt, " mean of {} samples", a.samples)?;
+                }
+                match controller {
+                    Some(s) => s.fmt(fmt),
+                    None => write!(fmt, "controller off"),
+                }
+            }
         }
     }
 }
@@ -133,7 +490,11 @@ impl fmt::Display for Stats {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ToClient {
     Stats(Stats),
-    Settings(ps::Settings),
+    Settings(ControllerSettings),
+    Controllers(Vec<ControllerInfo>),
+    Schedule(Vec<schedule::ScheduleStatus>),
+    Rules(Vec<rules::RuleStatus>),
+    Archive(archive::ArchiveStatus),
     Ok,
     Err(String),
 }
@@ -154,8 +515,9 @@ impl std::fmt::Display for UnexpectedObjectKind {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArchivedDay {
     pub all: PathBuf,
-    pub one_minute_averages: PathBuf,
-    pub ten_minute_averages: PathBuf,
+    /// the aggregated stats at each of the configured resolutions
+    pub aggregates: Vec<(archive::Bucket, PathBuf)>,
+    pub events: PathBuf,
 }
 
 impl ArchivedDay {
@@ -179,16 +541,119 @@ impl ArchivedDay {
     }
 
     pub fn exists(&self) -> std::result::Result<bool, Box<dyn std::error::Error>> {
+        for (_, path) in &self.aggregates {
+            if ArchivedDay::file_exists(path)? {
+                return Ok(true);
+            }
+        }
         Ok(ArchivedDay::file_exists(&self.all)?
-            || ArchivedDay::file_exists(&self.one_minute_averages)?
-            || ArchivedDay::file_exists(&self.ten_minute_averages)?)
+            || ArchivedDay::file_exists(&self.events)?)
     }
 }
 
+fn default_max_charge_rate() -> f32 {
+    0.2
+}
+
+/// The battery bank a controller charges, used to check settings
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Config {
+pub struct BatteryConfig {
+    /// capacity in amp hours
+    pub capacity: f32,
+    /// the highest charge current allowed as a fraction of capacity,
+    /// 0.2 (C/5) if not specified
+    #[serde(default = "default_max_charge_rate")]
+    pub max_charge_rate: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ControllerConfig {
+    pub name: String,
+    #[serde(default)]
+    pub model: Model,
     pub device: String,
     pub modbus_id: u8,
+    #[serde(default)]
+    pub battery: Option<BatteryConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModbusGatewayConfig {
+    /// the address to listen on, e.g. 0.0.0.0:502
+    pub listen: String,
+    /// allow clients to write registers and coils, otherwise the
+    /// gateway is read only
+    #[serde(default)]
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetricsConfig {
+    /// the address to serve OpenMetrics on, e.g. 0.0.0.0:9100
+    pub listen: String,
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    String::from("solar")
+}
+
+fn default_mqtt_prefix() -> String {
+    String::from("solar")
+}
+
+fn default_discovery_prefix() -> String {
+    String::from("homeassistant")
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MqttConfig {
+    /// the broker's host name or address
+    pub host: String,
+    /// 1883 if not specified
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    /// "solar" if not specified
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// stats are published under prefix/controller/field, "solar" if
+    /// not specified
+    #[serde(default = "default_mqtt_prefix")]
+    pub prefix: String,
+    /// where Home Assistant looks for discovery configs,
+    /// "homeassistant" if not specified
+    #[serde(default = "default_discovery_prefix")]
+    pub discovery_prefix: String,
+}
+
+fn default_archive_resolutions() -> Vec<archive::Bucket> {
+    vec!["1m".parse().unwrap(), "10m".parse().unwrap()]
+}
+
+fn default_auto_archive() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Config {
+    /// a single controller called "default". When it's the only
+    /// controller it's published directly under netidx_base.
+    #[serde(default)]
+    pub device: Option<String>,
+    #[serde(default)]
+    pub modbus_id: Option<u8>,
+    #[serde(default)]
+    pub battery: Option<BatteryConfig>,
+    /// each controller is published under netidx_base/name
+    #[serde(default)]
+    pub controllers: Vec<ControllerConfig>,
     pub run_directory: PathBuf,
     pub archive_directory: PathBuf,
     pub stats_interval: u64,
@@ -196,6 +661,34 @@ pub struct Config {
     pub netidx_base: String,
     pub netidx_bind: String,
     pub netidx_spn: Option<String>,
+    #[serde(default)]
+    pub modbus_gateway: Option<ModbusGatewayConfig>,
+    #[serde(default)]
+    pub metrics: Option<MetricsConfig>,
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub schedule: Option<schedule::ScheduleConfig>,
+    #[serde(default)]
+    pub rules: Vec<rules::Rule>,
+    #[serde(default)]
+    pub notify: Option<notify::NotifyConfig>,
+    /// the resolutions archived stats are aggregated at, e.g. 1m, 10m,
+    /// 1h, 1d. 1m and 10m if not specified.
+    #[serde(default = "default_archive_resolutions")]
+    pub archive_resolutions: Vec<archive::Bucket>,
+    /// the daemon rotates the stats and event logs at local midnight
+    /// and archives the day that ended. true if not specified, turn
+    /// it off to archive with `solar archive` from cron instead.
+    #[serde(default = "default_auto_archive")]
+    pub auto_archive: bool,
+    /// how long archives are kept, forever if not specified
+    #[serde(default)]
+    pub retention: Option<archive::RetentionConfig>,
+    /// where settings profiles are kept, run_directory/profiles if
+    /// not specified
+    #[serde(default)]
+    pub profile_directory: Option<PathBuf>,
 }
 
 fn cat_paths(p0: impl AsRef<Path>, p1: impl AsRef<Path>) -> PathBuf {
@@ -206,6 +699,23 @@ fn cat_paths(p0: impl AsRef<Path>, p1: impl AsRef<Path>) -> PathBuf {
 }
 
 impl Config {
+    /// All the configured controllers. A config with only a top level
+    /// `device` gets a single controller called "default".
+    pub fn controllers(&self) -> Vec<ControllerConfig> {
+        let mut res = Vec::new();
+        if let Some(device) = &self.device {
+            res.push(ControllerConfig {
+                name: String::from(DEFAULT_CONTROLLER),
+                model: Model::default(),
+                device: device.clone(),
+                modbus_id: self.modbus_id.unwrap_or(1),
+                battery: self.battery.clone(),
+            })
+        }
+        res.extend(self.controllers.iter().cloned());
+        res
+    }
+
     pub fn pid_file(&self) -> PathBuf {
         cat_paths(&self.run_directory, "solar.pid")
     }
@@ -218,16 +728,55 @@ impl Config {
         cat_paths(&self.run_directory, "solar.log")
     }
 
+    pub fn event_log(&self) -> PathBuf {
+        cat_paths(&self.run_directory, "events.log")
+    }
+
+    /// the stats log of the given day, after the daemon rotated it
+    pub fn rotated_log(&self, date: NaiveDate) -> PathBuf {
+        cat_paths(&self.run_directory, format!("solar.log-{}", date.format("%Y%m%d")))
+    }
+
+    /// the event log of the given day, after the daemon rotated it
+    pub fn rotated_event_log(&self, date: NaiveDate) -> PathBuf {
+        let name = format!("events.log-{}", date.format("%Y%m%d"));
+        cat_paths(&self.run_directory, name)
+    }
+
+    pub fn settings_journal(&self) -> PathBuf {
+        cat_paths(&self.run_directory, "settings.journal")
+    }
+
+    pub fn profile_dir(&self) -> PathBuf {
+        match &self.profile_directory {
+            Some(dir) => dir.clone(),
+            None => cat_paths(&self.run_directory, "profiles"),
+        }
+    }
+
     fn archive_for_date_pfx(&self, date: Date<Local>, pfx: &str) -> PathBuf {
         let d = date.format("%Y%m%d");
         cat_paths(&self.archive_directory, format!("solar.log-{}{}.gz", d, pfx))
     }
 
+    /// the archive of stats aggregated at any resolution, configured
+    /// or not
+    pub fn aggregate_for_date(&self, date: Date<Local>, b: archive::Bucket) -> PathBuf {
+        self.archive_for_date_pfx(date, &b.to_string())
+    }
+
     pub fn archive_for_date(&self, date: Date<Local>) -> ArchivedDay {
         ArchivedDay {
             all: self.archive_for_date_pfx(date, ""),
-            one_minute_averages: self.archive_for_date_pfx(date, "1m"),
-            ten_minute_averages: self.archive_for_date_pfx(date, "10m"),
+            aggregates: self
+                .archive_resolutions
+                .iter()
+                .map(|b| (*b, self.aggregate_for_date(date, *b)))
+                .collect(),
+            events: cat_paths(
+                &self.archive_directory,
+                format!("solar.events-{}.gz", date.format("%Y%m%d")),
+            ),
         }
     }
 }
@@ -255,9 +804,12 @@ pub fn send_command(
         match serde_json::from_str(&line)? {
             ToClient::Ok => (),
             ToClient::Err(e) => bail!(e),
-            ToClient::Settings(_) | ToClient::Stats(_) => {
-                bail!("got unexpected command reply")
-            }
+            ToClient::Settings(_)
+            | ToClient::Stats(_)
+            | ToClient::Controllers(_)
+            | ToClient::Schedule(_)
+            | ToClient::Rules(_)
+            | ToClient::Archive(_) => bail!("got unexpected command reply"),
         }
     }
     Ok(())
@@ -0,0 +1,108 @@
+//! Notifications sent by the daemon when something needs attention.
+//! Conditions are raised and cleared, a notification is sent when a
+//! condition changes, but no more often than once per `repeat_after`
+//! seconds for the same condition, so a flapping condition produces
+//! at most one raised and one cleared notification per interval.
+use chrono::prelude::*;
+use std::fmt;
+
+/// The conditions that produce notifications
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Trigger {
+    /// a fault or alarm bit was set or cleared
+    Fault,
+    /// the load was disconnected because the battery is low
+    Lvd,
+    /// the controller could not be read for `unreachable_after` minutes
+    Unreachable,
+    /// the battery is hotter than `battery_temperature`
+    BatteryTemperature,
+}
+
+impl fmt::Display for Trigger {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Trigger::Fault => write!(fmt, "fault"),
+            Trigger::Lvd => write!(fmt, "lvd"),
+            Trigger::Unreachable => write!(fmt, "unreachable"),
+            Trigger::BatteryTemperature => write!(fmt, "battery temperature"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Sink {
+    /// run program with the notification as json on stdin
+    Command {
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    /// log the notification to the local syslog
+    Syslog,
+    /// POST the notification as json to url
+    Webhook { url: String },
+}
+
+fn default_unreachable_after() -> u64 {
+    5
+}
+
+fn default_battery_temperature() -> f32 {
+    45.
+}
+
+fn default_repeat_after() -> u64 {
+    3600
+}
+
+fn default_max_per_hour() -> usize {
+    20
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NotifyConfig {
+    pub sinks: Vec<Sink>,
+    /// the conditions to notify about, all of them if empty
+    #[serde(default)]
+    pub triggers: Vec<Trigger>,
+    /// minutes, 5 if not specified
+    #[serde(default = "default_unreachable_after")]
+    pub unreachable_after: u64,
+    /// degrees celsius, 45 if not specified. The condition clears 5
+    /// degrees below.
+    #[serde(default = "default_battery_temperature")]
+    pub battery_temperature: f32,
+    /// the minimum seconds between notifications about the same
+    /// condition, 3600 if not specified
+    #[serde(default = "default_repeat_after")]
+    pub repeat_after: u64,
+    /// the most notifications sent in any hour, 20 if not specified
+    #[serde(default = "default_max_per_hour")]
+    pub max_per_hour: usize,
+}
+
+impl NotifyConfig {
+    pub fn enabled(&self, t: Trigger) -> bool {
+        self.triggers.is_empty() || self.triggers.contains(&t)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub timestamp: DateTime<Local>,
+    pub controller: String,
+    pub trigger: Trigger,
+    /// what raised the condition, e.g. the name of the fault
+    pub subject: String,
+    /// true when the condition is raised, false when it clears
+    pub active: bool,
+    pub message: String,
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let state = if self.active { "raised" } else { "cleared" };
+        write!(fmt, "{} {} {}: {}", self.controller, self.trigger, state, self.message)
+    }
+}
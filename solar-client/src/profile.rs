@@ -0,0 +1,169 @@
+//! Named settings profiles. Each profile is a json file in the profile
+//! directory holding the model tagged settings, e.g. summer.json,
+//! winter.json, equalize.json.
+use crate::{Config, ControllerSettings};
+use anyhow::Result;
+use morningstar::prostar_mppt as ps;
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+static EXT: &str = "json";
+
+fn check_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(|c: char| c == '/' || c == '\0')
+    {
+        bail!("invalid profile name {:?}", name)
+    }
+    Ok(())
+}
+
+fn path(cfg: &Config, name: &str) -> Result<PathBuf> {
+    check_name(name)?;
+    let mut path = cfg.profile_dir();
+    // not set_extension, it would replace anything after a '.' in the
+    // name
+    path.push(format!("{}.{}", name, EXT));
+    Ok(path)
+}
+
+/// Parse settings. Files written before model support hold bare
+/// prostar settings, those are accepted too.
+pub fn parse(v: Value) -> Result<ControllerSettings> {
+    match serde_json::from_value::<ControllerSettings>(v.clone()) {
+        Ok(s) => Ok(s),
+        Err(_) => {
+            let s = serde_json::from_value::<ps::Settings>(v)?;
+            Ok(ControllerSettings::ProstarMppt(s))
+        }
+    }
+}
+
+pub fn load(cfg: &Config, name: &str) -> Result<ControllerSettings> {
+    let path = path(cfg, name)?;
+    let file = match fs::File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            bail!("no such profile {}", name)
+        }
+        Err(e) => bail!("failed to open profile {}: {}", name, e),
+    };
+    parse(serde_json::from_reader(file)?)
+}
+
+/// Save settings as the named profile, replacing any existing profile
+/// with that name.
+pub fn save(cfg: &Config, name: &str, settings: &ControllerSettings) -> Result<()> {
+    let path = path(cfg, name)?;
+    fs::create_dir_all(cfg.profile_dir())?;
+    // write to a temporary and rename so a crash never leaves a
+    // truncated profile behind
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    serde_json::to_writer_pretty(&mut file, settings)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
+pub fn list(cfg: &Config) -> Result<Vec<String>> {
+    let dir = match fs::read_dir(cfg.profile_dir()) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut names = Vec::new();
+    for ent in dir {
+        let path = ent?.path();
+        if path.extension().map(|e| e == EXT).unwrap_or(false) {
+            if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                names.push(name.into());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+fn flatten(pfx: &str, v: &Value, acc: &mut BTreeMap<String, Value>) {
+    match v {
+        Value::Object(m) => {
+            for (k, v) in m {
+                let pfx =
+                    if pfx.is_empty() { k.clone() } else { format!("{}.{}", pfx, k) };
+                flatten(&pfx, v, acc)
+            }
+        }
+        v => {
+            acc.insert(pfx.into(), v.clone());
+        }
+    }
+}
+
+/// The fields that differ between two sets of settings, as (field,
+/// value in a, value in b). A field missing on one side is null.
+pub fn diff(
+    a: &ControllerSettings,
+    b: &ControllerSettings,
+) -> Result<Vec<(String, Value, Value)>> {
+    let (mut fa, mut fb) = (BTreeMap::new(), BTreeMap::new());
+    flatten("", &serde_json::to_value(a)?, &mut fa);
+    flatten("", &serde_json::to_value(b)?, &mut fb);
+    let mut res = Vec::new();
+    for (k, va) in &fa {
+        match fb.remove(k) {
+            Some(vb) if &vb == va => (),
+            Some(vb) => res.push((k.clone(), va.clone(), vb)),
+            None => res.push((k.clone(), va.clone(), Value::Null)),
+        }
+    }
+    for (k, vb) in fb {
+        res.push((k, Value::Null, vb));
+    }
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Config {
+        serde_json::from_str(
+            r#"{
+                "run_directory": "/var/run/solar",
+                "archive_directory": "/var/lib/solar",
+                "stats_interval": 1,
+                "log_level": "Info",
+                "netidx_base": "/solar",
+                "netidx_bind": "local",
+                "netidx_spn": null
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn dotted_names() {
+        let cfg = config();
+        let p = path(&cfg, "summer.v2").unwrap();
+        assert_eq!(p, PathBuf::from("/var/run/solar/profiles/summer.v2.json"));
+        assert_ne!(p, path(&cfg, "summer.v3").unwrap());
+        assert_eq!(p.file_stem().and_then(|s| s.to_str()), Some("summer.v2"));
+    }
+
+    #[test]
+    fn invalid_names() {
+        let cfg = config();
+        for name in &["", ".hidden", "a/b", "a\0b"] {
+            assert!(path(&cfg, name).is_err(), "{:?}", name)
+        }
+    }
+}
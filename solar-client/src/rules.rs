@@ -0,0 +1,375 @@
+//! Rules that act on live stats. A rule watches one numeric field of a
+//! controller's stats. When the trigger condition has held for the
+//! dwell time the rule becomes active and it's action is sent to the
+//! controller. It stays active until the release condition has held
+//! for the release dwell time, then the release action, if any, is
+//! sent. Using a release threshold apart from the trigger threshold
+//! gives hysteresis, e.g. load off below 11.9V, back on above 12.8V.
+//!
+//! Fields are read in volts, amps, degrees celsius, watts, amp hours,
+//! kilowatt hours and hours.
+use crate::{ControllerStats, FromClient};
+use anyhow::Result;
+use chrono::prelude::*;
+use morningstar::prostar_mppt as ps;
+use std::{collections::HashSet, fmt};
+use uom::si::{
+    electric_charge::ampere_hour, electric_current::ampere, electric_potential::volt,
+    energy::kilowatt_hour, power::watt, thermodynamic_temperature::degree_celsius,
+    time::hour,
+};
+
+macro_rules! fields {
+    ($($fld:ident($unit:expr) => $get:expr),* $(,)?) => {
+        /// The names of the stats fields rules can watch
+        pub static FIELDS: &[&str] = &[$(stringify!($fld)),*];
+
+        /// The unit the named field is read in
+        pub fn unit(name: &str) -> Option<&'static str> {
+            match name {
+                $(stringify!($fld) => Some($unit),)*
+                _ => None,
+            }
+        }
+
+        fn prostar_mppt(st: &ps::Stats, name: &str) -> Option<f32> {
+            match name {
+                $(stringify!($fld) => {
+                    let f: fn(&ps::Stats) -> Option<f32> = $get;
+                    f(st)
+                })*
+                _ => None,
+            }
+        }
+    };
+}
+
+fields! {
+    supply_3v3("volts") => |s| Some(s.supply_3v3.get::<volt>()),
+    supply_12v("volts") => |s| Some(s.supply_12v.get::<volt>()),
+    supply_5v("volts") => |s| Some(s.supply_5v.get::<volt>()),
+    gate_drive_voltage("volts") => |s| Some(s.gate_drive_voltage.get::<volt>()),
+    battery_terminal_voltage("volts") =>
+        |s| Some(s.battery_terminal_voltage.get::<volt>()),
+    array_voltage("volts") => |s| Some(s.array_voltage.get::<volt>()),
+    load_voltage("volts") => |s| Some(s.load_voltage.get::<volt>()),
+    charge_current("amperes") => |s| Some(s.charge_current.get::<ampere>()),
+    array_current("amperes") => |s| Some(s.array_current.get::<ampere>()),
+    load_current("amperes") => |s| Some(s.load_current.get::<ampere>()),
+    battery_current_net("amperes") => |s| Some(s.battery_current_net.get::<ampere>()),
+    battery_sense_voltage("volts") => |s| Some(s.battery_sense_voltage.get::<volt>()),
+    meterbus_voltage("volts") => |s| Some(s.meterbus_voltage.get::<volt>()),
+    heatsink_temperature("celsius") =>
+        |s| Some(s.heatsink_temperature.get::<degree_celsius>()),
+    battery_temperature("celsius") =>
+        |s| Some(s.battery_temperature.get::<degree_celsius>()),
+    ambient_temperature("celsius") =>
+        |s| Some(s.ambient_temperature.get::<degree_celsius>()),
+    rts_temperature("celsius") =>
+        |s| s.rts_temperature.map(|t| t.get::<degree_celsius>()),
+    battery_voltage_slow("volts") => |s| Some(s.battery_voltage_slow.get::<volt>()),
+    target_voltage("volts") => |s| Some(s.target_voltage.get::<volt>()),
+    ah_charge_resettable("amp_hours") =>
+        |s| Some(s.ah_charge_resettable.get::<ampere_hour>()),
+    ah_charge_total("amp_hours") => |s| Some(s.ah_charge_total.get::<ampere_hour>()),
+    kwh_charge_resettable("kilowatt_hours") =>
+        |s| Some(s.kwh_charge_resettable.get::<kilowatt_hour>()),
+    kwh_charge_total("kilowatt_hours") =>
+        |s| Some(s.kwh_charge_total.get::<kilowatt_hour>()),
+    lvd_setpoint("volts") => |s| Some(s.lvd_setpoint.get::<volt>()),
+    ah_load_resettable("amp_hours") =>
+        |s| Some(s.ah_load_resettable.get::<ampere_hour>()),
+    ah_load_total("amp_hours") => |s| Some(s.ah_load_total.get::<ampere_hour>()),
+    hourmeter("hours") => |s| Some(s.hourmeter.get::<hour>()),
+    array_power("watts") => |s| Some(s.array_power.get::<watt>()),
+    array_vmp("volts") => |s| Some(s.array_vmp.get::<volt>()),
+    array_max_power_sweep("watts") => |s| Some(s.array_max_power_sweep.get::<watt>()),
+    array_voc("volts") => |s| Some(s.array_voc.get::<volt>()),
+    battery_v_min_daily("volts") => |s| Some(s.battery_v_min_daily.get::<volt>()),
+    battery_v_max_daily("volts") => |s| Some(s.battery_v_max_daily.get::<volt>()),
+    ah_charge_daily("amp_hours") => |s| Some(s.ah_charge_daily.get::<ampere_hour>()),
+    ah_load_daily("amp_hours") => |s| Some(s.ah_load_daily.get::<ampere_hour>()),
+    array_voltage_max_daily("volts") => |s| Some(s.array_voltage_max_daily.get::<volt>()),
+    array_voltage_fixed("volts") => |s| Some(s.array_voltage_fixed.get::<volt>()),
+    array_voc_percent_fixed("percent") => |s| Some(s.array_voc_percent_fixed),
+}
+
+/// The value of the named field, None if the field doesn't exist or
+/// isn't available from this controller.
+pub fn field(st: &ControllerStats, name: &str) -> Option<f32> {
+    match st {
+        ControllerStats::ProstarMppt(s) => prostar_mppt(s, name),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Condition {
+    Above(f32),
+    Below(f32),
+}
+
+impl Condition {
+    pub fn holds(&self, v: f32) -> bool {
+        match self {
+            Condition::Above(t) => v > *t,
+            Condition::Below(t) => v < *t,
+        }
+    }
+
+    /// the opposite condition at the same threshold
+    pub fn inverse(&self) -> Condition {
+        match self {
+            Condition::Above(t) => Condition::Below(*t),
+            Condition::Below(t) => Condition::Above(*t),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Condition::Above(t) => write!(fmt, "> {}", t),
+            Condition::Below(t) => write!(fmt, "< {}", t),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rule {
+    pub name: String,
+    /// the controller to watch and act on, the first controller if not
+    /// specified
+    #[serde(default)]
+    pub controller: Option<String>,
+    /// the stats field to watch, see FIELDS
+    pub field: String,
+    pub trigger: Condition,
+    /// the inverse of trigger if not specified
+    #[serde(default)]
+    pub release: Option<Condition>,
+    /// seconds the trigger condition must hold before the rule fires
+    #[serde(default)]
+    pub dwell: u64,
+    /// seconds the release condition must hold before the rule
+    /// releases, the same as dwell if not specified
+    #[serde(default)]
+    pub release_dwell: Option<u64>,
+    /// sent to the controller when the rule fires
+    pub action: FromClient,
+    /// sent to the controller when the rule releases
+    #[serde(default)]
+    pub release_action: Option<FromClient>,
+}
+
+impl Rule {
+    pub fn release(&self) -> Condition {
+        self.release.unwrap_or_else(|| self.trigger.inverse())
+    }
+
+    pub fn release_dwell(&self) -> u64 {
+        self.release_dwell.unwrap_or(self.dwell)
+    }
+
+    /// the command to send when the rule fires
+    pub fn action(&self) -> FromClient {
+        self.action.clone()
+    }
+
+    /// the command to send when the rule releases
+    pub fn release_action(&self) -> Option<FromClient> {
+        self.release_action.clone()
+    }
+}
+
+// rule actions must be commands the controller can run, not queries or
+// daemon commands
+fn check_action(action: &FromClient, nested: bool) -> Result<()> {
+    match action {
+        FromClient::SetCharging(_)
+        | FromClient::SetLoad(_)
+        | FromClient::ResetController
+        | FromClient::WriteSettings(_)
+        | FromClient::ApplyProfile(_) => Ok(()),
+        FromClient::Transaction(steps) if !nested => {
+            for step in steps {
+                check_action(&step.action, true)?;
+                if let Some(undo) = &step.undo {
+                    check_action(undo, true)?
+                }
+            }
+            Ok(())
+        }
+        FromClient::Transaction(_) => bail!("transactions can't be nested"),
+        FromClient::LogRotated
+        | FromClient::Stop
+        | FromClient::TailStats
+        | FromClient::ReadSettings
+        | FromClient::ListControllers
+        | FromClient::ScheduleStatus
+        | FromClient::RuleStatus
+        | FromClient::ArchiveStatus
+        | FromClient::Source(_, _)
+        | FromClient::Target(_, _) => bail!("{:?} is not a controller command", action),
+    }
+}
+
+/// check that rule names are unique, every rule watches a field that
+/// exists, and it's actions are controller commands
+pub fn check(rules: &[Rule]) -> Result<()> {
+    let mut names = HashSet::new();
+    for r in rules {
+        if !names.insert(r.name.as_str()) {
+            bail!("duplicate rule name {}", r.name)
+        }
+        if !FIELDS.contains(&r.field.as_str()) {
+            bail!("rule {}: unknown field {}", r.name, r.field)
+        }
+        let (trigger, release) = (r.trigger, r.release());
+        let overlap = match (trigger, release) {
+            (Condition::Below(a), Condition::Above(b)) => b < a,
+            (Condition::Above(a), Condition::Below(b)) => b > a,
+            (_, _) => true,
+        };
+        if overlap {
+            bail!("rule {}: release {} overlaps trigger {}", r.name, release, trigger)
+        }
+        for a in std::iter::once(&r.action).chain(r.release_action.iter()) {
+            if let Err(e) = check_action(a, false) {
+                bail!("rule {}: invalid action {}", r.name, e)
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RuleState {
+    /// the trigger condition doesn't hold
+    Idle,
+    /// the trigger condition holds, waiting for the dwell time
+    Triggering,
+    /// the rule fired
+    Active,
+    /// the release condition holds, waiting for the release dwell time
+    Releasing,
+}
+
+impl RuleState {
+    pub fn is_active(&self) -> bool {
+        match self {
+            RuleState::Idle | RuleState::Triggering => false,
+            RuleState::Active | RuleState::Releasing => true,
+        }
+    }
+}
+
+impl fmt::Display for RuleState {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleState::Idle => write!(fmt, "idle"),
+            RuleState::Triggering => write!(fmt, "triggering"),
+            RuleState::Active => write!(fmt, "active"),
+            RuleState::Releasing => write!(fmt, "releasing"),
+        }
+    }
+}
+
+/// The state of one rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleStatus {
+    pub name: String,
+    pub controller: String,
+    pub field: String,
+    /// the last value seen, None before the first stats arrive
+    pub value: Option<f32>,
+    pub state: RuleState,
+    /// when the rule entered it's current state
+    pub since: DateTime<Local>,
+}
+
+impl fmt::Display for RuleStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{} ({} {}", self.name, self.controller, self.field)?;
+        match self.value {
+            None => write!(fmt, " unknown)")?,
+            Some(v) => write!(fmt, " {})", v)?,
+        }
+        write!(fmt, ": {} since {}", self.state, self.since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Step;
+
+    fn rule(name: &str, trigger: Condition, action: FromClient) -> Rule {
+        Rule {
+            name: String::from(name),
+            controller: None,
+            field: String::from("battery_terminal_voltage"),
+            trigger,
+            release: None,
+            dwell: 0,
+            release_dwell: None,
+            action,
+            release_action: None,
+        }
+    }
+
+    #[test]
+    fn valid_rules() {
+        let mut r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+        r.release = Some(Condition::Above(12.8));
+        r.release_action = Some(FromClient::SetLoad(true));
+        assert!(check(&[r]).is_ok())
+    }
+
+    #[test]
+    fn duplicate_names() {
+        let r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+        assert!(check(&[r.clone(), r]).is_err())
+    }
+
+    #[test]
+    fn unknown_field() {
+        let mut r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+        r.field = String::from("battery_volts");
+        assert!(check(&[r]).is_err())
+    }
+
+    #[test]
+    fn overlapping_release() {
+        let mut r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+        r.release = Some(Condition::Above(11.0));
+        assert!(check(&[r.clone()]).is_err());
+        r.release = Some(Condition::Below(13.0));
+        assert!(check(&[r]).is_err())
+    }
+
+    #[test]
+    fn non_command_actions() {
+        for a in vec![
+            FromClient::Stop,
+            FromClient::LogRotated,
+            FromClient::ReadSettings,
+            FromClient::RuleStatus,
+            FromClient::SetLoad(false).target(Some("east")),
+            FromClient::Transaction(vec![Step::new(FromClient::set_load(false))]),
+        ] {
+            let r = rule("lvd", Condition::Below(11.9), a.clone());
+            assert!(check(&[r]).is_err(), "{:?}", a);
+            let mut r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+            r.release_action = Some(a.clone());
+            assert!(check(&[r]).is_err(), "{:?}", a)
+        }
+    }
+
+    #[test]
+    fn load_actions_leave_charging_alone() {
+        let mut r = rule("lvd", Condition::Below(11.9), FromClient::SetLoad(false));
+        r.release_action = Some(FromClient::SetLoad(true));
+        assert!(matches!(r.action(), FromClient::SetLoad(false)));
+        assert!(matches!(r.release_action(), Some(FromClient::SetLoad(true))))
+    }
+}
@@ -0,0 +1,229 @@
+//! Time of day schedules for the load and charging switches. A switch
+//! with a schedule is on while any of it's windows is open and off
+//! otherwise. Window edges may be clock times or offsets from sunrise
+//! or sunset, e.g. "22:30", "sunset", "sunrise+30", "sunset-45"
+//! (minutes).
+use crate::FromClient;
+use anyhow::{Error, Result};
+use chrono::{prelude::*, Duration};
+use std::{convert::TryFrom, fmt, str::FromStr};
+
+/// The switches a schedule can drive
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Switch {
+    Load,
+    Charging,
+}
+
+impl Switch {
+    /// The command that sets the switch
+    pub fn command(&self, on: bool) -> FromClient {
+        match self {
+            Switch::Charging => FromClient::SetCharging(on),
+            Switch::Load => FromClient::SetLoad(on),
+        }
+    }
+
+    /// The switch a client command sets and the state it leaves it
+    /// in. In a transaction that switches the load, like `solar load`,
+    /// the charging steps only suspend charging around it.
+    pub fn of(cmd: &FromClient) -> Option<(Switch, bool)> {
+        match cmd {
+            FromClient::SetLoad(on) => Some((Switch::Load, *on)),
+            FromClient::SetCharging(on) => Some((Switch::Charging, *on)),
+            FromClient::Transaction(steps) => {
+                let load = steps.iter().find_map(|s| match s.action {
+                    FromClient::SetLoad(on) => Some((Switch::Load, on)),
+                    _ => None,
+                });
+                load.or_else(|| steps.iter().rev().find_map(|s| Switch::of(&s.action)))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Switch {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Switch::Load => write!(fmt, "load"),
+            Switch::Charging => write!(fmt, "charging"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum TimeSpec {
+    Clock(NaiveTime),
+    Sunrise(i64),
+    Sunset(i64),
+}
+
+impl FromStr for TimeSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let offset = |rest: &str| -> Result<i64> {
+            let rest = rest.trim();
+            if rest.is_empty() {
+                Ok(0)
+            } else if rest.starts_with('+') {
+                Ok(rest[1..].trim().parse::<i64>()?)
+            } else if rest.starts_with('-') {
+                Ok(-rest[1..].trim().parse::<i64>()?)
+            } else {
+                bail!("invalid offset {}", rest)
+            }
+        };
+        if s.starts_with("sunrise") {
+            Ok(TimeSpec::Sunrise(offset(&s["sunrise".len()..])?))
+        } else if s.starts_with("sunset") {
+            Ok(TimeSpec::Sunset(offset(&s["sunset".len()..])?))
+        } else {
+            match NaiveTime::parse_from_str(s, "%H:%M") {
+                Ok(t) => Ok(TimeSpec::Clock(t)),
+                Err(_) => {
+                    bail!("invalid time {}, expected HH:MM, sunrise[+-m], sunset[+-m]", s)
+                }
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for TimeSpec {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<TimeSpec> for String {
+    fn from(t: TimeSpec) -> String {
+        t.to_string()
+    }
+}
+
+impl fmt::Display for TimeSpec {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let off = |fmt: &mut fmt::Formatter, name: &str, m: i64| {
+            if m == 0 {
+                write!(fmt, "{}", name)
+            } else {
+                write!(fmt, "{}{:+}", name, m)
+            }
+        };
+        match self {
+            TimeSpec::Clock(t) => write!(fmt, "{}", t.format("%H:%M")),
+            TimeSpec::Sunrise(m) => off(fmt, "sunrise", *m),
+            TimeSpec::Sunset(m) => off(fmt, "sunset", *m),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Window {
+    /// the controller to switch, the first controller if not specified
+    #[serde(default)]
+    pub controller: Option<String>,
+    pub switch: Switch,
+    pub start: TimeSpec,
+    /// an end before the start closes the window the next day
+    pub end: TimeSpec,
+    /// the days the window opens on, every day if empty
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    /// location used to compute sunrise and sunset
+    #[serde(default)]
+    pub latitude: Option<f64>,
+    #[serde(default)]
+    pub longitude: Option<f64>,
+    pub windows: Vec<Window>,
+}
+
+impl ScheduleConfig {
+    /// check that every window can be resolved
+    pub fn check(&self) -> Result<()> {
+        for w in &self.windows {
+            for t in &[w.start, w.end] {
+                match t {
+                    TimeSpec::Clock(_) => (),
+                    TimeSpec::Sunrise(_) | TimeSpec::Sunset(_) => {
+                        if self.latitude.is_none() || self.longitude.is_none() {
+                            bail!("{} requires latitude and longitude", t)
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The time t falls on the given day, None if it doesn't exist
+    /// (e.g. it's skipped by a daylight savings change).
+    pub fn resolve(&self, t: TimeSpec, day: NaiveDate) -> Option<DateTime<Local>> {
+        let sun = |offset: i64, rise: bool| {
+            let (lat, lon) = (self.latitude?, self.longitude?);
+            let (sunrise, sunset) =
+                sunrise::sunrise_sunset(lat, lon, day.year(), day.month(), day.day());
+            let ts = if rise { sunrise } else { sunset };
+            Some(Local.timestamp(ts, 0) + Duration::minutes(offset))
+        };
+        match t {
+            TimeSpec::Clock(t) => Local.from_local_datetime(&day.and_time(t)).earliest(),
+            TimeSpec::Sunrise(offset) => sun(offset, true),
+            TimeSpec::Sunset(offset) => sun(offset, false),
+        }
+    }
+
+    /// The open intervals of window w that start on the given day
+    pub fn interval(
+        &self,
+        w: &Window,
+        day: NaiveDate,
+    ) -> Option<(DateTime<Local>, DateTime<Local>)> {
+        if !w.weekdays.is_empty() && !w.weekdays.contains(&day.weekday()) {
+            return None;
+        }
+        let start = self.resolve(w.start, day)?;
+        let mut end = self.resolve(w.end, day)?;
+        if end <= start {
+            end = self.resolve(w.end, day.succ())?;
+        }
+        Some((start, end))
+    }
+}
+
+/// The state of one scheduled switch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleStatus {
+    pub controller: String,
+    pub switch: Switch,
+    /// the state the schedule wants now
+    pub scheduled: bool,
+    /// the next transition, and the state after it
+    pub next: Option<(DateTime<Local>, bool)>,
+    /// a manual override, and the state it holds, lasts until the
+    /// next transition, or a day if there is none
+    pub overridden: Option<bool>,
+}
+
+impl fmt::Display for ScheduleStatus {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let onoff = |b: bool| if b { "on" } else { "off" };
+        write!(fmt, "{} {}: {}", self.controller, self.switch, onoff(self.scheduled))?;
+        if let Some(o) = self.overridden {
+            write!(fmt, " (overridden {})", onoff(o))?;
+        }
+        match self.next {
+            None => write!(fmt, " no transitions scheduled"),
+            Some((t, b)) => write!(fmt, " next {} at {}", onoff(b), t),
+        }
+    }
+}
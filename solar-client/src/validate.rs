@@ -0,0 +1,244 @@
+//! Sanity checks applied to settings before they are written to a
+//! controller. Voltages are on the controller's 12V scale, the
+//! controller multiplies them for 24V and 48V banks.
+use crate::{BatteryConfig, ControllerSettings};
+use anyhow::Result;
+use morningstar::prostar_mppt as ps;
+use uom::si::{
+    electric_current::ampere, electric_potential::volt,
+    thermodynamic_temperature::degree_celsius, time::day,
+};
+
+// the range of setpoints that make sense for a 12V lead acid or
+// lithium bank
+static MIN_VOLTAGE: f32 = 10.;
+static MAX_VOLTAGE: f32 = 17.5;
+// the ProStar MPPT tops out at 40A
+static MAX_CURRENT: f32 = 40.;
+// temperature compensation in volts per degree C for the whole bank
+static MAX_TEMP_COMP: f32 = 0.06;
+static MIN_TEMP: f32 = -40.;
+static MAX_TEMP: f32 = 80.;
+
+macro_rules! get {
+    ($s:ident, $fld:ident, $unit:ty) => {
+        (stringify!($fld), $s.$fld.get::<$unit>())
+    };
+}
+
+struct Errors(Vec<String>);
+
+impl Errors {
+    fn below(&mut self, (a, va): (&str, f32), (b, vb): (&str, f32)) {
+        if !(va < vb) {
+            self.0.push(format!("{} ({}) must be below {} ({})", a, va, b, vb))
+        }
+    }
+
+    fn not_above(&mut self, (a, va): (&str, f32), (b, vb): (&str, f32)) {
+        if !(va <= vb) {
+            self.0.push(format!("{} ({}) must not be above {} ({})", a, va, b, vb))
+        }
+    }
+
+    fn range(&mut self, (a, va): (&str, f32), min: f32, max: f32) {
+        if !(va >= min && va <= max) {
+            self.0.push(format!("{} ({}) must be between {} and {}", a, va, min, max))
+        }
+    }
+}
+
+fn prostar_mppt(s: &ps::Settings, battery: Option<&BatteryConfig>) -> Vec<String> {
+    let mut e = Errors(Vec::new());
+    let regulation = get!(s, regulation_voltage, volt);
+    let float = get!(s, float_voltage, volt);
+    let equalize = get!(s, equalize_voltage, volt);
+    let float_cancel = get!(s, float_cancel_voltage, volt);
+    let float_low = get!(s, float_low_battery_voltage_trigger, volt);
+    let limit = get!(s, reference_charge_voltage_limit, volt);
+    let hvd = get!(s, high_voltage_disconnect, volt);
+    let hvr = get!(s, high_voltage_reconnect, volt);
+    let lvd = get!(s, load_low_voltage_disconnect, volt);
+    let lvr = get!(s, load_low_voltage_reconnect, volt);
+    let load_hvd = get!(s, load_high_voltage_disconnect, volt);
+    let load_hvr = get!(s, load_high_voltage_reconnect, volt);
+    for v in &[regulation, float, limit, hvd, hvr, lvd, lvr, load_hvd, load_hvr] {
+        e.range(*v, MIN_VOLTAGE, MAX_VOLTAGE);
+    }
+    // charging stages
+    e.below(float, regulation);
+    e.below(float_cancel, float);
+    e.below(float_low, float);
+    e.not_above(regulation, limit);
+    // equalization is disabled when there are no days between cycles
+    if s.days_between_equalize_cycles.get::<day>() > 0. {
+        e.range(equalize, MIN_VOLTAGE, MAX_VOLTAGE);
+        e.not_above(regulation, equalize);
+        e.not_above(equalize, limit);
+    }
+    // disconnects
+    e.below(regulation, hvd);
+    e.below(hvr, hvd);
+    e.below(lvd, lvr);
+    e.below(load_hvr, load_hvd);
+    e.below(lvr, load_hvr);
+    // temperature compensation
+    e.range(get!(s, temperature_compensation_coefficent, volt), 0., MAX_TEMP_COMP);
+    let tmin = get!(s, min_battery_temp_compensation_limit, degree_celsius);
+    let tmax = get!(s, max_battery_temp_compensation_limit, degree_celsius);
+    e.range(tmin, MIN_TEMP, MAX_TEMP);
+    e.range(tmax, MIN_TEMP, MAX_TEMP);
+    e.below(tmin, tmax);
+    // currents
+    let charge = get!(s, charge_current_limit, ampere);
+    let battery_charge = get!(s, battery_charge_current_limit, ampere);
+    e.range(charge, 0., MAX_CURRENT);
+    e.range(battery_charge, 0., MAX_CURRENT);
+    if let Some(b) = battery {
+        let max = b.capacity * b.max_charge_rate;
+        e.range(battery_charge, 0., max);
+    }
+    e.0
+}
+
+/// Check settings for values that would damage the battery or leave
+/// the controller in a nonsensical state. The error lists every
+/// problem found.
+pub fn validate(
+    settings: &ControllerSettings,
+    battery: Option<&BatteryConfig>,
+) -> Result<()> {
+    let errors = match settings {
+        ControllerSettings::ProstarMppt(s) => prostar_mppt(s, battery),
+    };
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        bail!("invalid settings: {}", errors.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use uom::si::f32::*;
+
+    fn v(x: f32) -> ElectricPotential {
+        ElectricPotential::new::<volt>(x)
+    }
+
+    fn a(x: f32) -> ElectricCurrent {
+        ElectricCurrent::new::<ampere>(x)
+    }
+
+    // a sane 12V lead acid configuration
+    fn settings() -> ps::Settings {
+        let mut s = ps::Settings::default();
+        s.regulation_voltage = v(14.4);
+        s.float_voltage = v(13.6);
+        s.float_low_battery_voltage_trigger = v(12.3);
+        s.float_cancel_voltage = v(12.5);
+        s.equalize_voltage = v(15.1);
+        s.days_between_equalize_cycles = Time::new::<day>(28.);
+        s.reference_charge_voltage_limit = v(15.5);
+        s.battery_charge_current_limit = a(30.);
+        s.charge_current_limit = a(30.);
+        s.temperature_compensation_coefficent = v(0.03);
+        s.high_voltage_disconnect = v(15.5);
+        s.high_voltage_reconnect = v(15.0);
+        s.max_battery_temp_compensation_limit =
+            ThermodynamicTemperature::new::<degree_celsius>(60.);
+        s.min_battery_temp_compensation_limit =
+            ThermodynamicTemperature::new::<degree_celsius>(-30.);
+        s.load_low_voltage_disconnect = v(11.5);
+        s.load_low_voltage_reconnect = v(12.6);
+        s.load_high_voltage_disconnect = v(15.5);
+        s.load_high_voltage_reconnect = v(14.5);
+        s
+    }
+
+    fn check(s: ps::Settings, battery: Option<&BatteryConfig>) -> Result<()> {
+        validate(&ControllerSettings::ProstarMppt(s), battery)
+    }
+
+    fn errors(s: ps::Settings, battery: Option<&BatteryConfig>) -> String {
+        check(s, battery).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn sane() {
+        check(settings(), None).unwrap();
+        let battery = BatteryConfig { capacity: 200., max_charge_rate: 0.2 };
+        check(settings(), Some(&battery)).unwrap();
+    }
+
+    #[test]
+    fn charging_stages() {
+        let mut s = settings();
+        s.float_voltage = v(14.5);
+        let e = errors(s, None);
+        assert!(e.contains("float_voltage (14.5) must be below regulation_voltage"));
+        let mut s = settings();
+        s.regulation_voltage = v(15.4);
+        s.reference_charge_voltage_limit = v(15.);
+        let e = errors(s, None);
+        assert!(e.contains("regulation_voltage (15.4) must not be above"));
+        assert!(e.contains("equalize_voltage (15.1) must not be above"));
+    }
+
+    #[test]
+    fn equalize() {
+        let mut s = settings();
+        s.equalize_voltage = v(14.);
+        assert!(errors(s, None).contains("must not be above equalize_voltage"));
+        // disabled equalization is not checked
+        let mut s = settings();
+        s.equalize_voltage = v(0.);
+        s.days_between_equalize_cycles = Time::new::<day>(0.);
+        check(s, None).unwrap();
+    }
+
+    #[test]
+    fn disconnects() {
+        let mut s = settings();
+        s.load_low_voltage_reconnect = v(11.);
+        let e = errors(s, None);
+        assert!(e.contains("load_low_voltage_disconnect (11.5) must be below"));
+        let mut s = settings();
+        s.high_voltage_reconnect = v(15.5);
+        let e = errors(s, None);
+        assert!(e.contains("high_voltage_reconnect (15.5) must be below"));
+    }
+
+    #[test]
+    fn temperature() {
+        let mut s = settings();
+        s.min_battery_temp_compensation_limit =
+            ThermodynamicTemperature::new::<degree_celsius>(70.);
+        let e = errors(s, None);
+        assert!(e.contains("min_battery_temp_compensation_limit"));
+        assert!(e.contains("must be below max_battery_temp_compensation_limit"));
+        let mut s = settings();
+        s.temperature_compensation_coefficent = v(0.1);
+        let e = errors(s, None);
+        assert!(e.contains("temperature_compensation_coefficent (0.1) must be between"));
+    }
+
+    #[test]
+    fn battery_charge_rate() {
+        let battery = BatteryConfig { capacity: 100., max_charge_rate: 0.25 };
+        let e = errors(settings(), Some(&battery));
+        assert!(e.contains("battery_charge_current_limit (30) must be between 0 and 25"));
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut s = settings();
+        s.float_voltage = v(14.5);
+        s.charge_current_limit = a(50.);
+        let e = errors(s, None);
+        assert!(e.starts_with("invalid settings: "));
+        assert!(e.contains("float_voltage"));
+        assert!(e.contains("charge_current_limit (50) must be between 0 and 40"));
+    }
+}
@@ -0,0 +1,521 @@
+//! Check the archives for damage, gzip streams cut short by a crash
+//! while they were written, lines that don't parse, stats out of
+//! order or repeated, and aggregates that were never derived, and
+//! salvage what can still be read from the damaged ones.
+use crate::{
+    archive::{self, ArchiveFile, ArchiveKind, ArchiveWriter, Bucket},
+    events::Event,
+    ArchivedDay, Config, Stats,
+};
+use anyhow::Result;
+use chrono::prelude::*;
+use libflate::gzip::Decoder;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+// the most problems listed for one archive
+static MAX_LISTED: usize = 20;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Problem {
+    /// nothing after the line can be read, usually because the gzip
+    /// stream was cut short
+    Truncated {
+        line: usize,
+        error: String,
+    },
+    Unparseable {
+        line: usize,
+        error: String,
+    },
+    /// the stats are older than the controller's previous stats
+    OutOfOrder {
+        line: usize,
+        controller: String,
+    },
+    /// the stats have the same timestamp as the controller's previous
+    /// stats
+    Duplicate {
+        line: usize,
+        controller: String,
+    },
+    /// the raw stats were never aggregated at a configured resolution
+    Missing(Bucket),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Truncated { line, error } => {
+                write!(fmt, "unreadable after line {}, {}", line, error)
+            }
+            Problem::Unparseable { line, error } => {
+                write!(fmt, "line {} doesn't parse, {}", line, error)
+            }
+            Problem::OutOfOrder { line, controller } => {
+                write!(
+                    fmt,
+                    "line {} is older than the stats before it for {}",
+                    line, controller
+                )
+            }
+            Problem::Duplicate { line, controller } => {
+                write!(
+                    fmt,
+                    "line {} repeats the stats before it for {}",
+                    line, controller
+                )
+            }
+            Problem::Missing(b) => write!(fmt, "the {} aggregate is missing", b),
+        }
+    }
+}
+
+/// What was found in one archive
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub file: ArchiveFile,
+    /// the records that could be read
+    pub records: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// the records of the archive itself are damaged, as opposed to
+    /// just missing aggregates
+    pub fn damaged(&self) -> bool {
+        self.problems.iter().any(|p| !matches!(p, Problem::Missing(_)))
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}: {} readable records", self.file.path.display(), self.records)?;
+        for p in self.problems.iter().take(MAX_LISTED) {
+            write!(fmt, "\n    {}", p)?
+        }
+        if self.problems.len() > MAX_LISTED {
+            write!(fmt, "\n    and {} more", self.problems.len() - MAX_LISTED)?
+        }
+        Ok(())
+    }
+}
+
+// the controller and timestamp stats are ordered by
+type Key<T> = fn(&T) -> (&str, DateTime<Local>);
+
+fn stats_key(s: &Stats) -> (&str, DateTime<Local>) {
+    (s.name(), s.timestamp())
+}
+
+fn parse_event(line: &str) -> serde_json::Result<Event> {
+    serde_json::from_str(line)
+}
+
+// Pass each record in an archive that can be read to keep, and return
+// how many there were and what's wrong with the archive. Records with
+// a key must be in order, events have none because they are logged as
+// they are recorded, not in timestamp order.
+fn scan<T>(
+    path: &Path,
+    parse: impl Fn(&str) -> serde_json::Result<T>,
+    key: Option<Key<T>>,
+    mut keep: impl FnMut(T),
+) -> Result<(usize, Vec<Problem>)> {
+    let mut records = 0;
+    let mut problems = Vec::new();
+    let mut reader = match Decoder::new(fs::File::open(path)?) {
+        Ok(dec) => BufReader::new(dec),
+        Err(e) => {
+            problems.push(Problem::Truncated { line: 0, error: e.to_string() });
+            return Ok((records, problems));
+        }
+    };
+    let mut last: HashMap<String, DateTime<Local>> = HashMap::new();
+    let mut buf = String::new();
+    let mut line = 0;
+    loop {
+        buf.clear();
+        match reader.read_line(&mut buf) {
+            Ok(0) => break,
+            Ok(_) => line += 1,
+            Err(e) => {
+                problems.push(Problem::Truncated { line, error: e.to_string() });
+                break;
+            }
+        }
+        if buf.trim().is_empty() {
+            continue;
+        }
+        let r = match parse(&buf) {
+            Ok(r) => r,
+            Err(e) => {
+                problems.push(Problem::Unparseable { line, error: e.to_string() });
+                continue;
+            }
+        };
+        if let Some((name, ts)) = key.map(|key| key(&r)) {
+            let controller = String::from(name);
+            match last.get(name) {
+                Some(prev) if ts < *prev => {
+                    problems.push(Problem::OutOfOrder { line, controller })
+                }
+                Some(prev) if ts == *prev => {
+                    problems.push(Problem::Duplicate { line, controller })
+                }
+                Some(_) | None => {
+                    last.insert(controller, ts);
+                }
+            }
+        }
+        records += 1;
+        keep(r)
+    }
+    Ok((records, problems))
+}
+
+fn check_stats(path: &Path) -> Result<(usize, Vec<Problem>)> {
+    scan(path, archive::parse_stats, Some(stats_key), |_| ())
+}
+
+fn check_events(path: &Path) -> Result<(usize, Vec<Problem>)> {
+    scan(path, parse_event, None, |_| ())
+}
+
+// the stats that can be read, only repair needs to hold them all
+fn scan_stats(path: &Path) -> Result<(Vec<Stats>, Vec<Problem>)> {
+    let mut stats = Vec::new();
+    let (_, problems) =
+        scan(path, archive::parse_stats, Some(stats_key), |s| stats.push(s))?;
+    Ok((stats, problems))
+}
+
+fn scan_events(path: &Path) -> Result<(Vec<Event>, Vec<Problem>)> {
+    let mut events = Vec::new();
+    let (_, problems) = scan(path, parse_event, None, |e| events.push(e))?;
+    Ok((events, problems))
+}
+
+/// The stats archive exists and can be read to the end without
+/// problems
+pub(crate) fn stats_intact(path: &Path) -> Result<bool> {
+    if !ArchivedDay::file_exists(path)? {
+        return Ok(false);
+    }
+    Ok(check_stats(path)?.1.is_empty())
+}
+
+/// What stops the stats archive being read to the end, if anything
+pub(crate) fn stats_unreadable(path: &Path) -> Result<Option<Problem>> {
+    let (_, problems) = check_stats(path)?;
+    Ok(problems
+        .into_iter()
+        .find(|p| matches!(p, Problem::Truncated { .. } | Problem::Unparseable { .. })))
+}
+
+/// The events archive exists and can be read to the end without
+/// problems
+pub(crate) fn events_intact(path: &Path) -> Result<bool> {
+    if !ArchivedDay::file_exists(path)? {
+        return Ok(false);
+    }
+    Ok(check_events(path)?.1.is_empty())
+}
+
+/// Check every archive in the archive directory, oldest first
+pub fn verify(cfg: &Config) -> Result<Vec<Report>> {
+    let mut files = archive::list(cfg)?;
+    files.retain(|f| !f.leftover);
+    let today = Local::today().naive_local();
+    let mut reports = Vec::new();
+    for f in &files {
+        debug!("verifying {}", f.path.display());
+        let (records, mut problems) = match f.kind {
+            ArchiveKind::Events => check_events(&f.path)?,
+            ArchiveKind::Raw | ArchiveKind::Aggregate(_) => check_stats(&f.path)?,
+        };
+        if f.kind == ArchiveKind::Raw {
+            for b in &cfg.archive_resolutions {
+                let kind = ArchiveKind::Aggregate(*b);
+                let exists = files.iter().any(|g| g.date == f.date && g.kind == kind);
+                // the retention policy may remove aggregates before raw stats
+                let pruned = cfg
+                    .retention
+                    .as_ref()
+                    .map(|r| r.expired(kind, f.date, today))
+                    .unwrap_or(false);
+                if !exists && !pruned {
+                    problems.push(Problem::Missing(*b))
+                }
+            }
+        }
+        reports.push(Report { file: f.clone(), records, problems });
+    }
+    Ok(reports)
+}
+
+/// Where a damaged archive is kept after it's repaired, .damaged is
+/// appended to the name, and a number after that if an archive
+/// repaired earlier is already kept there.
+pub fn damaged_path(path: &Path) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(".damaged");
+    let mut n = 0;
+    loop {
+        let mut candidate = p.clone();
+        if n > 0 {
+            candidate.push(format!(".{}", n));
+        }
+        let candidate = PathBuf::from(candidate);
+        if fs::symlink_metadata(&candidate).is_err() {
+            break candidate;
+        }
+        n += 1
+    }
+}
+
+fn write_salvaged<T: serde::Serialize>(
+    path: &Path,
+    records: &[T],
+) -> io::Result<(PathBuf, PathBuf)> {
+    let mut w = ArchiveWriter::create(path)?;
+    for r in records {
+        w.write(r)?
+    }
+    w.close()
+}
+
+// write the records to a fresh archive at path, moving the damaged
+// one aside
+fn salvage<T: serde::Serialize>(path: &Path, records: &[T]) -> Result<()> {
+    let r = write_salvaged(path, records)
+        .and_then(|(tmp, path)| fs::rename(&path, damaged_path(&path)).map(|()| tmp));
+    match r {
+        Ok(tmp) => Ok(fs::rename(&tmp, path)?),
+        Err(e) => {
+            let _ = fs::remove_file(archive::tmp_path(path));
+            Err(e.into())
+        }
+    }
+}
+
+/// Repair the archive a report found problems with. Every record that
+/// can be read is salvaged into a fresh archive, stats are put back
+/// in order with the repeated ones dropped, and the damaged archive is
+/// kept next to it with .damaged appended. Missing aggregates are
+/// derived from the raw stats.
+pub fn repair(cfg: &Config, report: &Report) -> Result<()> {
+    let f = &report.file;
+    if report.damaged() {
+        match f.kind {
+            ArchiveKind::Events => {
+                let (events, _) = scan_events(&f.path)?;
+                salvage(&f.path, &events)?
+            }
+            ArchiveKind::Raw | ArchiveKind::Aggregate(_) => {
+                let (mut stats, _) = scan_stats(&f.path)?;
+                stats.sort_by_key(|s| s.timestamp());
+                let mut seen = HashSet::new();
+                stats.retain(|s| seen.insert((String::from(s.name()), s.timestamp())));
+                salvage(&f.path, &stats)?
+            }
+        }
+    }
+    let missing = report
+        .problems
+        .iter()
+        .filter_map(|p| match p {
+            Problem::Missing(b) => Some(*b),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        let day = match Local.from_local_date(&f.date).earliest() {
+            Some(day) => day,
+            None => bail!("invalid local date {}", f.date),
+        };
+        let aggregates = missing
+            .iter()
+            .map(|b| (*b, cfg.aggregate_for_date(day, *b)))
+            .collect::<Vec<_>>();
+        let stats = archive::read_history_file(f.path.clone())
+            .map_err(|e| anyhow!("failed to open {:?}, {}", f.path, e))?;
+        archive::write_archive(stats, None, &aggregates)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ControllerStats;
+    use morningstar::prostar_mppt as ps;
+    use std::{env, process};
+
+    fn tmp_dir(name: &str) -> PathBuf {
+        let dir =
+            env::temp_dir().join(format!("solar-verify-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path) -> Config {
+        let cfg = serde_json::json!({
+            "run_directory": dir,
+            "archive_directory": dir,
+            "stats_interval": 1,
+            "log_level": "Info",
+            "netidx_base": "/solar",
+            "netidx_bind": "local",
+            "archive_resolutions": ["10m"],
+        });
+        serde_json::from_value(cfg).unwrap()
+    }
+
+    fn day() -> Date<Local> {
+        Local.from_local_date(&NaiveDate::from_ymd(2021, 6, 15)).unwrap()
+    }
+
+    fn sample(name: &str, secs: i64) -> Stats {
+        Stats::V6 {
+            timestamp: day().and_hms(12, 0, 0) + chrono::Duration::seconds(secs),
+            name: String::from(name),
+            controller: Some(ControllerStats::ProstarMppt(ps::Stats::default())),
+            aggregate: None,
+        }
+    }
+
+    fn write<T: serde::Serialize>(path: &Path, records: &[T]) {
+        let mut w = ArchiveWriter::create(path).unwrap();
+        for r in records {
+            w.write(r).unwrap()
+        }
+        w.finish().unwrap()
+    }
+
+    fn raw_report(cfg: &Config) -> Report {
+        let reports = verify(cfg).unwrap();
+        reports.into_iter().find(|r| r.file.kind == ArchiveKind::Raw).unwrap()
+    }
+
+    #[test]
+    fn intact() {
+        let dir = tmp_dir("intact");
+        let cfg = config(&dir);
+        let path = cfg.archive_for_date(day()).all;
+        write(&path, &[sample("east", 0), sample("west", 0), sample("east", 60)]);
+        let r = raw_report(&cfg);
+        assert_eq!(r.records, 3);
+        // the aggregate was never written
+        assert!(matches!(r.problems[..], [Problem::Missing(_)]));
+        assert!(!r.damaged());
+        assert!(stats_unreadable(&path).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn out_of_order_and_duplicates() {
+        let dir = tmp_dir("order");
+        let cfg = config(&dir);
+        let path = cfg.archive_for_date(day()).all;
+        let stats = [
+            sample("east", 0),
+            sample("east", 60),
+            sample("east", 60),
+            sample("west", 30),
+            sample("east", 30),
+        ];
+        write(&path, &stats);
+        let (records, problems) = scan_stats(&path).unwrap();
+        assert_eq!(records.len(), 5);
+        let found = problems
+            .iter()
+            .map(|p| match p {
+                Problem::Duplicate { line, controller } => {
+                    ("duplicate", *line, controller.as_str())
+                }
+                Problem::OutOfOrder { line, controller } => {
+                    ("out of order", *line, controller.as_str())
+                }
+                p => panic!("unexpected problem {}", p),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(found, vec![("duplicate", 3, "east"), ("out of order", 5, "east")]);
+        assert!(stats_unreadable(&path).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn unparseable() {
+        let dir = tmp_dir("unparseable");
+        let path = dir.join("solar.log-20210615.gz");
+        let lines = [
+            serde_json::to_value(sample("east", 0)).unwrap(),
+            serde_json::Value::from("garbage"),
+            serde_json::to_value(sample("east", 60)).unwrap(),
+        ];
+        write(&path, &lines);
+        let (records, problems) = scan_stats(&path).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(matches!(problems[..], [Problem::Unparseable { line: 2, .. }]));
+        assert!(stats_unreadable(&path).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn truncated() {
+        let dir = tmp_dir("truncated");
+        let path = dir.join("solar.log-20210615.gz");
+        let stats = (0..500).map(|i| sample("east", i)).collect::<Vec<_>>();
+        write(&path, &stats);
+        let len = fs::metadata(&path).unwrap().len();
+        fs::OpenOptions::new().write(true).open(&path).unwrap().set_len(len / 2).unwrap();
+        let (records, problems) = scan_stats(&path).unwrap();
+        assert!(records.len() < 500);
+        assert!(matches!(problems[..], [Problem::Truncated { .. }]));
+        assert!(!stats_intact(&path).unwrap());
+        assert!(stats_unreadable(&path).unwrap().is_some());
+        fs::remove_dir_all(&dir).unwrap()
+    }
+
+    #[test]
+    fn repair_salvages() {
+        let dir = tmp_dir("repair");
+        let cfg = config(&dir);
+        let path = cfg.archive_for_date(day()).all;
+        let stats = [sample("east", 60), sample("east", 0), sample("east", 60)];
+        write(&path, &stats);
+        let r = raw_report(&cfg);
+        assert!(r.damaged());
+        repair(&cfg, &r).unwrap();
+        let (records, problems) = scan_stats(&path).unwrap();
+        assert!(problems.is_empty());
+        let ts = records.iter().map(|s| s.timestamp()).collect::<Vec<_>>();
+        assert_eq!(
+            ts,
+            vec![sample("east", 0).timestamp(), sample("east", 60).timestamp()]
+        );
+        // the damaged archive is kept, and the missing aggregate derived
+        let damaged = dir.join("solar.log-20210615.gz.damaged");
+        assert_eq!(scan_stats(&damaged).unwrap().0.len(), 3);
+        let aggregate = cfg.aggregate_for_date(day(), "10m".parse().unwrap());
+        assert!(stats_intact(&aggregate).unwrap());
+        assert!(raw_report(&cfg).problems.is_empty());
+        // a second repair keeps the first damaged archive
+        write(&path, &stats);
+        repair(&cfg, &raw_report(&cfg)).unwrap();
+        assert_eq!(damaged_path(&path), dir.join("solar.log-20210615.gz.damaged.2"));
+        assert!(dir.join("solar.log-20210615.gz.damaged.1").exists());
+        assert_eq!(scan_stats(&damaged).unwrap().0.len(), 3);
+        assert!(!archive::tmp_path(&path).exists());
+        // the damaged copies aren't verified, but they are pruned
+        assert!(verify(&cfg).unwrap().iter().all(|r| !r.file.leftover));
+        assert_eq!(archive::list(&cfg).unwrap().iter().filter(|f| f.leftover).count(), 2);
+        fs::remove_dir_all(&dir).unwrap()
+    }
+}
@@ -0,0 +1,263 @@
+use crate::ToMainLoop;
+use anyhow::Result;
+use chrono::prelude::*;
+use futures::{prelude::*, select_biased};
+use parking_lot::Mutex;
+use solar_client::{
+    archive::{self, ArchiveStatus, PendingDay},
+    events::Event,
+    Config, Stats,
+};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    fs,
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    sync::mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
+    task, time,
+};
+
+// how long to wait before archiving a day again after it failed
+static RETRY_INTERVAL: Duration = Duration::from_secs(600);
+// the longest the rotation timer sleeps, so a clock change or a
+// suspend can't make it miss midnight by much
+static MAX_SLEEP: Duration = Duration::from_secs(3600);
+
+/// The day the stats in the live log started, today if it's empty
+pub(crate) fn log_date(cfg: &Config) -> NaiveDate {
+    archive::read_history_file(cfg.log_file())
+        .ok()
+        .and_then(|mut stats| stats.next())
+        .map(|s| s.timestamp().date().naive_local())
+        .unwrap_or_else(|| Local::today().naive_local())
+}
+
+fn until_midnight() -> Duration {
+    let now = Local::now();
+    let tomorrow = now.date().succ();
+    // a dst change can skip midnight
+    let midnight =
+        tomorrow.and_hms_opt(0, 0, 0).or_else(|| tomorrow.and_hms_opt(1, 0, 0));
+    match midnight.and_then(|m| (m - now).to_std().ok()) {
+        None => MAX_SLEEP,
+        // a second late so the date has certainly changed
+        Some(d) => (d + Duration::from_secs(1)).min(MAX_SLEEP),
+    }
+}
+
+/// Wake the main loop to rotate the logs now and just after every
+/// local midnight, it decides if the day actually changed.
+pub(crate) fn run_timer(to_main: Sender<ToMainLoop>) {
+    task::spawn(async move {
+        loop {
+            if to_main.send(ToMainLoop::Rotate).await.is_err() {
+                break;
+            }
+            time::sleep(until_midnight()).await
+        }
+    });
+}
+
+async fn exists(path: &Path) -> Result<bool> {
+    match fs::metadata(path).await {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Move the lines of a live log dated before today to the rotated log
+// of their day, and keep today's lines in the live log. A line that
+// can't be dated goes with the line before it, or the first day if it
+// leads. Returns the days rotated.
+async fn split_log(
+    live: &Path,
+    rotated: impl Fn(NaiveDate) -> PathBuf,
+    first: NaiveDate,
+    today: NaiveDate,
+    date: impl Fn(&str) -> Option<NaiveDate>,
+) -> Result<BTreeSet<NaiveDate>> {
+    let mut days = BTreeSet::new();
+    if !exists(live).await? {
+        return Ok(days);
+    }
+    let tmp = live.with_extension("tmp");
+    let mut kept = BufWriter::new(fs::File::create(&tmp).await?);
+    let mut out: Option<(NaiveDate, BufWriter<fs::File>)> = None;
+    let mut lines = BufReader::new(fs::File::open(live).await?).lines();
+    let mut day = first;
+    while let Some(line) = lines.next_line().await? {
+        day = date(&line).unwrap_or(day);
+        let w = if day >= today {
+            &mut kept
+        } else {
+            if out.as_ref().map(|(d, _)| *d != day).unwrap_or(true) {
+                if let Some((_, w)) = out.take() {
+                    finish(w).await?
+                }
+                let path = rotated(day);
+                let f =
+                    fs::OpenOptions::new().create(true).append(true).open(path).await?;
+                out = Some((day, BufWriter::new(f)));
+                days.insert(day);
+            }
+            &mut out.as_mut().unwrap().1
+        };
+        w.write_all(line.as_bytes()).await?;
+        w.write_all(b"\n").await?;
+    }
+    if let Some((_, w)) = out {
+        finish(w).await?
+    }
+    finish(kept).await?;
+    fs::rename(&tmp, live).await?;
+    Ok(days)
+}
+
+async fn finish(mut w: BufWriter<fs::File>) -> Result<()> {
+    w.flush().await?;
+    Ok(w.get_ref().sync_all().await?)
+}
+
+/// Move the stats and events logged before today from the live logs to
+/// the logs of their days, first is the day the live log started.
+/// Returns the days rotated. The caller must flush and reopen the live
+/// logs.
+pub(crate) async fn rotate(
+    cfg: &Config,
+    first: NaiveDate,
+    today: NaiveDate,
+) -> Result<BTreeSet<NaiveDate>> {
+    let stats_date = |l: &str| {
+        let s = serde_json::from_str::<Stats>(l).ok()?;
+        Some(s.timestamp().date().naive_local())
+    };
+    let rotated = |d| cfg.rotated_log(d);
+    let mut days = split_log(&cfg.log_file(), rotated, first, today, stats_date).await?;
+    let event_date = |l: &str| {
+        let e = serde_json::from_str::<Event>(l).ok()?;
+        Some(e.timestamp.date().naive_local())
+    };
+    let rotated = |d| cfg.rotated_event_log(d);
+    let events = split_log(&cfg.event_log(), rotated, first, today, event_date).await?;
+    days.extend(events);
+    Ok(days)
+}
+
+fn add_pending(status: &mut ArchiveStatus, date: NaiveDate) {
+    if !status.pending.iter().any(|p| p.date == date) {
+        let next_attempt = Local::now();
+        status.pending.push(PendingDay { date, attempts: 0, error: None, next_attempt });
+        status.pending.sort_by_key(|p| p.date);
+    }
+}
+
+async fn prune(cfg: &Config) {
+    let c = cfg.clone();
+    match task::spawn_blocking(move || archive::prune(&c, false)).await {
+        Ok(Ok(removed)) => info!("pruned {} archives", removed.len()),
+        Ok(Err(e)) => error!("failed to prune the archive {}", e),
+        Err(e) => error!("archive pruning failed {}", e),
+    }
+}
+
+async fn archive_day(cfg: &Config, status: &Mutex<ArchiveStatus>, date: NaiveDate) {
+    let c = cfg.clone();
+    let r = match task::spawn_blocking(move || archive::archive_rotated(&c, date)).await {
+        Ok(r) => r,
+        Err(e) => Err(anyhow!("archiver task failed {}", e)),
+    };
+    if r.is_ok() && cfg.retention.as_ref().map(|r| r.auto_prune).unwrap_or(false) {
+        prune(cfg).await
+    }
+    let mut status = status.lock();
+    match r {
+        Ok(()) => {
+            info!("archived {}", date);
+            status.pending.retain(|p| p.date != date);
+            status.last_archived = Some((date, Local::now()));
+        }
+        Err(e) => {
+            error!("failed to archive {}, will retry {}", date, e);
+            if let Some(p) = status.pending.iter_mut().find(|p| p.date == date) {
+                p.attempts += 1;
+                p.error = Some(e.to_string());
+                p.next_attempt =
+                    Local::now() + chrono::Duration::from_std(RETRY_INTERVAL).unwrap();
+            }
+        }
+    }
+}
+
+async fn run(
+    cfg: Config,
+    status: Arc<Mutex<ArchiveStatus>>,
+    mut days: UnboundedReceiver<NaiveDate>,
+) {
+    // days rotated before a restart that weren't archived
+    match archive::rotated(&cfg) {
+        Err(e) => error!("failed to list the rotated logs {}", e),
+        Ok(rotated) => {
+            let mut status = status.lock();
+            for date in rotated {
+                add_pending(&mut status, date)
+            }
+        }
+    }
+    loop {
+        let now = Local::now();
+        let due = status
+            .lock()
+            .pending
+            .iter()
+            .filter(|p| p.next_attempt <= now)
+            .map(|p| p.date)
+            .collect::<Vec<_>>();
+        for date in due {
+            archive_day(&cfg, &status, date).await
+        }
+        let next = status.lock().pending.iter().map(|p| p.next_attempt).min();
+        let wait = match next {
+            None => RETRY_INTERVAL,
+            Some(t) => (t - Local::now()).to_std().unwrap_or(Duration::from_secs(0)),
+        };
+        select_biased! {
+            d = days.recv().fuse() => match d {
+                None => break,
+                Some(d) => add_pending(&mut status.lock(), d),
+            },
+            _ = time::sleep(wait).fuse() => (),
+        }
+    }
+}
+
+/// Archives the days the main loop rotates in the background, trying
+/// again later if archiving fails.
+pub(crate) struct Archiver {
+    status: Arc<Mutex<ArchiveStatus>>,
+    days: UnboundedSender<NaiveDate>,
+}
+
+impl Archiver {
+    pub(crate) fn start(cfg: &Config) -> Self {
+        let status =
+            ArchiveStatus { auto_archive: cfg.auto_archive, ..Default::default() };
+        let status = Arc::new(Mutex::new(status));
+        let (days, rx) = unbounded_channel();
+        task::spawn(run(cfg.clone(), status.clone(), rx));
+        Archiver { status, days }
+    }
+
+    /// archive the logs rotated for the given day
+    pub(crate) fn archive(&self, date: NaiveDate) {
+        let _ = self.days.send(date);
+    }
+
+    pub(crate) fn status(&self) -> ArchiveStatus {
+        self.status.lock().clone()
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{
+    modbus,
+    modbus_tcp::{Request, Response},
+    sim,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use solar_client::{
+    Capabilities, ControllerConfig, ControllerSettings, ControllerStats, Model,
+};
+
+static SIM_PREFIX: &str = "sim:";
+
+/// The controls common to all models. Each model maps these onto it's
+/// own coils.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Coil {
+    ChargeDisconnect,
+    LoadDisconnect,
+    ResetControl,
+}
+
+/// A charge controller. Implementations exist for each supported
+/// model, the daemon, the stats log, and the netidx tree only deal
+/// with this interface and the model tagged stats and settings.
+#[async_trait]
+pub(crate) trait Controller: Send {
+    fn model(&self) -> Model;
+
+    fn capabilities(&self) -> Capabilities;
+
+    async fn read_stats(&mut self) -> Result<ControllerStats>;
+
+    async fn read_settings(&mut self) -> Result<ControllerSettings>;
+
+    async fn write_settings(&mut self, settings: &ControllerSettings) -> Result<()>;
+
+    async fn write_coil(&mut self, coil: Coil, bit: bool) -> Result<()>;
+
+    async fn raw(&mut self, _req: &Request) -> Result<Response> {
+        bail!("{:?} does not support register access", self.model())
+    }
+
+    /// Called when an operation was cancelled part way through. The
+    /// next operation must not depend on the state of the link.
+    fn reset(&mut self) {}
+}
+
+/// Open the controller described by cfg. A device of the form `sim:`
+/// selects the simulator, `tcp:host:port` reaches the controller
+/// through a Modbus TCP gateway using modbus_id as the unit id, and
+/// anything else is opened as a serial device.
+pub(crate) async fn open(cfg: &ControllerConfig) -> Box<dyn Controller> {
+    match cfg.model {
+        Model::ProstarMppt => {
+            if cfg.device.starts_with(SIM_PREFIX) {
+                info!("using simulated controller for {}", cfg.name);
+                Box::new(sim::Connection::new().await)
+            } else {
+                Box::new(modbus::Connection::new(cfg.device.clone(), cfg.modbus_id).await)
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use crate::queue::Handle;
+use anyhow::Result;
+use solar_client::{
+    events::{self, Detail, Event},
+    faults::Faults,
+    Config, ControllerStats,
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+#[derive(Default)]
+struct Last {
+    reachable: Option<bool>,
+    charge_state: Option<String>,
+    load_state: Option<String>,
+    faults: Faults,
+}
+
+/// Finds the transitions in each controller's stats by comparing them
+/// with the last stats seen.
+pub(crate) struct Tracker(Vec<(String, Last)>);
+
+impl Tracker {
+    pub(crate) fn new(controllers: &[Handle]) -> Self {
+        Tracker(
+            controllers.iter().map(|c| (c.info.name.clone(), Last::default())).collect(),
+        )
+    }
+
+    pub(crate) fn stats(&mut self, i: usize, st: Option<&ControllerStats>) -> Vec<Event> {
+        let (name, last) = &mut self.0[i];
+        let mut events = Vec::new();
+        if last.reachable != Some(st.is_some()) {
+            last.reachable = Some(st.is_some());
+            events.push(Event::new(name, Detail::Reachable(st.is_some())))
+        }
+        if let Some(st) = st {
+            let (charge, load) = match st {
+                ControllerStats::ProstarMppt(s) => {
+                    (format!("{:?}", s.charge_state), format!("{:?}", s.load_state))
+                }
+            };
+            if last.charge_state.as_ref() != Some(&charge) {
+                let from = last.charge_state.replace(charge.clone());
+                events.push(Event::new(name, Detail::ChargeState { from, to: charge }))
+            }
+            if last.load_state.as_ref() != Some(&load) {
+                let from = last.load_state.replace(load.clone());
+                events.push(Event::new(name, Detail::LoadState { from, to: load }))
+            }
+            let faults = st.faults();
+            events.extend(events::fault_changes(name, &last.faults, &faults));
+            last.faults = faults;
+        }
+        events
+    }
+}
+
+pub(crate) struct EventLog {
+    file: fs::File,
+    buf: Vec<u8>,
+}
+
+impl EventLog {
+    pub(crate) async fn open(cfg: &Config) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .append(true)
+            .create(true)
+            .open(&cfg.event_log())
+            .await?;
+        Ok(EventLog { file, buf: Vec::new() })
+    }
+
+    // events are rare, so they are written through immediately
+    pub(crate) async fn append(&mut self, events: &[Event]) -> Result<()> {
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.buf.clear();
+        for e in events {
+            debug!("event: {}", e);
+            serde_json::to_writer(&mut self.buf, e)?;
+            self.buf.push(b'\n');
+        }
+        self.file.write_all(&self.buf).await?;
+        self.file.flush().await?;
+        Ok(())
+    }
+}
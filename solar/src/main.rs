@@ -4,6 +4,8 @@ extern crate serde_derive;
 extern crate log;
 #[macro_use]
 extern crate netidx_core;
+#[macro_use]
+extern crate anyhow;
 
 macro_rules! log_fatal {
     ($e:expr, $m:expr, $a:expr) => {
@@ -17,16 +19,40 @@ macro_rules! log_fatal {
     };
 }
 
+mod archiver;
 mod control_socket;
+mod controller;
+mod events;
+mod metrics;
 mod modbus;
+mod modbus_gateway;
+mod modbus_tcp;
+mod mqtt;
+mod notify;
 mod publisher;
+mod queue;
+mod rules;
+mod scheduler;
+mod sim;
 
 use anyhow::Result;
+use archiver::Archiver;
 use daemonize::Daemonize;
+use events::{EventLog, Tracker};
 use futures::{prelude::*, select_biased};
-use morningstar::prostar_mppt as ps;
+use metrics::Metrics;
+use mqtt::Mqtt;
+use notify::Notifier;
 use publisher::Netidx;
-use solar_client::{self, archive, Config, FromClient, Stats, ToClient};
+use queue::{Command, Event, Handle, Reply};
+use rules::Rules;
+use scheduler::Scheduler;
+use solar_client::{
+    self, archive,
+    events::{self as ev, Detail},
+    export, journal, profile, verify, Config, ControllerSettings, FromClient, Source,
+    Stats, Step, ToClient,
+};
 use std::time::Duration;
 use structopt::StructOpt;
 use tokio::{
@@ -34,15 +60,29 @@ use tokio::{
     io::{self, AsyncWriteExt},
     runtime::Runtime,
     sync::mpsc::{channel, Sender},
-    time,
+    task, time,
 };
 
 #[derive(Debug, Clone)]
 pub(crate) enum ToMainLoop {
     FromClient(FromClient, Sender<ToClient>),
+    Modbus(
+        u8,
+        modbus_tcp::Request,
+        Sender<std::result::Result<modbus_tcp::Response, u8>>,
+    ),
+    Controller(usize, Event),
+    ScheduleFailed(usize),
+    RuleFailed(usize),
+    Metrics(Sender<String>),
+    Schedule,
+    Rotate,
     Tick,
 }
 
+// how often the schedule is checked
+static SCHEDULE_INTERVAL: Duration = Duration::from_secs(15);
+
 async fn open_log(cfg: &Config) -> Result<io::BufWriter<fs::File>, io::Error> {
     let log = fs::OpenOptions::new()
         .write(true)
@@ -64,21 +104,161 @@ async fn send_reply(r: Result<()>, s: Sender<ToClient>) {
     }
 }
 
+fn command_event(ctl: &Handle, source: Source, command: FromClient) -> ev::Event {
+    ev::Event::new(&ctl.info.name, Detail::Command { source, command })
+}
+
+async fn log_events(log: &mut EventLog, events: &[ev::Event]) {
+    if let Err(e) = log.append(events).await {
+        error!("failed to write the event log {}", e)
+    }
+}
+
+// commands without a target go to the first controller
+fn find_controller(controllers: &[Handle], name: Option<&str>) -> Result<usize> {
+    match name {
+        None if controllers.len() > 0 => Ok(0),
+        None => bail!("no controllers are configured"),
+        Some(name) => match controllers.iter().position(|c| c.info.name == name) {
+            Some(i) => Ok(i),
+            None => bail!("no such controller {}", name),
+        },
+    }
+}
+
+fn to_command(cfg: &Config, source: Source, cmd: FromClient) -> Result<Command> {
+    Ok(match cmd {
+        FromClient::SetCharging(b) => Command::SetCharging(b),
+        FromClient::SetLoad(b) => Command::SetLoad(b),
+        FromClient::ResetController => Command::Reset,
+        FromClient::WriteSettings(s) => Command::WriteSettings(s, source),
+        FromClient::ReadSettings => Command::ReadSettings,
+        FromClient::ApplyProfile(name) => {
+            Command::WriteSettings(profile::load(cfg, &name)?, source)
+        }
+        FromClient::Transaction(steps) => {
+            let mut cmds = Vec::new();
+            for step in steps {
+                let action = to_command(cfg, source, step.action)?;
+                let undo = step.undo.map(|u| to_command(cfg, source, u)).transpose()?;
+                let nested = |c: &Command| matches!(c, Command::Transaction(_));
+                if nested(&action) || undo.as_ref().map(nested).unwrap_or(false) {
+                    bail!("transactions can't be nested")
+                }
+                cmds.push((action, undo))
+            }
+            Command::Transaction(cmds)
+        }
+        FromClient::LogRotated
+        | FromClient::Stop
+        | FromClient::TailStats
+        | FromClient::ListControllers
+        | FromClient::ScheduleStatus
+        | FromClient::RuleStatus
+        | FromClient::ArchiveStatus
+        | FromClient::Source(_, _)
+        | FromClient::Target(_, _) => bail!("not a controller command"),
+    })
+}
+
+async fn controller_command(ctl: Handle, cmd: Command, reply: Sender<ToClient>) {
+    match ctl.command(cmd).await {
+        Ok(Reply::Settings(s)) => {
+            reply.send(ToClient::Settings(s)).await.ok();
+        }
+        Ok(Reply::Ok) | Ok(Reply::Raw(_)) => send_reply(Ok(()), reply).await,
+        Err(e) => send_reply(Err(e), reply).await,
+    }
+}
+
+// a command issued by the daemon itself, failed is sent back to the
+// main loop if it doesn't succeed
+async fn background_command(
+    ctl: Handle,
+    cmd: Command,
+    failed: ToMainLoop,
+    to_main: Sender<ToMainLoop>,
+) {
+    if let Err(e) = ctl.command(cmd).await {
+        warn!("{:?} for {} failed {}", failed, ctl.info.name, e);
+        let _ = to_main.send(failed).await;
+    }
+}
+
+async fn modbus_command(
+    ctl: Handle,
+    req: modbus_tcp::Request,
+    reply: Sender<std::result::Result<modbus_tcp::Response, u8>>,
+) {
+    let r = match ctl.command(Command::Raw(req)).await {
+        Ok(Reply::Raw(r)) => Ok(r),
+        Ok(Reply::Ok) | Ok(Reply::Settings(_)) => Err(modbus_tcp::GATEWAY_TARGET_FAILED),
+        Err(e) => {
+            warn!("modbus gateway request to {} failed {}", ctl.info.name, e);
+            Err(modbus_tcp::GATEWAY_TARGET_FAILED)
+        }
+    };
+    reply.send(r).await.ok();
+}
+
 async fn run_server(config: Config) {
     let (to_main, mut receiver) = channel(100);
+    let mut log_date = archiver::log_date(&config);
     let mut log = log_fatal!(open_log(&config).await, "failed to open log {}", return);
-    let mut mb = modbus::Connection::new(config.device.clone(), config.modbus_id).await;
+    let mut eventlog =
+        log_fatal!(EventLog::open(&config).await, "failed to open event log {}", return);
+    let mut controllers = Vec::new();
+    for (i, c) in config.controllers().into_iter().enumerate() {
+        let mb = controller::open(&c).await;
+        controllers.push(Handle::start(i, &c, mb, to_main.clone()));
+    }
+    if controllers.len() == 0 {
+        warn!("no controllers are configured");
+    }
+    let mut tracker = Tracker::new(&controllers);
+    let mut notifier = config.notify.as_ref().map(Notifier::new);
+    let mut metrics = Metrics::new(&controllers);
+    let mut scheduler = match &config.schedule {
+        None => None,
+        Some(cfg) => Some(log_fatal!(
+            Scheduler::new(cfg, &controllers),
+            "invalid schedule {}",
+            return
+        )),
+    };
+    let mut rules =
+        log_fatal!(Rules::new(&config.rules, &controllers), "invalid rules {}", return);
     let mut tick = time::interval(Duration::from_secs(config.stats_interval));
+    let mut schedule_tick = time::interval(SCHEDULE_INTERVAL);
+    let archiver = Archiver::start(&config);
+    if config.auto_archive {
+        archiver::run_timer(to_main.clone());
+    }
     control_socket::run_server(&config, to_main.clone());
-    let netidx =
-        log_fatal!(Netidx::new(&config, to_main).await, "init publisher {}", return);
+    if let Some(gw) = &config.modbus_gateway {
+        log_fatal!(
+            modbus_gateway::check_units(&config.controllers()),
+            "invalid modbus gateway {}",
+            return
+        );
+        modbus_gateway::run_server(gw, to_main.clone());
+    }
+    if let Some(m) = &config.metrics {
+        metrics::run_server(m, to_main.clone());
+    }
+    let infos = controllers.iter().map(|c| c.info.clone()).collect::<Vec<_>>();
+    let mut mqtt = config.mqtt.as_ref().map(|m| Mqtt::start(m, &infos, to_main.clone()));
+    let netidx = log_fatal!(
+        Netidx::new(&config, &infos, to_main.clone()).await,
+        "init publisher {}",
+        return
+    );
     let mut tailing: Vec<Sender<ToClient>> = Vec::new();
     let mut statsbuf = Vec::new();
-    let mut initsettings = false;
-    let mut batch = netidx.start_batch();
     loop {
         let msg = select_biased! {
             _ = tick.tick().fuse() => ToMainLoop::Tick,
+            _ = schedule_tick.tick().fuse() => ToMainLoop::Schedule,
             m = receiver.recv().fuse() => match m {
                 None => break,
                 Some(m) => m
@@ -86,82 +266,221 @@ async fn run_server(config: Config) {
         };
         debug!("run_server: {:?}", msg);
         match msg {
-            ToMainLoop::FromClient(msg, reply) => match msg {
-                FromClient::SetCharging(b) => {
-                    send_reply(mb.write_coil(ps::Coil::ChargeDisconnect, !b).await, reply)
-                        .await
-                }
-                FromClient::SetLoad(b) => {
-                    send_reply(mb.write_coil(ps::Coil::LoadDisconnect, !b).await, reply)
-                        .await
-                }
-                FromClient::ResetController => {
-                    send_reply(mb.write_coil(ps::Coil::ResetControl, true).await, reply)
-                        .await
-                }
-                FromClient::LogRotated => {
-                    log = log_fatal!(
-                        open_log(&config).await,
-                        "failed to open log {}",
-                        break
-                    );
-                    send_reply(Ok(()), reply).await
-                }
-                FromClient::TailStats => tailing.push(reply),
-                FromClient::WriteSettings(settings) => {
-                    let r = mb.write_settings(&settings).await;
-                    if r.is_ok() {
-                        netidx.update_settings(&mut batch, &settings);
+            ToMainLoop::FromClient(msg, reply) => {
+                let (source, msg) = msg.unsource();
+                let source = source.unwrap_or(Source::Cli);
+                match msg.untarget() {
+                    (_, FromClient::LogRotated) => {
+                        log = log_fatal!(
+                            open_log(&config).await,
+                            "failed to open log {}",
+                            break
+                        );
+                        eventlog = log_fatal!(
+                            EventLog::open(&config).await,
+                            "failed to open event log {}",
+                            break
+                        );
+                        log_date = chrono::Local::today().naive_local();
+                        send_reply(Ok(()), reply).await
+                    }
+                    (_, FromClient::TailStats) => tailing.push(reply),
+                    (_, FromClient::ListControllers) => {
+                        let l = controllers.iter().map(|c| c.info.clone()).collect();
+                        reply.send(ToClient::Controllers(l)).await.ok();
+                    }
+                    (_, FromClient::ScheduleStatus) => {
+                        let l = match &scheduler {
+                            None => Vec::new(),
+                            Some(s) => s
+                                .status(chrono::Local::now())
+                                .into_iter()
+                                .map(|(_, s)| s)
+                                .collect(),
+                        };
+                        reply.send(ToClient::Schedule(l)).await.ok();
+                    }
+                    (_, FromClient::RuleStatus) => {
+                        let l = rules.status().into_iter().map(|(_, s)| s).collect();
+                        reply.send(ToClient::Rules(l)).await.ok();
+                    }
+                    (_, FromClient::ArchiveStatus) => {
+                        reply.send(ToClient::Archive(archiver.status())).await.ok();
+                    }
+                    (_, FromClient::Stop) => {
+                        reply.send(ToClient::Ok).await.ok();
+                        time::sleep(Duration::from_millis(200)).await;
+                        break;
+                    }
+                    // controller commands run on the controller's task so
+                    // a slow or unresponsive controller can't stall the
+                    // main loop
+                    (target, cmd) => {
+                        match find_controller(&controllers, target.as_deref()) {
+                            Err(e) => send_reply(Err(e), reply).await,
+                            Ok(i) => match to_command(&config, source, cmd.clone()) {
+                                Err(e) => send_reply(Err(e), reply).await,
+                                Ok(ctlcmd) => {
+                                    // only commands from clients override
+                                    // the schedule
+                                    let client = !matches!(
+                                        source,
+                                        Source::Schedule | Source::Rules
+                                    );
+                                    if let (Some(s), true) = (&mut scheduler, client) {
+                                        s.manual(i, &cmd, chrono::Local::now())
+                                    }
+                                    let ctl = controllers[i].clone();
+                                    let e = command_event(&ctl, source, cmd);
+                                    log_events(&mut eventlog, &[e]).await;
+                                    task::spawn(controller_command(ctl, ctlcmd, reply));
+                                }
+                            },
+                        }
                     }
-                    send_reply(r, reply).await
                 }
-                FromClient::ReadSettings => match mb.read_settings().await {
-                    Ok(s) => {
-                        netidx.update_settings(&mut batch, &s);
-                        reply.send(ToClient::Settings(s)).await.ok();
+            }
+            ToMainLoop::Modbus(unit, req, reply) => {
+                // modbus gateway requests are routed by unit id
+                match controllers.iter().find(|c| c.address == unit) {
+                    None => {
+                        reply.send(Err(modbus_tcp::GATEWAY_PATH_UNAVAILABLE)).await.ok();
                     }
-                    Err(e) => {
-                        reply.send(ToClient::Err(e.to_string())).await.ok();
+                    Some(ctl) => {
+                        task::spawn(modbus_command(ctl.clone(), req, reply));
                     }
-                },
-                FromClient::Stop => {
-                    reply.send(ToClient::Ok).await.ok();
-                    time::sleep(Duration::from_millis(200)).await;
-                    break;
                 }
-            },
+            }
             ToMainLoop::Tick => {
-                let controller = {
-                    if !initsettings {
-                        debug!("tick: reading initial settings");
-                        match mb.read_settings().await {
-                            Ok(s) => {
-                                initsettings = true;
-                                netidx.update_settings(&mut batch, &s);
+                for ctl in &controllers {
+                    if !ctl.poll() {
+                        warn!("tick: {} is still busy, skipping stats", ctl.info.name)
+                    }
+                }
+            }
+            ToMainLoop::Schedule => {
+                if let Some(scheduler) = &mut scheduler {
+                    let now = chrono::Local::now();
+                    for (id, i, cmd) in scheduler.poll(now) {
+                        match to_command(&config, Source::Schedule, cmd.clone()) {
+                            Err(e) => error!("invalid scheduled command {}", e),
+                            Ok(ctlcmd) => {
+                                let (ctl, to_main) =
+                                    (controllers[i].clone(), to_main.clone());
+                                let e = command_event(&ctl, Source::Schedule, cmd);
+                                log_events(&mut eventlog, &[e]).await;
+                                let failed = ToMainLoop::ScheduleFailed(id);
+                                task::spawn(background_command(
+                                    ctl, ctlcmd, failed, to_main,
+                                ));
                             }
-                            Err(_) => (),
                         }
                     }
-                    debug!("tick: reading stats");
-                    match mb.read_stats().await {
-                        Ok(s) => {
-                            netidx.update_stats(&mut batch, &s);
-                            netidx.update_control(&mut batch, &s);
-                            Some(s)
+                    let mut batch = netidx.start_batch();
+                    for (i, st) in scheduler.status(now) {
+                        if let Err(e) = netidx.update_schedule(&mut batch, i, &st) {
+                            warn!("failed to publish the schedule {}", e)
                         }
-                        Err(e) => {
-                            error!("reading stats failed: {}", e);
-                            None
+                    }
+                    if batch.len() > 0 {
+                        batch.commit(Some(Duration::from_secs(10))).await;
+                    }
+                }
+            }
+            // the day the live logs hold has ended, move them aside
+            // for the archiver and start new ones
+            ToMainLoop::Rotate => {
+                let today = chrono::Local::today().naive_local();
+                if log_date < today {
+                    log_fatal!(log.flush().await, "fatal: failed to flush log {}", break);
+                    match archiver::rotate(&config, log_date, today).await {
+                        Err(e) => error!("failed to rotate the logs {}", e),
+                        Ok(days) => {
+                            log = log_fatal!(
+                                open_log(&config).await,
+                                "failed to open log {}",
+                                break
+                            );
+                            eventlog = log_fatal!(
+                                EventLog::open(&config).await,
+                                "failed to open event log {}",
+                                break
+                            );
+                            for d in days {
+                                archiver.archive(d)
+                            }
+                            log_date = today;
                         }
                     }
-                };
-                debug!("tick: flushing publisher");
-                if batch.len() > 0 {
-                    batch.commit(Some(Duration::from_secs(10))).await;
-                    batch = netidx.start_batch();
+                }
+            }
+            ToMainLoop::ScheduleFailed(id) => {
+                if let Some(scheduler) = &mut scheduler {
+                    scheduler.failed(id)
+                }
+            }
+            ToMainLoop::RuleFailed(id) => rules.failed(id),
+            ToMainLoop::Metrics(reply) => {
+                reply.send(metrics.render(&controllers)).await.ok();
+            }
+            ToMainLoop::Controller(i, Event::Settings(s)) => {
+                metrics.settings(i, &s);
+                let mut batch = netidx.start_batch();
+                netidx.update_settings(&mut batch, i, &s);
+                batch.commit(Some(Duration::from_secs(10))).await;
+            }
+            ToMainLoop::Controller(_, Event::Applied(entry)) => {
+                if let Err(e) = journal::append(&config, &entry) {
+                    error!("failed to write the settings journal {}", e)
+                }
+                let mut e =
+                    ev::Event::new(&entry.controller, Detail::Settings(entry.source));
+                e.timestamp = entry.timestamp;
+                log_events(&mut eventlog, &[e]).await;
+            }
+            ToMainLoop::Controller(i, Event::Stats(controller)) => {
+                let mut batch = netidx.start_batch();
+                if let Some(s) = &controller {
+                    netidx.update_stats(&mut batch, i, s);
                 }
                 let timestamp = chrono::Local::now();
-                let st = Stats::V3 { timestamp, controller };
+                metrics.stats(i, controller.as_ref(), timestamp);
+                if let Some(mqtt) = &mut mqtt {
+                    mqtt.stats(i, controller.as_ref());
+                }
+                let events = tracker.stats(i, controller.as_ref());
+                log_events(&mut eventlog, &events).await;
+                if let (Some(notifier), Some(cfg)) = (&mut notifier, &config.notify) {
+                    let name = &controllers[i].info.name;
+                    notifier.events(&events);
+                    notifier.stats(name, controller.as_ref(), timestamp);
+                    for n in notifier.poll(timestamp) {
+                        task::spawn(notify::send(cfg.sinks.clone(), n));
+                    }
+                }
+                // the rules act on the stats of the controller they watch
+                for (id, cmd) in rules.eval(i, controller.as_ref(), timestamp) {
+                    match to_command(&config, Source::Rules, cmd.clone()) {
+                        Err(e) => error!("invalid rule action {}", e),
+                        Ok(ctlcmd) => {
+                            let (ctl, to_main) =
+                                (controllers[i].clone(), to_main.clone());
+                            let e = command_event(&ctl, Source::Rules, cmd);
+                            log_events(&mut eventlog, &[e]).await;
+                            let failed = ToMainLoop::RuleFailed(id);
+                            task::spawn(background_command(ctl, ctlcmd, failed, to_main));
+                        }
+                    }
+                }
+                for (j, st) in rules.status() {
+                    if j == i {
+                        if let Err(e) = netidx.update_rule(&mut batch, i, &st) {
+                            warn!("failed to publish rule {} {}", st.name, e)
+                        }
+                    }
+                }
+                let name = controllers[i].info.name.clone();
+                let st = Stats::V6 { timestamp, name, controller, aggregate: None };
                 statsbuf.clear();
                 log_fatal!(
                     serde_json::to_writer(&mut statsbuf, &st),
@@ -174,17 +493,20 @@ async fn run_server(config: Config) {
                     "fatal: failed to log stats {}",
                     break
                 );
-                let mut i = 0;
-                debug!("tick: writing stats to tailing clients");
-                while i < tailing.len() {
-                    match tailing[i].send(ToClient::Stats(st)).await {
-                        Ok(()) => i += 1,
+                let mut j = 0;
+                debug!("stats: writing stats to tailing clients");
+                while j < tailing.len() {
+                    match tailing[j].send(ToClient::Stats(st.clone())).await {
+                        Ok(()) => j += 1,
                         Err(_) => {
-                            tailing.remove(i);
+                            tailing.remove(j);
                         }
                     }
                 }
-                debug!("tick: finished");
+                debug!("stats: flushing publisher");
+                if batch.len() > 0 {
+                    batch.commit(Some(Duration::from_secs(10))).await;
+                }
             }
         }
     }
@@ -216,6 +538,82 @@ enum Settings {
     },
     #[structopt(name = "write", help = "write charge controller settings")]
     Write { file: String },
+    #[structopt(name = "history", help = "show the settings journal")]
+    History {
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(
+        name = "rollback",
+        help = "restore the settings in effect before journal entry n"
+    )]
+    Rollback { n: usize },
+}
+
+#[derive(Debug, StructOpt)]
+enum Archive {
+    #[structopt(
+        name = "rebuild",
+        help = "derive the aggregated stats again from the raw archives"
+    )]
+    Rebuild {
+        #[structopt(short = "f", long = "from", help = "first day, %Y%m%d")]
+        from: String,
+        #[structopt(short = "t", long = "to", help = "last day, %Y%m%d, default today")]
+        to: Option<String>,
+        #[structopt(
+            short = "r",
+            long = "resolution",
+            help = "a resolution to rebuild, e.g. 1h, default the configured ones"
+        )]
+        resolution: Vec<archive::Bucket>,
+    },
+    #[structopt(
+        name = "prune",
+        help = "remove the archives the retention policy doesn't keep"
+    )]
+    Prune {
+        #[structopt(short = "n", long = "dry-run", help = "only list what would go")]
+        dry_run: bool,
+    },
+    #[structopt(name = "verify", help = "check the archives for damage")]
+    Verify {
+        #[structopt(
+            long = "repair",
+            help = "salvage what can be read from the damaged archives"
+        )]
+        repair: bool,
+    },
+    #[structopt(name = "status", help = "show the state of the daemon's archiver")]
+    Status {
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+}
+
+#[derive(Debug, StructOpt)]
+enum Profile {
+    #[structopt(name = "save", help = "save the current settings as a named profile")]
+    Save {
+        name: String,
+        #[structopt(short = "f", long = "file", help = "save a settings file instead")]
+        file: Option<String>,
+    },
+    #[structopt(name = "list", help = "list saved profiles")]
+    List,
+    #[structopt(name = "show", help = "show a saved profile")]
+    Show {
+        name: String,
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(
+        name = "diff",
+        help = "compare a profile with another profile or the current settings"
+    )]
+    Diff { name: String, other: Option<String> },
+    #[structopt(name = "apply", help = "write a saved profile to the charge controller")]
+    Apply { name: String },
 }
 
 #[derive(Debug, StructOpt)]
@@ -239,6 +637,8 @@ enum SubCommand {
         file: Option<String>,
         #[structopt(short = "d", long = "to-date")]
         to_date: Option<String>,
+        #[structopt(subcommand)]
+        cmd: Option<Archive>,
     },
     #[structopt(name = "reset", help = "reset the charge controller")]
     ResetController,
@@ -249,6 +649,98 @@ enum SubCommand {
     },
     #[structopt(name = "settings", help = "read/write charge controller settings")]
     Settings(Settings),
+    #[structopt(name = "profile", help = "manage named settings profiles")]
+    Profile(Profile),
+    #[structopt(name = "schedule", help = "show the scheduled switches and overrides")]
+    Schedule {
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(name = "events", help = "show logged events")]
+    Events {
+        #[structopt(
+            short = "f",
+            long = "from",
+            help = "first day, %Y%m%d, default today"
+        )]
+        from: Option<String>,
+        #[structopt(short = "t", long = "to", help = "last day, %Y%m%d, default today")]
+        to: Option<String>,
+        #[structopt(
+            short = "k",
+            long = "kind",
+            help = "charge-state, load-state, fault, reachable, settings, or command"
+        )]
+        kind: Vec<ev::Kind>,
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(name = "export", help = "export logged stats")]
+    Export {
+        #[structopt(
+            short = "f",
+            long = "from",
+            help = "first day, %Y%m%d, default today"
+        )]
+        from: Option<String>,
+        #[structopt(short = "t", long = "to", help = "last day, %Y%m%d, default today")]
+        to: Option<String>,
+        #[structopt(
+            short = "r",
+            long = "resolution",
+            default_value = "raw",
+            help = "raw, 1m, or 10m"
+        )]
+        resolution: archive::Resolution,
+        #[structopt(
+            short = "b",
+            long = "bucket",
+            help = "aggregate into buckets of this size, e.g. 1h or 1d"
+        )]
+        bucket: Option<archive::Bucket>,
+        #[structopt(
+            short = "F",
+            long = "field",
+            help = "a field to export, default all"
+        )]
+        field: Vec<String>,
+        #[structopt(
+            long = "format",
+            default_value = "csv",
+            help = "csv, influx, or json"
+        )]
+        format: export::Format,
+        #[structopt(
+            short = "o",
+            long = "output",
+            help = "file to write, default stdout"
+        )]
+        output: Option<String>,
+    },
+    #[structopt(name = "rules", help = "show the automation rules and their states")]
+    Rules {
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(name = "controllers", help = "list controllers and their capabilities")]
+    Controllers {
+        #[structopt(short = "j", long = "json")]
+        json: bool,
+    },
+    #[structopt(
+        name = "modbus-standin",
+        help = "serve a stand in modbus tcp device for testing"
+    )]
+    ModbusStandin {
+        #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:1502")]
+        listen: String,
+        #[structopt(
+            short = "r",
+            long = "registers",
+            help = "a json file of the coils and registers to serve"
+        )]
+        registers: Option<String>,
+    },
 }
 
 #[derive(Debug, StructOpt)]
@@ -256,10 +748,38 @@ enum SubCommand {
 struct Options {
     #[structopt(short = "c", long = "config", default_value = "/etc/solar.conf")]
     config: String,
+    #[structopt(short = "n", long = "controller", help = "the controller to command")]
+    controller: Option<String>,
     #[structopt(subcommand)]
     cmd: SubCommand,
 }
 
+fn read_settings(config: &Config, target: Option<&str>) -> ControllerSettings {
+    let cmd = FromClient::ReadSettings.target(target);
+    match solar_client::send_query(config, cmd).expect("failed to get settings").next() {
+        None => panic!("no response from server"),
+        Some(ToClient::Err(e)) => panic!("failed to get settings {}", e),
+        Some(ToClient::Settings(s)) => s,
+        Some(_) => panic!("unexpected response"),
+    }
+}
+
+fn read_settings_file(file: &str) -> ControllerSettings {
+    let file = std::fs::File::open(file).expect("failed to open settings");
+    let settings = serde_json::from_reader(&file).expect("failed to parse settings");
+    profile::parse(settings).expect("failed to parse settings")
+}
+
+// a day given on the command line, today if not given
+fn parse_day(d: Option<String>) -> chrono::NaiveDate {
+    match d {
+        None => chrono::Local::today().naive_local(),
+        Some(d) => {
+            chrono::NaiveDate::parse_from_str(&d, "%Y%m%d").expect("invalid date, %Y%m%d")
+        }
+    }
+}
+
 fn main() {
     use std::fs;
     let opt = Options::from_args();
@@ -274,6 +794,7 @@ fn main() {
         },
     }
     use std::iter::once;
+    let target = opt.controller.as_deref();
     match opt.cmd {
         SubCommand::Start { daemonize } => {
             if daemonize {
@@ -298,29 +819,97 @@ fn main() {
         }
         SubCommand::Stop => solar_client::send_command(&config, once(FromClient::Stop))
             .expect("failed to stop the daemon"),
-        SubCommand::Load(v) => solar_client::send_command(
-            &config,
-            &[
-                FromClient::SetCharging(false),
-                FromClient::SetLoad(v.get()),
-                FromClient::SetCharging(true),
-            ],
-        )
-        .expect("failed to set the load. Is the daemon running?"),
+        SubCommand::Load(v) => {
+            let cmd = FromClient::set_load(v.get()).target(target);
+            solar_client::send_command(&config, once(cmd))
+                .expect("failed to set the load. Is the daemon running?")
+        }
         SubCommand::Charging(v) => {
-            solar_client::send_command(&config, once(FromClient::SetCharging(v.get())))
+            let cmd = FromClient::SetCharging(v.get()).target(target);
+            solar_client::send_command(&config, once(cmd))
                 .expect("failed to disable charging. Is the daemon running?")
         }
-        SubCommand::CancelFloat => solar_client::send_command(
-            &config,
-            &[FromClient::SetCharging(false), FromClient::SetCharging(true)],
-        )
-        .expect("failed to cancel float"),
+        SubCommand::CancelFloat => {
+            let cmd = FromClient::Transaction(vec![
+                Step::new(FromClient::SetCharging(false))
+                    .undo(FromClient::SetCharging(true)),
+                Step::new(FromClient::SetCharging(true)),
+            ]);
+            solar_client::send_command(&config, once(cmd.target(target)))
+                .expect("failed to cancel float")
+        }
         SubCommand::ResetController => {
-            solar_client::send_command(&config, once(FromClient::ResetController))
+            let cmd = FromClient::ResetController.target(target);
+            solar_client::send_command(&config, once(cmd))
                 .expect("failed to reset the controller")
         }
-        SubCommand::ArchiveLog { file, to_date } => {
+        SubCommand::ArchiveLog {
+            cmd: Some(Archive::Rebuild { from, to, resolution }),
+            ..
+        } => {
+            let (from, to) = (parse_day(Some(from)), parse_day(to));
+            let resolution = if resolution.is_empty() {
+                config.archive_resolutions.clone()
+            } else {
+                resolution
+            };
+            let missing = archive::rebuild(&config, from, to, &resolution)
+                .expect("failed to rebuild the archive");
+            for d in missing {
+                eprintln!("no raw stats archived for {}", d)
+            }
+        }
+        SubCommand::ArchiveLog { cmd: Some(Archive::Prune { dry_run }), .. } => {
+            if config.retention.is_none() {
+                println!("no retention policy is configured")
+            } else {
+                let removed = archive::prune(&config, dry_run)
+                    .expect("failed to prune the archive");
+                let bytes = removed.iter().map(|f| f.size).sum::<u64>();
+                for f in removed {
+                    println!("{}", f)
+                }
+                let verb = if dry_run { "would free" } else { "freed" };
+                println!("{} {} bytes", verb, bytes)
+            }
+        }
+        SubCommand::ArchiveLog { cmd: Some(Archive::Verify { repair }), .. } => {
+            let reports = verify::verify(&config).expect("failed to verify the archive");
+            let mut damaged = 0;
+            for r in &reports {
+                if r.problems.is_empty() {
+                    continue;
+                }
+                damaged += 1;
+                println!("{}", r);
+                if repair {
+                    match verify::repair(&config, r) {
+                        Ok(()) => println!("repaired {}", r.file.path.display()),
+                        Err(e) => {
+                            println!("failed to repair {} {}", r.file.path.display(), e)
+                        }
+                    }
+                }
+            }
+            println!("checked {} archives, {} with problems", reports.len(), damaged)
+        }
+        SubCommand::ArchiveLog { cmd: Some(Archive::Status { json }), .. } => {
+            match solar_client::send_query(&config, FromClient::ArchiveStatus)
+                .expect("failed to get the archive status")
+                .next()
+            {
+                None => panic!("no response from server"),
+                Some(ToClient::Archive(s)) => {
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&s).unwrap())
+                    } else {
+                        print!("{}", s)
+                    }
+                }
+                Some(_) => panic!("unexpected response"),
+            }
+        }
+        SubCommand::ArchiveLog { file, to_date, cmd: None } => {
             let to_date = to_date.map(|d| {
                 use chrono::{naive::NaiveDate, offset::LocalResult, prelude::*};
                 let nd = NaiveDate::parse_from_str(&d, "%Y%m%d")
@@ -340,12 +929,22 @@ fn main() {
                 .expect("failed to tail stats")
             {
                 match m {
-                    ToClient::Ok | ToClient::Err(_) | ToClient::Settings(_) => {
+                    ToClient::Ok
+                    | ToClient::Err(_)
+                    | ToClient::Settings(_)
+                    | ToClient::Controllers(_)
+                    | ToClient::Schedule(_)
+                    | ToClient::Rules(_)
+                    | ToClient::Archive(_) => {
                         panic!("unexpected response")
                     }
                     ToClient::Stats(s) => {
+                        if target.map(|t| t != s.name()).unwrap_or(false) {
+                            continue;
+                        }
                         if json {
-                            println!("{}", serde_json::to_string_pretty(&s).unwrap())
+                            let v = s.to_json_with_faults().unwrap();
+                            println!("{}", serde_json::to_string_pretty(&v).unwrap())
                         } else {
                             println!("{}", s)
                         }
@@ -354,29 +953,188 @@ fn main() {
             }
         }
         SubCommand::Settings(Settings::Read { json }) => {
-            match solar_client::send_query(&config, FromClient::ReadSettings)
-                .expect("failed to get settings")
+            let s = read_settings(&config, target);
+            if json {
+                println!("{}", serde_json::to_string_pretty(&s).unwrap())
+            } else {
+                println!("{}", s)
+            }
+        }
+        SubCommand::Settings(Settings::Write { file }) => {
+            let cmd = FromClient::WriteSettings(read_settings_file(&file)).target(target);
+            solar_client::send_command(&config, once(cmd))
+                .expect("failed to write settings")
+        }
+        SubCommand::Settings(Settings::History { json }) => {
+            let entries = journal::read(&config).expect("failed to read the journal");
+            for (i, e) in entries.iter().enumerate() {
+                if target.map(|t| t != e.controller).unwrap_or(false) {
+                    continue;
+                }
+                if json {
+                    println!("{}", serde_json::to_string(&e).unwrap())
+                } else {
+                    print!("{}: {}", i, e)
+                }
+            }
+        }
+        SubCommand::Settings(Settings::Rollback { n }) => {
+            let entries = journal::read(&config).expect("failed to read the journal");
+            let e = entries.get(n).expect("no such journal entry");
+            if target.map(|t| t != e.controller).unwrap_or(false) {
+                panic!("journal entry {} is for controller {}", n, e.controller)
+            }
+            let old = e.old.expect("the settings before this change are unknown");
+            let cmd = FromClient::WriteSettings(old).target(Some(e.controller.as_str()));
+            solar_client::send_command(&config, once(cmd))
+                .expect("failed to roll back settings")
+        }
+        SubCommand::Profile(Profile::Save { name, file }) => {
+            let settings = match file {
+                Some(file) => read_settings_file(&file),
+                None => read_settings(&config, target),
+            };
+            profile::save(&config, &name, &settings).expect("failed to save profile")
+        }
+        SubCommand::Profile(Profile::List) => {
+            for name in profile::list(&config).expect("failed to list profiles") {
+                println!("{}", name)
+            }
+        }
+        SubCommand::Profile(Profile::Show { name, json }) => {
+            let s = profile::load(&config, &name).expect("failed to load profile");
+            if json {
+                println!("{}", serde_json::to_string_pretty(&s).unwrap())
+            } else {
+                println!("{}", s)
+            }
+        }
+        SubCommand::Profile(Profile::Diff { name, other }) => {
+            let a = profile::load(&config, &name).expect("failed to load profile");
+            let (other, b) = match other {
+                Some(other) => match profile::load(&config, &other) {
+                    Ok(b) => (other, b),
+                    Err(e) => panic!("failed to load profile {}", e),
+                },
+                None => (String::from("current"), read_settings(&config, target)),
+            };
+            let d = profile::diff(&a, &b).expect("failed to compare settings");
+            if d.is_empty() {
+                println!("{} and {} are the same", name, other)
+            }
+            for (field, va, vb) in d {
+                println!("{}: {}: {} {}: {}", field, name, va, other, vb)
+            }
+        }
+        SubCommand::Profile(Profile::Apply { name }) => {
+            let cmd = FromClient::ApplyProfile(name).target(target);
+            solar_client::send_command(&config, once(cmd))
+                .expect("failed to apply profile")
+        }
+        SubCommand::Schedule { json } => {
+            match solar_client::send_query(&config, FromClient::ScheduleStatus)
+                .expect("failed to get the schedule")
                 .next()
             {
                 None => panic!("no response from server"),
-                Some(ToClient::Stats(_))
-                | Some(ToClient::Ok)
-                | Some(ToClient::Err(_)) => panic!("unexpected response"),
-                Some(ToClient::Settings(s)) => {
+                Some(ToClient::Schedule(l)) => {
                     if json {
-                        println!("{}", serde_json::to_string_pretty(&s).unwrap())
+                        println!("{}", serde_json::to_string_pretty(&l).unwrap())
                     } else {
-                        println!("{}", s)
+                        for s in l {
+                            if target.map(|t| t != s.controller).unwrap_or(false) {
+                                continue;
+                            }
+                            println!("{}", s)
+                        }
                     }
                 }
+                Some(_) => panic!("unexpected response"),
             }
         }
-        SubCommand::Settings(Settings::Write { file }) => {
-            let file = fs::File::open(&file).expect("failed to open settings");
-            let settings =
-                serde_json::from_reader(&file).expect("failed to parse settings");
-            solar_client::send_command(&config, once(FromClient::WriteSettings(settings)))
-                .expect("failed to write settings")
+        SubCommand::Events { from, to, kind, json } => {
+            let (from, to) = (parse_day(from), parse_day(to));
+            let events =
+                ev::read(&config, from, to, &kind).expect("failed to read events");
+            for e in events {
+                if target.map(|t| t != e.controller).unwrap_or(false) {
+                    continue;
+                }
+                if json {
+                    println!("{}", serde_json::to_string(&e).unwrap())
+                } else {
+                    println!("{}", e)
+                }
+            }
+        }
+        SubCommand::Export { from, to, resolution, bucket, field, format, output } => {
+            let (from, to) = (parse_day(from), parse_day(to));
+            let fields = export::fields(&field).expect("invalid field");
+            let range = archive::read_range(&config, from, to, resolution)
+                .expect("failed to read stats");
+            for d in range.missing() {
+                eprintln!("no stats for {}", d)
+            }
+            let stats = range.filter(|s| target.map(|t| t == s.name()).unwrap_or(true));
+            let stats: Box<dyn Iterator<Item = Stats> + '_> = match bucket {
+                None => Box::new(stats),
+                Some(b) => Box::new(archive::decimate(b, stats)),
+            };
+            let out: Box<dyn std::io::Write> = match output {
+                None => Box::new(std::io::stdout()),
+                Some(f) => Box::new(fs::File::create(f).expect("failed to open output")),
+            };
+            let mut out = std::io::BufWriter::new(out);
+            export::write(&mut out, format, &fields, stats).expect("failed to export")
+        }
+        SubCommand::Rules { json } => {
+            match solar_client::send_query(&config, FromClient::RuleStatus)
+                .expect("failed to get the rules")
+                .next()
+            {
+                None => panic!("no response from server"),
+                Some(ToClient::Rules(l)) => {
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&l).unwrap())
+                    } else {
+                        for s in l {
+                            if target.map(|t| t != s.controller).unwrap_or(false) {
+                                continue;
+                            }
+                            println!("{}", s)
+                        }
+                    }
+                }
+                Some(_) => panic!("unexpected response"),
+            }
+        }
+        SubCommand::Controllers { json } => {
+            match solar_client::send_query(&config, FromClient::ListControllers)
+                .expect("failed to list controllers")
+                .next()
+            {
+                None => panic!("no response from server"),
+                Some(ToClient::Controllers(l)) => {
+                    if json {
+                        println!("{}", serde_json::to_string_pretty(&l).unwrap())
+                    } else {
+                        for c in l {
+                            println!("{}", c)
+                        }
+                    }
+                }
+                Some(_) => panic!("unexpected response"),
+            }
+        }
+        SubCommand::ModbusStandin { listen, registers } => {
+            env_logger::builder()
+                .filter_level(config.log_level)
+                .parse_default_env()
+                .init();
+            Runtime::new()
+                .unwrap()
+                .block_on(modbus_tcp::run_standin(listen, registers))
+                .expect("modbus stand in failed")
         }
     }
 }
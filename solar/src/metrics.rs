@@ -0,0 +1,326 @@
+use crate::{queue::Handle, ToMainLoop};
+use chrono::prelude::*;
+use morningstar::prostar_mppt as ps;
+use solar_client::{rules, ControllerSettings, ControllerStats, MetricsConfig};
+use std::{fmt::Write as FmtWrite, sync::atomic::Ordering};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{channel, Sender},
+    task, time,
+};
+use uom::si::{
+    electric_current::ampere, electric_potential::volt, electrical_resistance::ohm,
+    thermodynamic_temperature::degree_celsius, time::second,
+};
+
+// how long a client gets to send it's request
+static REQUEST_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+static MAX_REQUEST: usize = 8192;
+static UNAVAILABLE: &str = "503 Service Unavailable";
+static CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+macro_rules! settings {
+    ($s:ident, $($fld:ident($unit:ty, $suffix:expr)),* $(,)?) => {
+        vec![$((stringify!($fld), $suffix, $s.$fld.get::<$unit>())),*]
+    };
+}
+
+fn prostar_mppt_settings(s: &ps::Settings) -> Vec<(&'static str, &'static str, f32)> {
+    let mut v = settings!(
+        s,
+        regulation_voltage(volt, "volts"),
+        float_voltage(volt, "volts"),
+        time_before_float(second, "seconds"),
+        time_before_float_low_battery(second, "seconds"),
+        float_low_battery_voltage_trigger(volt, "volts"),
+        float_cancel_voltage(volt, "volts"),
+        exit_float_time(second, "seconds"),
+        equalize_voltage(volt, "volts"),
+        days_between_equalize_cycles(second, "seconds"),
+        equalize_time_limit_above_regulation_voltage(second, "seconds"),
+        equalize_time_limit_at_regulation_voltage(second, "seconds"),
+        reference_charge_voltage_limit(volt, "volts"),
+        battery_charge_current_limit(ampere, "amperes"),
+        temperature_compensation_coefficent(volt, "volts_per_celsius"),
+        high_voltage_disconnect(volt, "volts"),
+        high_voltage_reconnect(volt, "volts"),
+        maximum_charge_voltage_reference(volt, "volts"),
+        max_battery_temp_compensation_limit(degree_celsius, "celsius"),
+        min_battery_temp_compensation_limit(degree_celsius, "celsius"),
+        load_low_voltage_disconnect(volt, "volts"),
+        load_low_voltage_reconnect(volt, "volts"),
+        load_high_voltage_disconnect(volt, "volts"),
+        load_high_voltage_reconnect(volt, "volts"),
+        lvd_load_current_compensation(ohm, "ohms"),
+        lvd_warning_timeout(second, "seconds"),
+        led_green_to_green_and_yellow_limit(volt, "volts"),
+        led_green_and_yellow_to_yellow_limit(volt, "volts"),
+        led_yellow_to_yellow_and_red_limit(volt, "volts"),
+        led_yellow_and_red_to_red_flashing_limit(volt, "volts"),
+        charge_current_limit(ampere, "amperes"),
+        mppt_fixed_vmp(volt, "volts"),
+    );
+    v.push(("mppt_fixed_vmp_percent", "percent", s.mppt_fixed_vmp_percent));
+    v
+}
+
+fn settings_values(s: &ControllerSettings) -> Vec<(&'static str, &'static str, f32)> {
+    match s {
+        ControllerSettings::ProstarMppt(s) => prostar_mppt_settings(s),
+    }
+}
+
+#[derive(Default)]
+struct Latest {
+    stats: Option<ControllerStats>,
+    settings: Option<ControllerSettings>,
+    last_success: Option<DateTime<Local>>,
+    consecutive_failures: u64,
+}
+
+/// The latest stats and settings of each controller, rendered in
+/// OpenMetrics text format on request.
+pub(crate) struct Metrics(Vec<Latest>);
+
+// a metric family, it's samples are added by the caller
+fn family(buf: &mut String, name: &str, typ: &str, unit: Option<&str>, help: &str) {
+    let _ = writeln!(buf, "# TYPE {} {}", name, typ);
+    if let Some(unit) = unit {
+        let _ = writeln!(buf, "# UNIT {} {}", name, unit);
+    }
+    let _ = writeln!(buf, "# HELP {} {}", name, help);
+}
+
+// label values escape backslash, double quote, and newline
+fn escape(v: &str) -> String {
+    let mut res = String::with_capacity(v.len());
+    for c in v.chars() {
+        match c {
+            '\\' => res.push_str("\\\\"),
+            '"' => res.push_str("\\\""),
+            '\n' => res.push_str("\\n"),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+// rust spells the non finite floats inf and NaN, openmetrics +Inf,
+// -Inf and NaN
+fn float(v: f32) -> String {
+    if v.is_nan() {
+        String::from("NaN")
+    } else if v.is_infinite() {
+        String::from(if v > 0. { "+Inf" } else { "-Inf" })
+    } else {
+        v.to_string()
+    }
+}
+
+fn sample(buf: &mut String, name: &str, controller: &str, v: impl std::fmt::Display) {
+    let _ = writeln!(buf, "{}{{controller=\"{}\"}} {}", name, escape(controller), v);
+}
+
+impl Metrics {
+    pub(crate) fn new(controllers: &[Handle]) -> Self {
+        Metrics(controllers.iter().map(|_| Latest::default()).collect())
+    }
+
+    pub(crate) fn stats(
+        &mut self,
+        i: usize,
+        st: Option<&ControllerStats>,
+        now: DateTime<Local>,
+    ) {
+        let l = &mut self.0[i];
+        match st {
+            None => l.consecutive_failures += 1,
+            Some(st) => {
+                l.stats = Some(*st);
+                l.last_success = Some(now);
+                l.consecutive_failures = 0;
+            }
+        }
+    }
+
+    pub(crate) fn settings(&mut self, i: usize, s: &ControllerSettings) {
+        self.0[i].settings = Some(*s);
+    }
+
+    pub(crate) fn render(&self, controllers: &[Handle]) -> String {
+        let mut buf = String::new();
+        let ctls = || controllers.iter().zip(self.0.iter());
+        for field in rules::FIELDS {
+            let unit = rules::unit(field).unwrap_or("");
+            let name = format!("solar_{}_{}", field, unit);
+            family(&mut buf, &name, "gauge", Some(unit), field);
+            for (c, l) in ctls() {
+                if let Some(v) = l.stats.as_ref().and_then(|st| rules::field(st, field)) {
+                    sample(&mut buf, &name, &c.info.name, float(v))
+                }
+            }
+        }
+        // every controller has the same settings, the layout comes from
+        // the first one that has been read
+        let layout = self.0.iter().find_map(|l| l.settings.as_ref().map(settings_values));
+        for (j, (field, unit, _)) in layout.unwrap_or_default().into_iter().enumerate() {
+            let name = format!("solar_setting_{}_{}", field, unit);
+            family(&mut buf, &name, "gauge", Some(unit), field);
+            for (c, l) in ctls() {
+                if let Some(s) = &l.settings {
+                    if let Some((_, _, v)) = settings_values(s).get(j) {
+                        sample(&mut buf, &name, &c.info.name, float(*v))
+                    }
+                }
+            }
+        }
+        let help = "1 if the last stats read succeeded";
+        family(&mut buf, "solar_controller_up", "gauge", None, help);
+        for (c, l) in ctls() {
+            let up = l.last_success.is_some() && l.consecutive_failures == 0;
+            sample(&mut buf, "solar_controller_up", &c.info.name, up as u8)
+        }
+        let name = "solar_controller_last_success_timestamp_seconds";
+        let help = "when stats were last read";
+        family(&mut buf, name, "gauge", Some("seconds"), help);
+        for (c, l) in ctls() {
+            if let Some(t) = l.last_success {
+                sample(&mut buf, name, &c.info.name, t.timestamp())
+            }
+        }
+        let name = "solar_controller_consecutive_failures";
+        let help = "stats reads failed since the last success";
+        family(&mut buf, name, "gauge", None, help);
+        for (c, l) in ctls() {
+            sample(&mut buf, name, &c.info.name, l.consecutive_failures)
+        }
+        let counters: [(&str, &str, fn(&Handle) -> u64); 5] = [
+            ("solar_modbus_stats_errors", "stats reads that failed", |c| {
+                c.counters.stats_errors.load(Ordering::Relaxed)
+            }),
+            ("solar_modbus_command_errors", "commands that failed", |c| {
+                c.counters.command_errors.load(Ordering::Relaxed)
+            }),
+            ("solar_modbus_preempted", "stats reads cancelled by a command", |c| {
+                c.counters.preempted.load(Ordering::Relaxed)
+            }),
+            ("solar_modbus_busy", "commands rejected by a full queue", |c| {
+                c.counters.busy.load(Ordering::Relaxed)
+            }),
+            ("solar_modbus_timeouts", "commands that timed out", |c| {
+                c.counters.timeouts.load(Ordering::Relaxed)
+            }),
+        ];
+        for (name, help, get) in counters.iter() {
+            family(&mut buf, name, "counter", None, help);
+            let total = format!("{}_total", name);
+            for c in controllers {
+                sample(&mut buf, &total, &c.info.name, get(c))
+            }
+        }
+        buf.push_str("# EOF\n");
+        buf
+    }
+}
+
+async fn respond(con: &mut TcpStream, status: &str, typ: &str, body: &str) {
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        typ,
+        body.len()
+    );
+    let _ = con.write_all(head.as_bytes()).await;
+    let _ = con.write_all(body.as_bytes()).await;
+}
+
+async fn read_request(con: &mut TcpStream) -> Option<String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() > MAX_REQUEST {
+            return None;
+        }
+        match con.read(&mut chunk).await {
+            Ok(0) | Err(_) => return None,
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&buf);
+    head.lines().next().map(String::from)
+}
+
+async fn handle_client(mut con: TcpStream, to_main: Sender<ToMainLoop>) {
+    let line = match time::timeout(REQUEST_TIMEOUT, read_request(&mut con)).await {
+        Ok(Some(line)) => line,
+        Ok(None) | Err(_) => return,
+    };
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let (tx, mut rx) = channel(1);
+            let body = match to_main.send(ToMainLoop::Metrics(tx)).await {
+                Err(_) => None,
+                Ok(()) => rx.recv().await,
+            };
+            match body {
+                None => respond(&mut con, UNAVAILABLE, "text/plain", "").await,
+                Some(body) => respond(&mut con, "200 OK", CONTENT_TYPE, &body).await,
+            }
+        }
+        (Some("GET"), Some(_)) => {
+            respond(&mut con, "404 Not Found", "text/plain", "try /metrics\n").await
+        }
+        (_, _) => respond(&mut con, "405 Method Not Allowed", "text/plain", "").await,
+    }
+}
+
+async fn accept_loop(cfg: MetricsConfig, to_main: Sender<ToMainLoop>) {
+    let listener = log_fatal!(
+        TcpListener::bind(&cfg.listen).await,
+        "failed to create metrics listener {}",
+        return
+    );
+    info!("metrics listening on {}", cfg.listen);
+    loop {
+        match listener.accept().await {
+            Ok((con, _)) => {
+                task::spawn(handle_client(con, to_main.clone()));
+            }
+            Err(e) => warn!("metrics: accept failed {}", e),
+        }
+    }
+}
+
+pub(crate) fn run_server(cfg: &MetricsConfig, to_main: Sender<ToMainLoop>) {
+    task::spawn(accept_loop(cfg.clone(), to_main));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_label_values() {
+        assert_eq!(escape("east"), "east");
+        assert_eq!(escape(r#"a"b\c"#), r#"a\"b\\c"#);
+        assert_eq!(escape("a\nb"), r"a\nb");
+    }
+
+    #[test]
+    fn escaped_sample() {
+        let mut buf = String::new();
+        sample(&mut buf, "solar_up", "shed \"north\"", 1);
+        assert_eq!(buf, "solar_up{controller=\"shed \\\"north\\\"\"} 1\n");
+    }
+
+    #[test]
+    fn non_finite_floats() {
+        assert_eq!(float(12.5), "12.5");
+        assert_eq!(float(f32::INFINITY), "+Inf");
+        assert_eq!(float(f32::NEG_INFINITY), "-Inf");
+        assert_eq!(float(f32::NAN), "NaN");
+    }
+}
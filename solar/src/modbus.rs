@@ -1,14 +1,83 @@
+use crate::{
+    controller::{Coil, Controller},
+    modbus_tcp,
+};
 use anyhow::{Error, Result};
+use async_trait::async_trait;
 use log::warn;
 use morningstar::prostar_mppt as ps;
-use std::time::{Duration, Instant};
-use tokio::time;
+use solar_client::{Capabilities, ControllerSettings, ControllerStats, Model};
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{sync::Mutex as AsyncMutex, time};
 
 static CMDTO: Duration = Duration::from_secs(30);
+static TCP_PREFIX: &str = "tcp:";
+
+// A serial device is opened directly by the controller connection. A
+// Modbus TCP gateway is reached through a bridge, and raw requests
+// share it's link.
+enum Transport {
+    Serial(String),
+    Tcp {
+        addr: String,
+        link: Arc<AsyncMutex<modbus_tcp::Link>>,
+        bridge: Option<modbus_tcp::Bridge>,
+    },
+}
+
+impl Transport {
+    fn new(device: String) -> Self {
+        if device.starts_with(TCP_PREFIX) {
+            let addr = String::from(&device[TCP_PREFIX.len()..]);
+            let link = Arc::new(AsyncMutex::new(modbus_tcp::Link::tcp(addr.clone())));
+            Transport::Tcp { addr, link, bridge: None }
+        } else {
+            Transport::Serial(device)
+        }
+    }
+
+    // the serial device the controller connection should open
+    fn device(&mut self) -> Result<String> {
+        match self {
+            Transport::Serial(device) => Ok(device.clone()),
+            Transport::Tcp { addr, link, bridge } => {
+                if bridge.is_none() {
+                    *bridge = Some(modbus_tcp::Bridge::new(addr, link.clone())?);
+                }
+                let path = bridge.as_ref().unwrap().path();
+                Ok(path.to_string_lossy().into_owned())
+            }
+        }
+    }
+
+    // A cancelled request may still get a reply, so the controller
+    // connection is given a new pty where it can't be mistaken for the
+    // reply to the next request. The link is kept.
+    fn reset(&mut self) {
+        if let Transport::Tcp { bridge, .. } = self {
+            *bridge = None
+        }
+    }
+
+    // Send one request pdu and return the reply pdu. The serial port
+    // can only be open once, so the controller connection must be
+    // closed first.
+    async fn raw(&self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Transport::Serial(device) => {
+                modbus_tcp::Link::serial(device.clone()).transact(unit, pdu).await
+            }
+            Transport::Tcp { link, .. } => link.lock().await.transact(unit, pdu).await,
+        }
+    }
+}
 
 pub struct Connection {
     con: Option<ps::Connection>,
-    device: String,
+    transport: Transport,
     address: u8,
     last_command: Instant,
 }
@@ -22,13 +91,18 @@ enum Command<'a> {
 
 impl Connection {
     pub async fn new(device: String, address: u8) -> Self {
-        Connection { con: None, device, address, last_command: Instant::now() }
+        Connection {
+            con: None,
+            transport: Transport::new(device),
+            address,
+            last_command: Instant::now(),
+        }
     }
 
     async fn get_con(&mut self) -> Result<&mut ps::Connection> {
         match self.con {
             Some(ref mut con) => Ok(con),
-            None => match ps::Connection::new(&self.device, self.address).await {
+            None => match ps::Connection::new(&self.transport.device()?, self.address).await {
                 Err(e) => {
                     warn!("failed to connect to controller {}", e);
                     Err(e)
@@ -140,4 +214,74 @@ impl Connection {
         self.wait_for_throttle().await;
         Ok(self.eval_command(Command::WriteSettings(settings)).await?)
     }
+
+    /// pass a raw register request through to the controller
+    pub async fn raw(
+        &mut self,
+        req: &modbus_tcp::Request,
+    ) -> Result<modbus_tcp::Response> {
+        self.wait_for_throttle().await;
+        let pdu = req.encode();
+        if let Transport::Serial(_) = self.transport {
+            // reopened by the next command
+            self.con = None;
+        }
+        let reply =
+            time::timeout(CMDTO, self.transport.raw(self.address, &pdu)).await??;
+        req.decode_reply(&reply)
+    }
+}
+
+pub(crate) fn prostar_coil(coil: Coil) -> ps::Coil {
+    match coil {
+        Coil::ChargeDisconnect => ps::Coil::ChargeDisconnect,
+        Coil::LoadDisconnect => ps::Coil::LoadDisconnect,
+        Coil::ResetControl => ps::Coil::ResetControl,
+    }
+}
+
+#[async_trait]
+impl Controller for Connection {
+    fn model(&self) -> Model {
+        Model::ProstarMppt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            charging_control: true,
+            load_control: true,
+            reset: true,
+            settings: true,
+            register_access: true,
+        }
+    }
+
+    async fn read_stats(&mut self) -> Result<ControllerStats> {
+        Ok(ControllerStats::ProstarMppt(Connection::read_stats(self).await?))
+    }
+
+    async fn read_settings(&mut self) -> Result<ControllerSettings> {
+        Ok(ControllerSettings::ProstarMppt(Connection::read_settings(self).await?))
+    }
+
+    async fn write_settings(&mut self, settings: &ControllerSettings) -> Result<()> {
+        match settings {
+            ControllerSettings::ProstarMppt(s) => {
+                Connection::write_settings(self, s).await
+            }
+        }
+    }
+
+    async fn write_coil(&mut self, coil: Coil, bit: bool) -> Result<()> {
+        Connection::write_coil(self, prostar_coil(coil), bit).await
+    }
+
+    async fn raw(&mut self, req: &modbus_tcp::Request) -> Result<modbus_tcp::Response> {
+        Connection::raw(self, req).await
+    }
+
+    fn reset(&mut self) {
+        self.con = None;
+        self.transport.reset();
+    }
 }
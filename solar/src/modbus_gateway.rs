@@ -0,0 +1,94 @@
+use crate::{
+    modbus_tcp::{self, Request, Response},
+    ToMainLoop,
+};
+use solar_client::{ControllerConfig, ModbusGatewayConfig};
+use std::collections::HashMap;
+use tokio::{
+    net::TcpListener,
+    sync::mpsc::{channel, Sender},
+    task,
+};
+
+async fn forward(
+    to_main: Sender<ToMainLoop>,
+    writable: bool,
+    unit: u8,
+    req: Request,
+) -> Result<Response, u8> {
+    if req.is_write() && !writable {
+        debug!("modbus gateway rejected write {:?}", req);
+        return Err(modbus_tcp::ILLEGAL_FUNCTION);
+    }
+    let (tx, mut rx) = channel(1);
+    match to_main.send(ToMainLoop::Modbus(unit, req, tx)).await {
+        Err(_) => Err(modbus_tcp::SERVER_DEVICE_FAILURE),
+        Ok(()) => match rx.recv().await {
+            None => Err(modbus_tcp::SERVER_DEVICE_FAILURE),
+            Some(r) => r,
+        },
+    }
+}
+
+async fn accept_loop(cfg: ModbusGatewayConfig, to_main: Sender<ToMainLoop>) {
+    let listener = log_fatal!(
+        TcpListener::bind(&cfg.listen).await,
+        "failed to create modbus gateway {}",
+        return
+    );
+    info!("modbus gateway listening on {}, writable: {}", cfg.listen, cfg.writable);
+    let writable = cfg.writable;
+    modbus_tcp::serve(listener, move |unit, req| {
+        forward(to_main.clone(), writable, unit, req)
+    })
+    .await
+}
+
+/// Requests are routed to the controller with the request's unit id,
+/// so every controller must have a different modbus_id
+pub(crate) fn check_units(controllers: &[ControllerConfig]) -> anyhow::Result<()> {
+    let mut units = HashMap::new();
+    for c in controllers {
+        if let Some(other) = units.insert(c.modbus_id, &c.name) {
+            bail!(
+                "controllers {} and {} both have modbus_id {}, the gateway can't tell them apart",
+                other,
+                c.name,
+                c.modbus_id
+            )
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn run_server(cfg: &ModbusGatewayConfig, to_main: Sender<ToMainLoop>) {
+    task::spawn(accept_loop(cfg.clone(), to_main));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solar_client::Model;
+
+    fn controller(name: &str, modbus_id: u8) -> ControllerConfig {
+        ControllerConfig {
+            name: String::from(name),
+            model: Model::default(),
+            device: String::from("sim:"),
+            modbus_id,
+            battery: None,
+        }
+    }
+
+    #[test]
+    fn distinct_units() {
+        let controllers = [controller("east", 1), controller("west", 2)];
+        assert!(check_units(&controllers).is_ok())
+    }
+
+    #[test]
+    fn duplicate_units() {
+        let controllers = [controller("east", 1), controller("west", 1)];
+        assert!(check_units(&controllers).is_err())
+    }
+}
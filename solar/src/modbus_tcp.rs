@@ -0,0 +1,808 @@
+use anyhow::{Error, Result};
+use futures::prelude::*;
+use log::{info, warn};
+use parking_lot::Mutex;
+use serde_derive::Deserialize;
+use std::{
+    collections::HashMap,
+    ffi::CStr,
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        fs::OpenOptionsExt,
+        io::{AsRawFd, FromRawFd},
+    },
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream as AsyncTcpStream},
+    runtime::Handle,
+    sync::Mutex as AsyncMutex,
+    task, time,
+};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+// the longest one transaction on the link may take
+static LINKTO: Duration = Duration::from_secs(10);
+
+pub(crate) static ILLEGAL_FUNCTION: u8 = 0x01;
+pub(crate) static ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+pub(crate) static ILLEGAL_DATA_VALUE: u8 = 0x03;
+pub(crate) static SERVER_DEVICE_FAILURE: u8 = 0x04;
+pub(crate) static GATEWAY_PATH_UNAVAILABLE: u8 = 0x0A;
+pub(crate) static GATEWAY_TARGET_FAILED: u8 = 0x0B;
+
+pub(crate) fn crc16(buf: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for b in buf {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xA001
+            } else {
+                crc >>= 1
+            }
+        }
+    }
+    crc
+}
+
+fn u16_at(buf: &[u8], i: usize) -> u16 {
+    u16::from_be_bytes([buf[i], buf[i + 1]])
+}
+
+// read one rtu request frame, as sent by a modbus master, returning the
+// unit id and the pdu
+fn read_rtu_request(r: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut frame = vec![0u8; 2];
+    r.read_exact(&mut frame)?;
+    let rest = match frame[1] {
+        1..=6 => 6,
+        15 | 16 => {
+            let mut b = [0u8; 5];
+            r.read_exact(&mut b)?;
+            frame.extend_from_slice(&b);
+            b[4] as usize + 2
+        }
+        fc => bail!("unsupported function code {}", fc),
+    };
+    let n = frame.len();
+    frame.resize(n + rest, 0);
+    r.read_exact(&mut frame[n..])?;
+    let n = frame.len();
+    if u16::from_le_bytes([frame[n - 2], frame[n - 1]]) != crc16(&frame[..n - 2]) {
+        bail!("rtu frame crc mismatch")
+    }
+    Ok((frame[0], frame[1..n - 2].to_vec()))
+}
+
+/// Send one request to a device on an RTU link and return the reply pdu
+pub(crate) async fn rtu_transact<S>(s: &mut S, unit: u8, pdu: &[u8]) -> Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    s.write_all(&frame).await?;
+    s.flush().await?;
+    let mut reply = vec![0u8; 2];
+    s.read_exact(&mut reply).await?;
+    let rest = if reply[1] & 0x80 != 0 {
+        3
+    } else {
+        match reply[1] {
+            1..=4 => {
+                let mut b = [0u8; 1];
+                s.read_exact(&mut b).await?;
+                reply.push(b[0]);
+                b[0] as usize + 2
+            }
+            5 | 6 | 15 | 16 => 6,
+            fc => bail!("unsupported function code in reply {}", fc),
+        }
+    };
+    let n = reply.len();
+    reply.resize(n + rest, 0);
+    s.read_exact(&mut reply[n..]).await?;
+    let n = reply.len();
+    if u16::from_le_bytes([reply[n - 2], reply[n - 1]]) != crc16(&reply[..n - 2]) {
+        bail!("rtu frame crc mismatch")
+    }
+    if reply[0] != unit {
+        bail!("reply from unexpected unit {}", reply[0])
+    }
+    Ok(reply[1..n - 2].to_vec())
+}
+
+fn write_rtu(w: &mut impl Write, unit: u8, pdu: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(pdu.len() + 3);
+    frame.push(unit);
+    frame.extend_from_slice(pdu);
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    w.write_all(&frame)?;
+    Ok(w.flush()?)
+}
+
+fn mbap_frame(tid: u16, unit: u8, pdu: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(7 + pdu.len());
+    buf.extend_from_slice(&tid.to_be_bytes());
+    buf.extend_from_slice(&[0, 0]);
+    buf.extend_from_slice(&((pdu.len() + 1) as u16).to_be_bytes());
+    buf.push(unit);
+    buf.extend_from_slice(pdu);
+    buf
+}
+
+fn check_mbap_header(hdr: &[u8; 7]) -> Result<usize> {
+    let len = u16_at(hdr, 4) as usize;
+    if u16_at(hdr, 2) != 0 {
+        bail!("not a modbus tcp frame")
+    }
+    if len < 2 || len > 254 {
+        bail!("invalid modbus tcp frame length {}", len)
+    }
+    Ok(len - 1)
+}
+
+/// The link to the devices behind a controller connection, a Modbus
+/// TCP gateway, or for raw requests a serial line at the ProStar's
+/// fixed 9600 baud 8N2. The port or connection is dropped on any error
+/// and reopened by the next transaction.
+pub(crate) enum Link {
+    Serial { device: String, port: Option<SerialStream> },
+    Tcp { addr: String, stream: Option<AsyncTcpStream>, tid: u16 },
+}
+
+impl Link {
+    pub(crate) fn serial(device: String) -> Self {
+        Link::Serial { device, port: None }
+    }
+
+    pub(crate) fn tcp(addr: String) -> Self {
+        Link::Tcp { addr, stream: None, tid: 0 }
+    }
+
+    fn close(&mut self) {
+        match self {
+            Link::Serial { port, .. } => *port = None,
+            Link::Tcp { stream, .. } => *stream = None,
+        }
+    }
+
+    async fn try_transact(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>> {
+        match self {
+            Link::Serial { device, port } => {
+                if port.is_none() {
+                    *port = Some(
+                        tokio_serial::new(device.as_str(), 9600)
+                            .stop_bits(tokio_serial::StopBits::Two)
+                            .open_native_async()?,
+                    );
+                }
+                rtu_transact(port.as_mut().unwrap(), unit, pdu).await
+            }
+            Link::Tcp { addr, stream, tid } => {
+                if stream.is_none() {
+                    let s = AsyncTcpStream::connect(addr.as_str()).await?;
+                    s.set_nodelay(true)?;
+                    info!("connected to modbus tcp gateway {}", addr);
+                    *stream = Some(s);
+                }
+                let s = stream.as_mut().unwrap();
+                *tid = tid.wrapping_add(1);
+                s.write_all(&mbap_frame(*tid, unit, pdu)).await?;
+                loop {
+                    let mut hdr = [0u8; 7];
+                    s.read_exact(&mut hdr).await?;
+                    let mut reply = vec![0u8; check_mbap_header(&hdr)?];
+                    s.read_exact(&mut reply).await?;
+                    // discard late replies to requests that already timed out
+                    if u16_at(&hdr, 0) == *tid {
+                        break Ok(reply);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send one request pdu to a device on the link and return the
+    /// reply pdu
+    pub(crate) async fn transact(&mut self, unit: u8, pdu: &[u8]) -> Result<Vec<u8>> {
+        let r = match time::timeout(LINKTO, self.try_transact(unit, pdu)).await {
+            Err(e) => Err(Error::from(e)),
+            Ok(r) => r,
+        };
+        if r.is_err() {
+            self.close()
+        }
+        r
+    }
+}
+
+fn open_pty() -> Result<(File, PathBuf)> {
+    unsafe {
+        let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+        if fd < 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        let master = File::from_raw_fd(fd);
+        if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        let mut buf = [0 as libc::c_char; 128];
+        if libc::ptsname_r(fd, buf.as_mut_ptr(), buf.len()) != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        let path = PathBuf::from(CStr::from_ptr(buf.as_ptr()).to_string_lossy().as_ref());
+        Ok((master, path))
+    }
+}
+
+fn open_raw(path: &Path) -> Result<File> {
+    let f = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    unsafe {
+        let mut t: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(f.as_raw_fd(), &mut t) != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+        libc::cfmakeraw(&mut t);
+        if libc::tcsetattr(f.as_raw_fd(), libc::TCSANOW, &t) != 0 {
+            return Err(Error::from(io::Error::last_os_error()));
+        }
+    }
+    Ok(f)
+}
+
+fn run_bridge(mut master: File, name: String, rt: Handle, link: Arc<AsyncMutex<Link>>) {
+    loop {
+        match read_rtu_request(&mut master) {
+            Err(e) => {
+                // the pty hangs up when the bridge is dropped
+                if e.downcast_ref::<io::Error>().is_some() {
+                    info!("modbus bridge to {} closed {}", name, e);
+                    break;
+                }
+                warn!("modbus bridge dropped request {}", e)
+            }
+            // on failure send nothing, the controller connection will time
+            // out and retry like it does on a serial line
+            Ok((unit, pdu)) => {
+                let r =
+                    rt.block_on(async { link.lock().await.transact(unit, &pdu).await });
+                match r {
+                    Err(e) => warn!("modbus request to {} failed {}", name, e),
+                    Ok(reply) => {
+                        if let Err(e) = write_rtu(&mut master, unit, &reply) {
+                            info!("modbus bridge to {} closed {}", name, e);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The morningstar crate only speaks RTU on a serial device it opens
+/// itself, so the controller connection is given a pseudo terminal.
+/// The bridge thread reads the RTU frames written to the pty, sends
+/// them over the link, and writes the replies back as RTU. Raw
+/// requests from the gateway lock the same link, so they never need a
+/// second TCP connection.
+pub(crate) struct Bridge {
+    path: PathBuf,
+    // keep the slave open so the master never sees a hangup between
+    // controller reconnects
+    _slave: File,
+}
+
+impl Bridge {
+    /// must be called from within the tokio runtime
+    pub(crate) fn new(name: &str, link: Arc<AsyncMutex<Link>>) -> Result<Self> {
+        let (master, path) = open_pty()?;
+        let slave = open_raw(&path)?;
+        let name = String::from(name);
+        let rt = Handle::current();
+        let n = name.clone();
+        thread::Builder::new()
+            .name(format!("modbus-bridge-{}", name))
+            .spawn(move || run_bridge(master, n, rt, link))?;
+        info!("modbus bridge to {} on {:?}", name, path);
+        Ok(Bridge { path, _slave: slave })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Request {
+    ReadCoils(u16, u16),
+    ReadDiscreteInputs(u16, u16),
+    ReadHoldingRegisters(u16, u16),
+    ReadInputRegisters(u16, u16),
+    WriteSingleCoil(u16, bool),
+    WriteSingleRegister(u16, u16),
+    WriteMultipleCoils(u16, Vec<bool>),
+    WriteMultipleRegisters(u16, Vec<u16>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Response {
+    Bits(Vec<bool>),
+    Registers(Vec<u16>),
+    Written,
+    Exception(u8),
+}
+
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    let mut buf = vec![0u8; (bits.len() + 7) / 8];
+    for (i, b) in bits.iter().enumerate() {
+        if *b {
+            buf[i / 8] |= 1 << (i % 8);
+        }
+    }
+    buf
+}
+
+fn reply_data(pdu: &[u8]) -> Result<&[u8]> {
+    let len = *pdu.get(1).ok_or_else(|| anyhow!("truncated reply"))? as usize;
+    pdu.get(2..2 + len).ok_or_else(|| anyhow!("truncated reply"))
+}
+
+impl Request {
+    /// decode a request pdu, on failure return the modbus exception code
+    pub(crate) fn decode(pdu: &[u8]) -> std::result::Result<Request, u8> {
+        if pdu.len() < 5 {
+            return Err(ILLEGAL_DATA_VALUE);
+        }
+        let (a, n) = (u16_at(pdu, 1), u16_at(pdu, 3));
+        match pdu[0] {
+            1 | 2 if n == 0 || n > 2000 => Err(ILLEGAL_DATA_VALUE),
+            3 | 4 if n == 0 || n > 125 => Err(ILLEGAL_DATA_VALUE),
+            1 => Ok(Request::ReadCoils(a, n)),
+            2 => Ok(Request::ReadDiscreteInputs(a, n)),
+            3 => Ok(Request::ReadHoldingRegisters(a, n)),
+            4 => Ok(Request::ReadInputRegisters(a, n)),
+            5 => match n {
+                0xFF00 => Ok(Request::WriteSingleCoil(a, true)),
+                0x0000 => Ok(Request::WriteSingleCoil(a, false)),
+                _ => Err(ILLEGAL_DATA_VALUE),
+            },
+            6 => Ok(Request::WriteSingleRegister(a, n)),
+            15 | 16 => {
+                let len = *pdu.get(5).ok_or(ILLEGAL_DATA_VALUE)? as usize;
+                let data = pdu.get(6..6 + len).ok_or(ILLEGAL_DATA_VALUE)?;
+                if pdu[0] == 15 {
+                    if n == 0 || len != (n as usize + 7) / 8 {
+                        return Err(ILLEGAL_DATA_VALUE);
+                    }
+                    let bits = (0..n as usize).map(|i| data[i / 8] & (1 << (i % 8)) != 0);
+                    Ok(Request::WriteMultipleCoils(a, bits.collect()))
+                } else {
+                    if n == 0 || n > 123 || len != n as usize * 2 {
+                        return Err(ILLEGAL_DATA_VALUE);
+                    }
+                    let regs = (0..n as usize).map(|i| u16_at(data, i * 2));
+                    Ok(Request::WriteMultipleRegisters(a, regs.collect()))
+                }
+            }
+            _ => Err(ILLEGAL_FUNCTION),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![self.function()];
+        let mut push = |v: u16| buf.extend_from_slice(&v.to_be_bytes());
+        match self {
+            Request::ReadCoils(a, n)
+            | Request::ReadDiscreteInputs(a, n)
+            | Request::ReadHoldingRegisters(a, n)
+            | Request::ReadInputRegisters(a, n) => {
+                push(*a);
+                push(*n);
+            }
+            Request::WriteSingleCoil(a, b) => {
+                push(*a);
+                push(if *b { 0xFF00 } else { 0x0000 });
+            }
+            Request::WriteSingleRegister(a, v) => {
+                push(*a);
+                push(*v);
+            }
+            Request::WriteMultipleCoils(a, bits) => {
+                push(*a);
+                push(bits.len() as u16);
+                let packed = pack_bits(bits);
+                buf.push(packed.len() as u8);
+                buf.extend_from_slice(&packed);
+            }
+            Request::WriteMultipleRegisters(a, regs) => {
+                push(*a);
+                push(regs.len() as u16);
+                buf.push((regs.len() * 2) as u8);
+                for r in regs {
+                    buf.extend_from_slice(&r.to_be_bytes());
+                }
+            }
+        }
+        buf
+    }
+
+    /// decode a device's reply to this request
+    pub(crate) fn decode_reply(&self, pdu: &[u8]) -> Result<Response> {
+        let fc = self.function();
+        match pdu.get(0) {
+            None => bail!("empty reply"),
+            Some(f) if *f == fc | 0x80 => match pdu.get(1) {
+                None => bail!("truncated exception reply"),
+                Some(code) => return Ok(Response::Exception(*code)),
+            },
+            Some(f) if *f != fc => bail!("reply to the wrong function {}", f),
+            Some(_) => (),
+        }
+        match self {
+            Request::ReadCoils(_, n) | Request::ReadDiscreteInputs(_, n) => {
+                let data = reply_data(pdu)?;
+                if data.len() * 8 < *n as usize {
+                    bail!("short coil reply")
+                }
+                let bits = (0..*n as usize).map(|i| data[i / 8] & (1 << (i % 8)) != 0);
+                Ok(Response::Bits(bits.collect()))
+            }
+            Request::ReadHoldingRegisters(_, n) | Request::ReadInputRegisters(_, n) => {
+                let data = reply_data(pdu)?;
+                if data.len() != *n as usize * 2 {
+                    bail!("short register reply")
+                }
+                let regs = (0..*n as usize).map(|i| u16_at(data, i * 2));
+                Ok(Response::Registers(regs.collect()))
+            }
+            Request::WriteSingleCoil(_, _)
+            | Request::WriteSingleRegister(_, _)
+            | Request::WriteMultipleCoils(_, _)
+            | Request::WriteMultipleRegisters(_, _) => Ok(Response::Written),
+        }
+    }
+
+    pub(crate) fn is_write(&self) -> bool {
+        match self {
+            Request::ReadCoils(_, _)
+            | Request::ReadDiscreteInputs(_, _)
+            | Request::ReadHoldingRegisters(_, _)
+            | Request::ReadInputRegisters(_, _) => false,
+            Request::WriteSingleCoil(_, _)
+            | Request::WriteSingleRegister(_, _)
+            | Request::WriteMultipleCoils(_, _)
+            | Request::WriteMultipleRegisters(_, _) => true,
+        }
+    }
+
+    pub(crate) fn function(&self) -> u8 {
+        match self {
+            Request::ReadCoils(_, _) => 1,
+            Request::ReadDiscreteInputs(_, _) => 2,
+            Request::ReadHoldingRegisters(_, _) => 3,
+            Request::ReadInputRegisters(_, _) => 4,
+            Request::WriteSingleCoil(_, _) => 5,
+            Request::WriteSingleRegister(_, _) => 6,
+            Request::WriteMultipleCoils(_, _) => 15,
+            Request::WriteMultipleRegisters(_, _) => 16,
+        }
+    }
+
+    /// encode a reply to this request
+    pub(crate) fn reply(&self, rsp: &Response) -> Vec<u8> {
+        let mut buf = vec![self.function()];
+        match (self, rsp) {
+            (_, Response::Bits(bits)) => {
+                let packed = pack_bits(bits);
+                buf.push(packed.len() as u8);
+                buf.extend_from_slice(&packed);
+            }
+            (_, Response::Registers(regs)) => {
+                buf.push((regs.len() * 2) as u8);
+                for r in regs {
+                    buf.extend_from_slice(&r.to_be_bytes());
+                }
+            }
+            (Request::WriteSingleCoil(a, b), Response::Written) => {
+                buf.extend_from_slice(&a.to_be_bytes());
+                buf.extend_from_slice(if *b { &[0xFF, 0x00] } else { &[0x00, 0x00] });
+            }
+            (Request::WriteSingleRegister(a, v), Response::Written) => {
+                buf.extend_from_slice(&a.to_be_bytes());
+                buf.extend_from_slice(&v.to_be_bytes());
+            }
+            (Request::WriteMultipleCoils(a, bits), Response::Written) => {
+                buf.extend_from_slice(&a.to_be_bytes());
+                buf.extend_from_slice(&(bits.len() as u16).to_be_bytes());
+            }
+            (Request::WriteMultipleRegisters(a, regs), Response::Written) => {
+                buf.extend_from_slice(&a.to_be_bytes());
+                buf.extend_from_slice(&(regs.len() as u16).to_be_bytes());
+            }
+            (_, Response::Exception(code)) => return exception(self.function(), *code),
+            (_, Response::Written) => {
+                return exception(self.function(), SERVER_DEVICE_FAILURE);
+            }
+        }
+        buf
+    }
+}
+
+pub(crate) fn exception(function: u8, code: u8) -> Vec<u8> {
+    vec![function | 0x80, code]
+}
+
+async fn serve_client<F, Fut>(mut stream: AsyncTcpStream, handler: F) -> Result<()>
+where
+    F: Fn(u8, Request) -> Fut,
+    Fut: Future<Output = std::result::Result<Response, u8>>,
+{
+    loop {
+        let mut hdr = [0u8; 7];
+        stream.read_exact(&mut hdr).await?;
+        let mut pdu = vec![0u8; check_mbap_header(&hdr)?];
+        stream.read_exact(&mut pdu).await?;
+        let (tid, unit) = (u16_at(&hdr, 0), hdr[6]);
+        let reply = match Request::decode(&pdu) {
+            Err(code) => exception(pdu[0], code),
+            Ok(req) => match handler(unit, req.clone()).await {
+                Ok(rsp) => req.reply(&rsp),
+                Err(code) => exception(req.function(), code),
+            },
+        };
+        stream.write_all(&mbap_frame(tid, unit, &reply)).await?;
+    }
+}
+
+/// Serve Modbus TCP on the listener, passing each decoded request to
+/// the handler. The handler returns the response or an exception code.
+pub(crate) async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(u8, Request) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = std::result::Result<Response, u8>> + Send + 'static,
+{
+    loop {
+        match listener.accept().await {
+            Err(e) => warn!("modbus tcp accept failed {}", e),
+            Ok((stream, addr)) => {
+                info!("modbus tcp client connected {}", addr);
+                let handler = handler.clone();
+                task::spawn(async move {
+                    let r = serve_client(stream, handler).await;
+                    info!("modbus tcp client {} disconnected {:?}", addr, r);
+                });
+            }
+        }
+    }
+}
+
+#[derive(Default, Deserialize)]
+struct RegisterBank {
+    #[serde(default)]
+    coils: HashMap<u16, bool>,
+    #[serde(default)]
+    registers: HashMap<u16, u16>,
+}
+
+impl RegisterBank {
+    fn process(&mut self, req: Request) -> std::result::Result<Response, u8> {
+        let range = |a: u16, n: u16| {
+            let (a, n) = (a as u32, n as u32);
+            if a + n > 0x10000 {
+                Err(ILLEGAL_DATA_ADDRESS)
+            } else {
+                Ok((a..a + n).map(|i| i as u16))
+            }
+        };
+        match req {
+            Request::ReadCoils(a, n) | Request::ReadDiscreteInputs(a, n) => {
+                Ok(Response::Bits(
+                    range(a, n)?
+                        .map(|i| self.coils.get(&i).copied().unwrap_or(false))
+                        .collect(),
+                ))
+            }
+            Request::ReadHoldingRegisters(a, n) | Request::ReadInputRegisters(a, n) => {
+                Ok(Response::Registers(
+                    range(a, n)?
+                        .map(|i| self.registers.get(&i).copied().unwrap_or(0))
+                        .collect(),
+                ))
+            }
+            Request::WriteSingleCoil(a, b) => {
+                self.coils.insert(a, b);
+                Ok(Response::Written)
+            }
+            Request::WriteSingleRegister(a, v) => {
+                self.registers.insert(a, v);
+                Ok(Response::Written)
+            }
+            Request::WriteMultipleCoils(a, bits) => {
+                for (i, b) in range(a, bits.len() as u16)?.zip(bits) {
+                    self.coils.insert(i, b);
+                }
+                Ok(Response::Written)
+            }
+            Request::WriteMultipleRegisters(a, regs) => {
+                for (i, v) in range(a, regs.len() as u16)?.zip(regs) {
+                    self.registers.insert(i, v);
+                }
+                Ok(Response::Written)
+            }
+        }
+    }
+}
+
+/// A local stand in for a Modbus TCP device. It serves the coils and
+/// registers in the json file, e.g. ones captured from a real
+/// controller through the gateway, as {"coils": {"1": true},
+/// "registers": {"0": 12}}. Anything not in the file reads as zero
+/// until it is written.
+pub(crate) async fn run_standin(listen: String, file: Option<String>) -> Result<()> {
+    let bank: RegisterBank = match file {
+        None => RegisterBank::default(),
+        Some(file) => serde_json::from_slice(&fs::read(&file)?)?,
+    };
+    let listener = TcpListener::bind(&listen).await?;
+    info!("modbus tcp stand in listening on {}", listen);
+    let bank = Arc::new(Mutex::new(bank));
+    serve(listener, move |_unit, req| {
+        let bank = bank.clone();
+        async move { bank.lock().process(req) }
+    })
+    .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn rtu(unit: u8, pdu: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        write_rtu(&mut buf, unit, pdu).unwrap();
+        buf
+    }
+
+    fn requests() -> Vec<Request> {
+        vec![
+            Request::ReadCoils(1, 10),
+            Request::ReadDiscreteInputs(2, 3),
+            Request::ReadHoldingRegisters(0xE000, 34),
+            Request::ReadInputRegisters(8, 2),
+            Request::WriteSingleCoil(1, true),
+            Request::WriteSingleCoil(2, false),
+            Request::WriteSingleRegister(0xE010, 1234),
+            Request::WriteMultipleCoils(
+                0,
+                vec![true, false, true, true, false, false, true, false, true],
+            ),
+            Request::WriteMultipleRegisters(0xE000, vec![1, 2, 0xFFFF]),
+        ]
+    }
+
+    #[test]
+    fn crc() {
+        // the example from the modbus serial line specification
+        assert_eq!(crc16(&[0x01, 0x03, 0x00, 0x00, 0x00, 0x0A]), 0xCDC5);
+        let frame = rtu(1, &[0x03, 0x00, 0x00, 0x00, 0x0A]);
+        assert_eq!(&frame[6..], &[0xC5, 0xCD]);
+    }
+
+    #[test]
+    fn rtu_requests() {
+        for req in requests() {
+            let pdu = req.encode();
+            let mut frame = Cursor::new(rtu(7, &pdu));
+            let (unit, read) = read_rtu_request(&mut frame).unwrap();
+            assert_eq!((unit, &read), (7, &pdu), "{:?}", req);
+            assert_eq!(frame.position() as usize, frame.get_ref().len());
+        }
+    }
+
+    #[test]
+    fn rtu_bad_frames() {
+        let mut frame = rtu(1, &Request::ReadHoldingRegisters(0, 2).encode());
+        frame[3] ^= 1;
+        assert!(read_rtu_request(&mut Cursor::new(frame)).is_err());
+        let frame = rtu(1, &[0x2B, 0x0E, 0x01, 0x00]);
+        assert!(read_rtu_request(&mut Cursor::new(frame)).is_err());
+        let frame = rtu(1, &Request::ReadHoldingRegisters(0, 2).encode());
+        assert!(read_rtu_request(&mut Cursor::new(&frame[..5])).is_err());
+    }
+
+    #[tokio::test]
+    async fn rtu_transactions() {
+        let req = Request::ReadHoldingRegisters(0x10, 2);
+        let reply = req.reply(&Response::Registers(vec![0x1234, 0x5678]));
+        let (mut client, mut device) = tokio::io::duplex(256);
+        device.write_all(&rtu(1, &reply)).await.unwrap();
+        let got = rtu_transact(&mut client, 1, &req.encode()).await.unwrap();
+        assert_eq!(got, reply);
+        let mut sent = vec![0u8; 8];
+        device.read_exact(&mut sent).await.unwrap();
+        assert_eq!(read_rtu_request(&mut Cursor::new(sent)).unwrap(), (1, req.encode()));
+        // exceptions are passed back as a reply
+        let ex = exception(3, ILLEGAL_DATA_ADDRESS);
+        device.write_all(&rtu(1, &ex)).await.unwrap();
+        let got = rtu_transact(&mut client, 1, &req.encode()).await.unwrap();
+        assert!(matches!(req.decode_reply(&got), Ok(Response::Exception(2))));
+        // a reply from another device on the line is an error
+        device.write_all(&rtu(2, &reply)).await.unwrap();
+        assert!(rtu_transact(&mut client, 1, &req.encode()).await.is_err());
+    }
+
+    #[test]
+    fn encode_decode() {
+        for req in requests() {
+            let pdu = req.encode();
+            assert_eq!(pdu[0], req.function());
+            let decoded = Request::decode(&pdu).unwrap();
+            assert_eq!(decoded.encode(), pdu, "{:?}", req);
+        }
+        let pdu = Request::WriteSingleCoil(1, true).encode();
+        assert_eq!(pdu, vec![5, 0, 1, 0xFF, 0]);
+    }
+
+    #[test]
+    fn decode_errors() {
+        assert_eq!(Request::decode(&[3, 0, 0]).unwrap_err(), ILLEGAL_DATA_VALUE);
+        assert_eq!(Request::decode(&[3, 0, 0, 0, 0]).unwrap_err(), ILLEGAL_DATA_VALUE);
+        assert_eq!(Request::decode(&[3, 0, 0, 0, 126]).unwrap_err(), ILLEGAL_DATA_VALUE);
+        assert_eq!(Request::decode(&[5, 0, 0, 0x12, 0]).unwrap_err(), ILLEGAL_DATA_VALUE);
+        assert_eq!(Request::decode(&[43, 0, 0, 0, 1]).unwrap_err(), ILLEGAL_FUNCTION);
+        // the byte count must match the register count
+        let pdu = [16, 0, 0, 0, 2, 2, 0, 1];
+        assert_eq!(Request::decode(&pdu).unwrap_err(), ILLEGAL_DATA_VALUE);
+    }
+
+    #[test]
+    fn replies() {
+        let req = Request::ReadCoils(0, 10);
+        let bits = (0..10).map(|i| i % 3 == 0).collect::<Vec<_>>();
+        let pdu = req.reply(&Response::Bits(bits.clone()));
+        assert_eq!(pdu, vec![1, 2, 0b0100_1001, 0b10]);
+        assert!(matches!(req.decode_reply(&pdu), Ok(Response::Bits(b)) if b == bits));
+        let req = Request::ReadInputRegisters(0, 2);
+        let pdu = req.reply(&Response::Registers(vec![1, 2]));
+        assert!(
+            matches!(req.decode_reply(&pdu), Ok(Response::Registers(r)) if r == [1, 2])
+        );
+        assert!(req.decode_reply(&pdu[..4]).is_err());
+        assert!(req.decode_reply(&[3, 4, 0, 1, 0, 2]).is_err());
+        let req = Request::WriteSingleRegister(0xE010, 7);
+        let pdu = req.reply(&Response::Written);
+        assert_eq!(pdu, req.encode());
+        assert!(matches!(req.decode_reply(&pdu), Ok(Response::Written)));
+    }
+
+    #[test]
+    fn register_bank() {
+        let mut bank: RegisterBank =
+            serde_json::from_str(r#"{"registers": {"0": 12, "1": 34}}"#).unwrap();
+        let r = bank.process(Request::ReadHoldingRegisters(0, 3));
+        assert!(matches!(r, Ok(Response::Registers(r)) if r == [12, 34, 0]));
+        bank.process(Request::WriteMultipleCoils(4, vec![true, true])).unwrap();
+        let r = bank.process(Request::ReadCoils(3, 3));
+        assert!(matches!(r, Ok(Response::Bits(b)) if b == [false, true, true]));
+        let r = bank.process(Request::ReadHoldingRegisters(0xFFFF, 2));
+        assert!(matches!(r, Err(c) if c == ILLEGAL_DATA_ADDRESS));
+    }
+}
@@ -0,0 +1,360 @@
+use crate::ToMainLoop;
+use morningstar::prostar_mppt::{ChargeState, LoadState};
+use rumqttc::{AsyncClient, Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::json;
+use solar_client::{
+    rules, schedule::Switch, ControllerInfo, ControllerStats, MqttConfig, Source,
+    ToClient,
+};
+use std::{collections::HashMap, time::Duration};
+use tokio::{
+    sync::mpsc::{channel, Sender},
+    task, time,
+};
+
+// how long to wait before reconnecting to the broker
+static RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// requests queued for the broker before publishing starts dropping stats
+static QUEUE_DEPTH: usize = 1000;
+
+// the home assistant device class, unit, and state class of a stats unit
+fn sensor_class(unit: &str) -> (Option<&'static str>, &'static str, &'static str) {
+    match unit {
+        "volts" => (Some("voltage"), "V", "measurement"),
+        "amperes" => (Some("current"), "A", "measurement"),
+        "watts" => (Some("power"), "W", "measurement"),
+        "celsius" => (Some("temperature"), "°C", "measurement"),
+        // the resettable and daily counters also only go up between
+        // resets, which total_increasing allows for
+        "kilowatt_hours" => (Some("energy"), "kWh", "total_increasing"),
+        "amp_hours" => (None, "Ah", "total_increasing"),
+        "hours" => (Some("duration"), "h", "total_increasing"),
+        "percent" => (None, "%", "measurement"),
+        _ => (None, "", "measurement"),
+    }
+}
+
+// whether the switch is on, the same as the netidx control values
+fn switch_on(st: &ControllerStats, switch: Switch) -> bool {
+    match (st, switch) {
+        (ControllerStats::ProstarMppt(st), Switch::Load) => match st.load_state {
+            LoadState::Disconnect | LoadState::Fault | LoadState::LVD => false,
+            LoadState::LVDWarning
+            | LoadState::Normal
+            | LoadState::NormalOff
+            | LoadState::NotUsed
+            | LoadState::Override
+            | LoadState::Start
+            | LoadState::Unknown(_) => true,
+        },
+        (ControllerStats::ProstarMppt(st), Switch::Charging) => match st.charge_state {
+            ChargeState::Disconnect | ChargeState::Fault => false,
+            ChargeState::UnknownState(_)
+            | ChargeState::Absorption
+            | ChargeState::BulkMPPT
+            | ChargeState::Equalize
+            | ChargeState::Fixed
+            | ChargeState::Float
+            | ChargeState::Night
+            | ChargeState::NightCheck
+            | ChargeState::Start
+            | ChargeState::Slave => true,
+        },
+    }
+}
+
+fn payload(on: bool) -> &'static str {
+    if on {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+#[derive(Clone)]
+struct Topics {
+    prefix: String,
+    discovery_prefix: String,
+}
+
+impl Topics {
+    fn status(&self) -> String {
+        format!("{}/status", self.prefix)
+    }
+
+    fn controller_status(&self, controller: &str) -> String {
+        format!("{}/{}/status", self.prefix, controller)
+    }
+
+    fn field(&self, controller: &str, field: &str) -> String {
+        format!("{}/{}/stats/{}", self.prefix, controller, field)
+    }
+
+    fn switch_state(&self, controller: &str, switch: &Switch) -> String {
+        format!("{}/{}/{}/state", self.prefix, controller, switch)
+    }
+
+    fn switch_set(&self, controller: &str, switch: &Switch) -> String {
+        format!("{}/{}/{}/set", self.prefix, controller, switch)
+    }
+
+    // prefix/controller/switch/set
+    fn parse_set(&self, topic: &str) -> Option<(String, Switch)> {
+        let rest = topic.strip_prefix(&self.prefix)?.strip_prefix('/')?;
+        let mut parts = rest.split('/');
+        let (controller, switch, set) = (parts.next()?, parts.next()?, parts.next()?);
+        if set != "set" || parts.next().is_some() {
+            return None;
+        }
+        let switch = match switch {
+            "load" => Switch::Load,
+            "charging" => Switch::Charging,
+            _ => return None,
+        };
+        Some((String::from(controller), switch))
+    }
+
+    fn discovery(&self, component: &str, controller: &str, object: &str) -> String {
+        format!(
+            "{}/{}/solar_{}/{}/config",
+            self.discovery_prefix, component, controller, object
+        )
+    }
+}
+
+fn switches(info: &ControllerInfo) -> Vec<Switch> {
+    let mut res = Vec::new();
+    if info.capabilities.load_control {
+        res.push(Switch::Load)
+    }
+    if info.capabilities.charging_control {
+        res.push(Switch::Charging)
+    }
+    res
+}
+
+// the retained discovery configs for every sensor and switch
+fn discovery(topics: &Topics, infos: &[ControllerInfo]) -> Vec<(String, String)> {
+    let mut res = Vec::new();
+    for info in infos {
+        let name = &info.name;
+        let device = json!({
+            "identifiers": [format!("solar_{}", name)],
+            "name": name,
+            "manufacturer": "Morningstar",
+            "model": format!("{:?}", info.model),
+        });
+        let availability = json!([
+            {"topic": topics.status()},
+            {"topic": topics.controller_status(name)},
+        ]);
+        for field in rules::FIELDS {
+            let (device_class, unit, state_class) =
+                sensor_class(rules::unit(field).unwrap_or(""));
+            let mut cfg = json!({
+                "name": format!("{} {}", name, field.replace('_', " ")),
+                "unique_id": format!("solar_{}_{}", name, field),
+                "state_topic": topics.field(name, field),
+                "unit_of_measurement": unit,
+                "state_class": state_class,
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            });
+            if let Some(class) = device_class {
+                cfg["device_class"] = json!(class);
+            }
+            res.push((topics.discovery("sensor", name, field), cfg.to_string()));
+        }
+        for switch in switches(info) {
+            let cfg = json!({
+                "name": format!("{} {}", name, switch),
+                "unique_id": format!("solar_{}_{}", name, switch),
+                "command_topic": topics.switch_set(name, &switch),
+                "state_topic": topics.switch_state(name, &switch),
+                "availability": availability,
+                "availability_mode": "all",
+                "device": device,
+            });
+            let object = switch.to_string();
+            res.push((topics.discovery("switch", name, &object), cfg.to_string()));
+        }
+    }
+    res
+}
+
+// announce ourselves, this runs after every (re)connect because the
+// broker may have lost retained messages
+async fn announce(client: AsyncClient, topics: Topics, infos: Vec<ControllerInfo>) {
+    for info in &infos {
+        for switch in switches(info) {
+            let topic = topics.switch_set(&info.name, &switch);
+            if let Err(e) = client.subscribe(topic, QoS::AtLeastOnce).await {
+                warn!("mqtt: subscribe failed {}", e)
+            }
+        }
+    }
+    for (topic, cfg) in discovery(&topics, &infos) {
+        if let Err(e) = client.publish(topic, QoS::AtLeastOnce, true, cfg).await {
+            warn!("mqtt: failed to publish discovery {}", e)
+        }
+    }
+    let r = client.publish(topics.status(), QoS::AtLeastOnce, true, "online").await;
+    if let Err(e) = r {
+        warn!("mqtt: failed to publish status {}", e)
+    }
+}
+
+// run a switch command on the main loop, the new state is published
+// with the next stats
+async fn set_switch(
+    to_main: Sender<ToMainLoop>,
+    controller: String,
+    switch: Switch,
+    on: bool,
+) {
+    let cmd = switch.command(on).source(Source::Mqtt).target(Some(&controller));
+    let (reply_tx, mut reply_rx) = channel(1);
+    if to_main.send(ToMainLoop::FromClient(cmd, reply_tx)).await.is_err() {
+        return;
+    }
+    if let Some(ToClient::Err(e)) = reply_rx.recv().await {
+        warn!("mqtt: failed to set {} {} {}", controller, switch, e)
+    }
+}
+
+async fn event_loop(
+    mut eventloop: rumqttc::EventLoop,
+    client: AsyncClient,
+    topics: Topics,
+    infos: Vec<ControllerInfo>,
+    to_main: Sender<ToMainLoop>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                info!("mqtt: connected");
+                task::spawn(announce(client.clone(), topics.clone(), infos.clone()));
+            }
+            Ok(Event::Incoming(Packet::Publish(p))) => {
+                let (controller, switch) = match topics.parse_set(&p.topic) {
+                    Some(s) => s,
+                    None => continue,
+                };
+                let on = match std::str::from_utf8(&p.payload).map(str::trim) {
+                    Ok("ON") => true,
+                    Ok("OFF") => false,
+                    _ => {
+                        warn!("mqtt: ignoring invalid command on {}", p.topic);
+                        continue;
+                    }
+                };
+                task::spawn(set_switch(to_main.clone(), controller, switch, on));
+            }
+            Ok(_) => (),
+            Err(e) => {
+                warn!("mqtt: connection failed {}", e);
+                time::sleep(RECONNECT_DELAY).await
+            }
+        }
+    }
+}
+
+// stats must never block the main loop, when the broker is slow they
+// are dropped, the next change will be published
+fn publish(client: &AsyncClient, topic: String, qos: QoS, payload: String) {
+    if let Err(e) = client.try_publish(topic, qos, true, payload) {
+        debug!("mqtt: dropped publish {}", e)
+    }
+}
+
+/// Publishes each controller's stats to an MQTT broker, with Home
+/// Assistant discovery configs for the sensors and switches.
+pub(crate) struct Mqtt {
+    client: AsyncClient,
+    topics: Topics,
+    // the last value published for each field and switch, and
+    // reachability
+    last: Vec<Last>,
+}
+
+struct Last {
+    name: String,
+    reachable: Option<bool>,
+    values: HashMap<&'static str, f32>,
+    switches: Vec<(Switch, Option<bool>)>,
+}
+
+impl Mqtt {
+    pub(crate) fn start(
+        cfg: &MqttConfig,
+        infos: &[ControllerInfo],
+        to_main: Sender<ToMainLoop>,
+    ) -> Self {
+        let topics = Topics {
+            prefix: cfg.prefix.clone(),
+            discovery_prefix: cfg.discovery_prefix.clone(),
+        };
+        let mut opts = MqttOptions::new(&cfg.client_id, &cfg.host, cfg.port);
+        opts.set_last_will(LastWill::new(
+            topics.status(),
+            "offline",
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(user) = &cfg.username {
+            opts.set_credentials(user, cfg.password.as_deref().unwrap_or(""));
+        }
+        let (client, eventloop) = AsyncClient::new(opts, QUEUE_DEPTH);
+        task::spawn(event_loop(
+            eventloop,
+            client.clone(),
+            topics.clone(),
+            infos.to_vec(),
+            to_main,
+        ));
+        let last = infos
+            .iter()
+            .map(|i| Last {
+                name: i.name.clone(),
+                reachable: None,
+                values: HashMap::new(),
+                switches: switches(i).into_iter().map(|s| (s, None)).collect(),
+            })
+            .collect();
+        Mqtt { client, topics, last }
+    }
+
+    /// publish the fields of a controller's stats, and the state of
+    /// it's switches, that changed
+    pub(crate) fn stats(&mut self, i: usize, st: Option<&ControllerStats>) {
+        let Last { name, reachable, values, switches } = &mut self.last[i];
+        if *reachable != Some(st.is_some()) {
+            *reachable = Some(st.is_some());
+            let status = if st.is_some() { "online" } else { "offline" };
+            let topic = self.topics.controller_status(name);
+            publish(&self.client, topic, QoS::AtLeastOnce, status.into());
+        }
+        let st = match st {
+            None => return,
+            Some(st) => st,
+        };
+        for field in rules::FIELDS {
+            if let Some(v) = rules::field(st, field) {
+                if values.get(field) != Some(&v) {
+                    values.insert(*field, v);
+                    let topic = self.topics.field(name, field);
+                    publish(&self.client, topic, QoS::AtMostOnce, v.to_string());
+                }
+            }
+        }
+        for (switch, last) in switches {
+            let on = switch_on(st, *switch);
+            if *last != Some(on) {
+                *last = Some(on);
+                let topic = self.topics.switch_state(name, switch);
+                publish(&self.client, topic, QoS::AtLeastOnce, payload(on).into());
+            }
+        }
+    }
+}
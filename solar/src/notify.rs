@@ -0,0 +1,295 @@
+use anyhow::Result;
+use chrono::{prelude::*, Duration};
+use morningstar::prostar_mppt as ps;
+use solar_client::{
+    events::{Detail, Event},
+    notify::{Notification, NotifyConfig, Sink, Trigger},
+    rules, ControllerStats,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    process::Stdio,
+    time,
+};
+use tokio::{io::AsyncWriteExt, process, task};
+
+// how long a sink gets to deliver one notification
+static SEND_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+// the battery temperature condition clears this far below the limit
+static TEMPERATURE_HYSTERESIS: f32 = 5.;
+
+// load states are logged as the Debug name of the controller's
+// LoadState, see events::Tracker
+fn is_lvd(state: &str) -> bool {
+    state == format!("{:?}", ps::LoadState::LVD)
+}
+
+#[derive(Default)]
+struct Condition {
+    // the state last notified and when
+    sent: Option<(DateTime<Local>, bool)>,
+    // the latest change, waiting to be sent
+    pending: Option<Notification>,
+}
+
+#[derive(Default)]
+struct Controller {
+    unreachable_since: Option<DateTime<Local>>,
+    unreachable: bool,
+    hot: bool,
+}
+
+/// Turns events and stats into notifications, holding back repeats
+/// and changes to the same condition that come too quickly.
+pub(crate) struct Notifier {
+    cfg: NotifyConfig,
+    controllers: HashMap<String, Controller>,
+    conditions: HashMap<(String, Trigger, String), Condition>,
+    // when the notifications in the last hour were sent
+    recent: VecDeque<DateTime<Local>>,
+    limited: bool,
+}
+
+impl Notifier {
+    pub(crate) fn new(cfg: &NotifyConfig) -> Self {
+        Notifier {
+            cfg: cfg.clone(),
+            controllers: HashMap::new(),
+            conditions: HashMap::new(),
+            recent: VecDeque::new(),
+            limited: false,
+        }
+    }
+
+    fn raise(
+        &mut self,
+        controller: &str,
+        trigger: Trigger,
+        subject: &str,
+        active: bool,
+        message: String,
+    ) {
+        if self.cfg.enabled(trigger) {
+            let n = Notification {
+                timestamp: Local::now(),
+                controller: String::from(controller),
+                trigger,
+                subject: String::from(subject),
+                active,
+                message,
+            };
+            let key = (n.controller.clone(), trigger, n.subject.clone());
+            self.conditions.entry(key).or_default().pending = Some(n);
+        }
+    }
+
+    pub(crate) fn events(&mut self, events: &[Event]) {
+        for e in events {
+            match &e.detail {
+                Detail::Fault { field, flag, active } => {
+                    let subject = format!("{}/{}", field, flag);
+                    let m = format!("{} {}", field, flag);
+                    self.raise(&e.controller, Trigger::Fault, &subject, *active, m)
+                }
+                Detail::LoadState { from, to } => {
+                    let was = from.as_deref().map(is_lvd).unwrap_or(false);
+                    let is = is_lvd(to);
+                    if was != is {
+                        let m = format!("load state {}", to);
+                        self.raise(&e.controller, Trigger::Lvd, "load", is, m)
+                    }
+                }
+                Detail::ChargeState { .. }
+                | Detail::Reachable(_)
+                | Detail::Settings(_)
+                | Detail::Command { .. } => (),
+            }
+        }
+    }
+
+    pub(crate) fn stats(
+        &mut self,
+        name: &str,
+        st: Option<&ControllerStats>,
+        now: DateTime<Local>,
+    ) {
+        let after = Duration::minutes(self.cfg.unreachable_after as i64);
+        let limit = self.cfg.battery_temperature;
+        let mut ctl = self.controllers.remove(name).unwrap_or_default();
+        match st {
+            None => {
+                let since = *ctl.unreachable_since.get_or_insert(now);
+                if !ctl.unreachable && now - since >= after {
+                    ctl.unreachable = true;
+                    let m = format!("unreachable since {}", since);
+                    self.raise(name, Trigger::Unreachable, "controller", true, m)
+                }
+            }
+            Some(st) => {
+                ctl.unreachable_since = None;
+                if ctl.unreachable {
+                    ctl.unreachable = false;
+                    let m = String::from("reachable");
+                    self.raise(name, Trigger::Unreachable, "controller", false, m)
+                }
+                if let Some(t) = rules::field(st, "battery_temperature") {
+                    let hot = if ctl.hot {
+                        t > limit - TEMPERATURE_HYSTERESIS
+                    } else {
+                        t > limit
+                    };
+                    if hot != ctl.hot {
+                        ctl.hot = hot;
+                        let m = format!("battery temperature {}C, limit {}C", t, limit);
+                        self.raise(name, Trigger::BatteryTemperature, "battery", hot, m)
+                    }
+                }
+            }
+        }
+        self.controllers.insert(String::from(name), ctl);
+    }
+
+    /// The notifications that are ready to send
+    pub(crate) fn poll(&mut self, now: DateTime<Local>) -> Vec<Notification> {
+        let repeat_after = Duration::seconds(self.cfg.repeat_after as i64);
+        let hour_ago = now - Duration::hours(1);
+        while self.recent.front().map(|t| *t < hour_ago).unwrap_or(false) {
+            self.recent.pop_front();
+        }
+        let mut ready = Vec::new();
+        for c in self.conditions.values_mut() {
+            let n = match &c.pending {
+                None => continue,
+                Some(n) => n,
+            };
+            match c.sent {
+                Some((_, active)) if active == n.active => {
+                    c.pending = None;
+                    continue;
+                }
+                Some((t, _)) if now - t < repeat_after => continue,
+                // a condition that clears before it was ever sent is
+                // not interesting
+                None if !n.active => {
+                    c.pending = None;
+                    continue;
+                }
+                Some(_) | None => (),
+            }
+            if self.recent.len() >= self.cfg.max_per_hour {
+                if !self.limited {
+                    warn!("notify: rate limit reached, holding notifications");
+                    self.limited = true;
+                }
+                continue;
+            }
+            self.limited = false;
+            self.recent.push_back(now);
+            c.sent = Some((now, n.active));
+            ready.extend(c.pending.take());
+        }
+        ready
+    }
+}
+
+async fn run_command(program: &str, args: &[String], n: &Notification) -> Result<()> {
+    let mut child = process::Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&serde_json::to_vec(n)?).await?;
+    }
+    let status = child.wait().await?;
+    if !status.success() {
+        bail!("{} exited with {}", program, status)
+    }
+    Ok(())
+}
+
+fn syslog(n: &Notification) -> Result<()> {
+    let formatter = syslog::Formatter3164 {
+        facility: syslog::Facility::LOG_DAEMON,
+        hostname: None,
+        process: "solar".into(),
+        pid: std::process::id() as i32,
+    };
+    let mut logger = syslog::unix(formatter).map_err(|e| anyhow!("{}", e))?;
+    let msg = n.to_string();
+    let r = if n.active { logger.warning(msg) } else { logger.notice(msg) };
+    r.map_err(|e| anyhow!("{}", e))
+}
+
+async fn webhook(url: &str, n: &Notification) -> Result<()> {
+    reqwest::Client::new().post(url).json(n).send().await?.error_for_status()?;
+    Ok(())
+}
+
+async fn deliver(sink: &Sink, n: &Notification) -> Result<()> {
+    match sink {
+        Sink::Command { program, args } => run_command(program, args, n).await,
+        Sink::Syslog => {
+            let n = n.clone();
+            task::spawn_blocking(move || syslog(&n)).await?
+        }
+        Sink::Webhook { url } => webhook(url, n).await,
+    }
+}
+
+/// Send a notification to every sink. A sink that fails doesn't stop
+/// the others.
+pub(crate) async fn send(sinks: Vec<Sink>, n: Notification) {
+    info!("notify: {}", n);
+    for sink in &sinks {
+        match tokio::time::timeout(SEND_TIMEOUT, deliver(sink, &n)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => warn!("notify: {:?} failed {}", sink, e),
+            Err(_) => warn!("notify: {:?} timed out", sink),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> NotifyConfig {
+        NotifyConfig {
+            sinks: vec![Sink::Syslog],
+            triggers: Vec::new(),
+            unreachable_after: 5,
+            battery_temperature: 45.,
+            repeat_after: 3600,
+            max_per_hour: 20,
+        }
+    }
+
+    fn load_state(from: Option<ps::LoadState>, to: ps::LoadState) -> Event {
+        let from = from.map(|s| format!("{:?}", s));
+        let to = format!("{:?}", to);
+        Event::new("east", Detail::LoadState { from, to })
+    }
+
+    #[test]
+    fn lvd_is_notified() {
+        let mut n = Notifier::new(&config());
+        n.events(&[load_state(Some(ps::LoadState::Normal), ps::LoadState::LVD)]);
+        let sent = n.poll(Local::now());
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].trigger, Trigger::Lvd);
+        assert!(sent[0].active);
+        n.events(&[load_state(Some(ps::LoadState::LVD), ps::LoadState::Normal)]);
+        let sent = n.poll(Local::now() + Duration::hours(2));
+        assert_eq!(sent.len(), 1);
+        assert!(!sent[0].active);
+    }
+
+    #[test]
+    fn other_load_states_are_not_notified() {
+        let mut n = Notifier::new(&config());
+        n.events(&[load_state(None, ps::LoadState::Normal)]);
+        n.events(&[load_state(Some(ps::LoadState::Normal), ps::LoadState::Disconnect)]);
+        assert!(n.poll(Local::now()).is_empty());
+    }
+}
@@ -8,10 +8,19 @@ use netidx::{
     chars::Chars,
     path::Path,
     pool::Pooled,
-    publisher::{BindCfg, DesiredAuth, Publisher, UpdateBatch, Val, Value, WriteRequest},
+    publisher::{
+        BindCfg, DesiredAuth, Publisher, SendResult, UpdateBatch, Val, Value,
+        WriteRequest,
+    },
 };
 use parking_lot::Mutex;
-use solar_client::{Config, FromClient, ToClient};
+use solar_client::{
+    faults,
+    rules::RuleStatus,
+    schedule::{ScheduleStatus, Switch},
+    Capabilities, Config, ControllerInfo, ControllerSettings, ControllerStats,
+    FromClient, Source, ToClient,
+};
 use std::sync::Arc;
 use tokio::{
     sync::mpsc::{self, Sender},
@@ -29,6 +38,63 @@ use uom::si::{
     time::{day, hour, minute, second},
 };
 
+// a boolean for each named bit of a fault or alarm bitfield
+struct PublishedFlags(Vec<(u32, Val)>);
+
+impl PublishedFlags {
+    fn new(publisher: &Publisher, base: &Path, table: &[&str]) -> Result<Self> {
+        let mut flags = Vec::new();
+        for (i, name) in table.iter().enumerate() {
+            if !name.is_empty() {
+                let val = publisher.publish(base.append(name), Value::Null)?;
+                flags.push((1 << i, val))
+            }
+        }
+        Ok(PublishedFlags(flags))
+    }
+
+    fn update(&self, batch: &mut UpdateBatch, bits: u32) {
+        for (mask, val) in &self.0 {
+            let v = if bits & mask != 0 { Value::True } else { Value::False };
+            val.update_changed(batch, v)
+        }
+    }
+}
+
+struct PublishedFaults {
+    array_faults: PublishedFlags,
+    load_faults: PublishedFlags,
+    alarms: PublishedFlags,
+    array_faults_daily: PublishedFlags,
+    load_faults_daily: PublishedFlags,
+    alarms_daily: PublishedFlags,
+}
+
+impl PublishedFaults {
+    fn new(publisher: &Publisher, base: &Path) -> Result<Self> {
+        let flags = |name: &str, table: &[&str]| {
+            PublishedFlags::new(publisher, &base.append(name), table)
+        };
+        Ok(PublishedFaults {
+            array_faults: flags("array_faults", faults::ARRAY_FAULTS)?,
+            load_faults: flags("load_faults", faults::LOAD_FAULTS)?,
+            alarms: flags("alarms", faults::ALARMS)?,
+            array_faults_daily: flags("array_faults_daily", faults::ARRAY_FAULTS)?,
+            load_faults_daily: flags("load_faults_daily", faults::LOAD_FAULTS)?,
+            alarms_daily: flags("alarms_daily", faults::ALARMS)?,
+        })
+    }
+
+    fn update(&self, batch: &mut UpdateBatch, st: &Stats) {
+        self.array_faults.update(batch, st.array_faults.bits() as u32);
+        self.load_faults.update(batch, st.load_faults.bits() as u32);
+        self.alarms.update(batch, st.alarms.bits());
+        self.array_faults_daily.update(batch, st.array_faults_daily.bits() as u32);
+        self.load_faults_daily.update(batch, st.load_faults_daily.bits() as u32);
+        self.alarms_daily.update(batch, st.alarms_daily.bits());
+    }
+}
+
 struct PublishedStats {
     timestamp: Val,
     software_version: Val,
@@ -82,6 +148,7 @@ struct PublishedStats {
     array_voltage_max_daily: Val,
     array_voltage_fixed: Val,
     array_voc_percent_fixed: Val,
+    faults: PublishedFaults,
 }
 
 impl PublishedStats {
@@ -176,6 +243,7 @@ impl PublishedStats {
                 .publish(base.append("array_voltage_fixed"), Value::Null)?,
             array_voc_percent_fixed: publisher
                 .publish(base.append("array_voc_percent_fixed"), Value::Null)?,
+            faults: PublishedFaults::new(publisher, &base.append("faults"))?,
         })
     }
 
@@ -303,6 +371,7 @@ impl PublishedStats {
             .update_changed(batch, Value::F32(st.array_voltage_fixed.get::<volt>()));
         self.array_voc_percent_fixed
             .update_changed(batch, Value::F32(st.array_voc_percent_fixed));
+        self.faults.update(batch, st);
     }
 }
 
@@ -339,6 +408,22 @@ macro_rules! bool {
     };
 }
 
+macro_rules! string {
+    ($r:expr) => {
+        match $r.value {
+            Value::String(s) => String::from(&*s),
+            v => {
+                let m = format!("{:?} not accepted, expected string", v);
+                warn!("{}", &m);
+                if let Some(reply) = $r.send_result {
+                    reply.send(Value::Error(Chars::from(m)));
+                }
+                return None;
+            }
+        }
+    };
+}
+
 struct PublishedSettings {
     regulation_voltage: Val,
     float_voltage: Val,
@@ -632,7 +717,14 @@ impl PublishedSettings {
         publisher.writes(self.charge_current_limit.id(), channel);
     }
 
-    fn process_writes(&self, mut batch: Pooled<Vec<WriteRequest>>, p: &mut Settings) {
+    // returns the reply channels of the accepted writes, they are
+    // answered once the controller has the new settings
+    fn process_writes(
+        &self,
+        mut batch: Pooled<Vec<WriteRequest>>,
+        p: &mut Settings,
+    ) -> Vec<SendResult> {
+        let mut replies = Vec::new();
         for r in batch.drain(..) {
             if r.id == self.regulation_voltage.id() {
                 p.regulation_voltage = ElectricPotential::new::<volt>(f32!(r));
@@ -754,8 +846,11 @@ impl PublishedSettings {
                 if let Some(reply) = r.send_result {
                     reply.send(Value::Error(Chars::from(m)))
                 }
+                continue;
             }
+            replies.extend(r.send_result);
         }
+        replies
     }
 }
 
@@ -763,6 +858,7 @@ struct PublishedControl {
     charging: Val,
     load: Val,
     reset: Val,
+    profile: Val,
 }
 
 impl PublishedControl {
@@ -771,6 +867,7 @@ impl PublishedControl {
             charging: publisher.publish(base.append("charging"), Value::Null)?,
             load: publisher.publish(base.append("load"), Value::Null)?,
             reset: publisher.publish(base.append("reset"), Value::Null)?,
+            profile: publisher.publish(base.append("profile"), Value::Null)?,
         })
     }
 
@@ -810,22 +907,37 @@ impl PublishedControl {
         &self,
         publisher: &Publisher,
         channel: fmpsc::Sender<Pooled<Vec<WriteRequest>>>,
+        caps: &Capabilities,
     ) {
-        publisher.writes(self.charging.id(), channel.clone());
-        publisher.writes(self.load.id(), channel.clone());
-        publisher.writes(self.reset.id(), channel);
+        if caps.charging_control {
+            publisher.writes(self.charging.id(), channel.clone());
+        }
+        if caps.load_control {
+            publisher.writes(self.load.id(), channel.clone());
+        }
+        if caps.reset {
+            publisher.writes(self.reset.id(), channel.clone());
+        }
+        if caps.settings {
+            publisher.writes(self.profile.id(), channel);
+        }
     }
 
-    fn process_writes(&self, mut batch: Pooled<Vec<WriteRequest>>) -> Vec<FromClient> {
+    fn process_writes(
+        &self,
+        mut batch: Pooled<Vec<WriteRequest>>,
+    ) -> Vec<(FromClient, Option<SendResult>)> {
         batch
             .drain(..)
             .filter_map(|r| {
                 if r.id == self.charging.id() {
-                    Some(FromClient::SetCharging(bool!(r)))
+                    Some((FromClient::SetCharging(bool!(r)), r.send_result))
                 } else if r.id == self.load.id() {
-                    Some(FromClient::SetLoad(bool!(r)))
+                    Some((FromClient::SetLoad(bool!(r)), r.send_result))
                 } else if r.id == self.reset.id() {
-                    Some(FromClient::ResetController)
+                    Some((FromClient::ResetController, r.send_result))
+                } else if r.id == self.profile.id() {
+                    Some((FromClient::ApplyProfile(string!(r)), r.send_result))
                 } else {
                     let m = format!("control id {:?} not recognized", r.id);
                     warn!("{}", &m);
@@ -839,12 +951,118 @@ impl PublishedControl {
     }
 }
 
-struct NetidxInner {
-    publisher: Publisher,
+struct PublishedSchedule {
+    scheduled: Val,
+    next: Val,
+    next_state: Val,
+    overridden: Val,
+}
+
+impl PublishedSchedule {
+    fn new(publisher: &Publisher, base: &Path) -> Result<Self> {
+        Ok(PublishedSchedule {
+            scheduled: publisher.publish(base.append("scheduled"), Value::Null)?,
+            next: publisher.publish(base.append("next"), Value::Null)?,
+            next_state: publisher.publish(base.append("next_state"), Value::Null)?,
+            overridden: publisher.publish(base.append("override"), Value::Null)?,
+        })
+    }
+
+    fn update(&self, batch: &mut UpdateBatch, st: &ScheduleStatus) {
+        use chrono::prelude::*;
+        let onoff = |b: bool| if b { Value::True } else { Value::False };
+        self.scheduled.update_changed(batch, onoff(st.scheduled));
+        let (next, next_state) = match st.next {
+            None => (Value::Null, Value::Null),
+            Some((t, b)) => (Value::DateTime(DateTime::<Utc>::from(t)), onoff(b)),
+        };
+        self.next.update_changed(batch, next);
+        self.next_state.update_changed(batch, next_state);
+        let overridden = st.overridden.map(onoff).unwrap_or(Value::Null);
+        self.overridden.update_changed(batch, overridden);
+    }
+}
+
+struct PublishedRule {
+    state: Val,
+    active: Val,
+    value: Val,
+    since: Val,
+}
+
+impl PublishedRule {
+    fn new(publisher: &Publisher, base: &Path) -> Result<Self> {
+        Ok(PublishedRule {
+            state: publisher.publish(base.append("state"), Value::Null)?,
+            active: publisher.publish(base.append("active"), Value::Null)?,
+            value: publisher.publish(base.append("value"), Value::Null)?,
+            since: publisher.publish(base.append("since"), Value::Null)?,
+        })
+    }
+
+    fn update(&self, batch: &mut UpdateBatch, st: &RuleStatus) {
+        use chrono::prelude::*;
+        let state = Value::String(Chars::from(st.state.to_string()));
+        self.state.update_changed(batch, state);
+        let active = if st.state.is_active() { Value::True } else { Value::False };
+        self.active.update_changed(batch, active);
+        self.value.update_changed(batch, st.value.map(Value::F32).unwrap_or(Value::Null));
+        self.since
+            .update_changed(batch, Value::DateTime(DateTime::<Utc>::from(st.since)));
+    }
+}
+
+// the stats, settings, and control trees are specific to the
+// controller model, currently only the prostar mppt is supported
+struct PublishedController {
+    info: ControllerInfo,
+    _model: Val,
     stats: PublishedStats,
     settings: PublishedSettings,
     control: PublishedControl,
-    current: Option<Settings>,
+    // published when the scheduler first reports on a switch
+    schedule: Vec<(Switch, PublishedSchedule)>,
+    // published when the rule first sees stats
+    rules: Vec<(String, PublishedRule)>,
+    base: Path,
+    current: Option<ControllerSettings>,
+}
+
+impl PublishedController {
+    fn new(publisher: &Publisher, base: Path, info: &ControllerInfo) -> Result<Self> {
+        let model = Value::String(Chars::from(format!("{:?}", info.model)));
+        let _model = publisher.publish(base.append("model"), model)?;
+        let stats = PublishedStats::new(publisher, &base.append("stats"))?;
+        let settings = PublishedSettings::new(publisher, &base.append("settings"))?;
+        let control = PublishedControl::new(publisher, &base.append("control"))?;
+        info!("published stats, settings, control for {}", info.name);
+        Ok(PublishedController {
+            info: info.clone(),
+            _model,
+            stats,
+            settings,
+            control,
+            schedule: Vec::new(),
+            rules: Vec::new(),
+            base,
+            current: None,
+        })
+    }
+}
+
+// tell the writer how their write turned out
+fn reply_write(result: Option<SendResult>, r: ToClient) {
+    if let Some(result) = result {
+        match r {
+            ToClient::Err(e) => result.send(Value::Error(Chars::from(e))),
+            _ => result.send(Value::Ok),
+        }
+    }
+}
+
+struct NetidxInner {
+    publisher: Publisher,
+    controllers: Vec<PublishedController>,
     to_main: Sender<ToMainLoop>,
 }
 
@@ -852,14 +1070,19 @@ struct NetidxInner {
 pub(crate) struct Netidx(Arc<Mutex<NetidxInner>>);
 
 impl Netidx {
-    async fn handle_writes(self) {
+    async fn handle_writes(self, i: usize) {
         let (settings_tx, settings_rx) = fmpsc::channel(10);
         let (control_tx, control_rx) = fmpsc::channel(10);
-        {
+        let name = {
             let inner = self.0.lock();
-            inner.settings.register_writable(&inner.publisher, settings_tx);
-            inner.control.register_writable(&inner.publisher, control_tx);
-        }
+            let ctl = &inner.controllers[i];
+            if ctl.info.capabilities.settings {
+                ctl.settings.register_writable(&inner.publisher, settings_tx);
+            }
+            let caps = &ctl.info.capabilities;
+            ctl.control.register_writable(&inner.publisher, control_tx, caps);
+            ctl.info.name.clone()
+        };
         let mut settings_rx = settings_rx.fuse();
         let mut control_rx = control_rx.fuse();
         'main: loop {
@@ -869,16 +1092,19 @@ impl Netidx {
                     Some(batch) => {
                         let (to_main, commands) = {
                             let inner = self.0.lock();
-                            (inner.to_main.clone(), inner.control.process_writes(batch))
+                            let commands = inner.controllers[i].control.process_writes(batch);
+                            (inner.to_main.clone(), commands)
                         };
-                        for cmd in commands {
+                        for (cmd, result) in commands {
                             let (reply_tx, mut reply_rx) = mpsc::channel(1);
+                            let cmd = cmd.source(Source::Netidx);
+                            let cmd = cmd.target(Some(name.as_str()));
                             let m = ToMainLoop::FromClient(cmd, reply_tx);
                             match to_main.send(m).await {
                                 Err(_) => break 'main,
                                 Ok(()) => match reply_rx.recv().await {
                                     None => break 'main,
-                                    Some(_) => ()
+                                    Some(r) => reply_write(result, r),
                                 }
                             }
                         }
@@ -887,37 +1113,43 @@ impl Netidx {
                 m = settings_rx.next() => match m {
                     None => break,
                     Some(batch) => {
-                        let (to_main, s) = {
+                        let (to_main, s, results) = {
                             let inner = self.0.lock();
-                            let mut s = match inner.current.as_ref() {
-                                Some(settings) => *settings,
+                            let ctl = &inner.controllers[i];
+                            match ctl.current {
+                                Some(ControllerSettings::ProstarMppt(mut s)) => {
+                                    let res = ctl.settings.process_writes(batch, &mut s);
+                                    let s = ControllerSettings::ProstarMppt(s);
+                                    (inner.to_main.clone(), s, res)
+                                }
                                 None => {
                                     warn!("settings are not initialized");
                                     continue;
                                 }
-                            };
-                            inner.settings.process_writes(batch, &mut s);
-                            (inner.to_main.clone(), s)
+                            }
                         };
                         let (reply_tx, mut reply_rx) = mpsc::channel(1);
-                        let msg =
-                            ToMainLoop::FromClient(FromClient::WriteSettings(s), reply_tx);
+                        let cmd = FromClient::WriteSettings(s)
+                            .source(Source::Netidx)
+                            .target(Some(name.as_str()));
+                        let msg = ToMainLoop::FromClient(cmd, reply_tx);
                         match to_main.send(msg).await {
                             Err(_) => break,
                             Ok(()) => (),
                         }
-                        match reply_rx.recv().await {
+                        let r = match reply_rx.recv().await {
                             None => break,
-                            Some(ToClient::Err(e)) =>
-                                warn!("failed to update settings {}", e),
-                            Some(ToClient::Ok) => {
-                                let mut inner = self.0.lock();
-                                inner.current = Some(s);
-                                info!("settings updated successfully");
-                            }
-                            Some(_) => {
-                                warn!("unexpected response from main loop");
-                            }
+                            Some(r) => r,
+                        };
+                        match &r {
+                            ToClient::Err(e) => warn!("failed to update settings {}", e),
+                            // the daemon publishes the settings it reads
+                            // back from the controller
+                            ToClient::Ok => info!("settings updated successfully"),
+                            _ => warn!("unexpected response from main loop"),
+                        }
+                        for result in results {
+                            reply_write(Some(result), r.clone())
                         }
                     }
                 }
@@ -925,7 +1157,11 @@ impl Netidx {
         }
     }
 
-    pub(crate) async fn new(cfg: &Config, to_main: Sender<ToMainLoop>) -> Result<Self> {
+    pub(crate) async fn new(
+        cfg: &Config,
+        controllers: &[ControllerInfo],
+        to_main: Sender<ToMainLoop>,
+    ) -> Result<Self> {
         let resolver = task::block_in_place(|| netidx::config::Config::load_default())?;
         let bindcfg = cfg.netidx_bind.parse::<BindCfg>()?;
         let base = Path::from(cfg.netidx_base.clone());
@@ -937,19 +1173,22 @@ impl Netidx {
         info!("create publisher");
         let publisher = Publisher::new(resolver, auth, bindcfg).await?;
         info!("created publisher");
-        let stats = PublishedStats::new(&publisher, &base.append("stats"))?;
-        let settings = PublishedSettings::new(&publisher, &base.append("settings"))?;
-        let control = PublishedControl::new(&publisher, &base.append("control"))?;
-        info!("published stats, settings, control");
-        let t = Netidx(Arc::new(Mutex::new(NetidxInner {
-            publisher,
-            stats,
-            settings,
-            control,
-            current: None,
-            to_main,
-        })));
-        task::spawn(t.clone().handle_writes());
+        // a single controller configured with the top level device
+        // keeps the paths it had before there could be several
+        let legacy = cfg.controllers.is_empty() && controllers.len() == 1;
+        let controllers = controllers
+            .iter()
+            .map(|c| {
+                let base = if legacy { base.clone() } else { base.append(&c.name) };
+                PublishedController::new(&publisher, base, c)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let n = controllers.len();
+        let t =
+            Netidx(Arc::new(Mutex::new(NetidxInner { publisher, controllers, to_main })));
+        for i in 0..n {
+            task::spawn(t.clone().handle_writes(i));
+        }
         Ok(t)
     }
 
@@ -957,22 +1196,80 @@ impl Netidx {
         self.0.lock().publisher.start_batch()
     }
 
-    pub(crate) fn update_stats(&self, batch: &mut UpdateBatch, st: &Stats) {
+    pub(crate) fn update_stats(
+        &self,
+        batch: &mut UpdateBatch,
+        i: usize,
+        st: &ControllerStats,
+    ) {
         let inner = self.0.lock();
         info!("stats updated");
-        inner.stats.update(batch, st);
+        let ctl = &inner.controllers[i];
+        match st {
+            ControllerStats::ProstarMppt(st) => {
+                ctl.stats.update(batch, st);
+                ctl.control.update(batch, st);
+            }
+        }
     }
 
-    pub(crate) fn update_settings(&self, batch: &mut UpdateBatch, set: &Settings) {
+    pub(crate) fn update_settings(
+        &self,
+        batch: &mut UpdateBatch,
+        i: usize,
+        set: &ControllerSettings,
+    ) {
         let mut inner = self.0.lock();
         info!("settings updated");
-        inner.current = Some(*set);
-        inner.settings.update(batch, set);
+        let ctl = &mut inner.controllers[i];
+        ctl.current = Some(*set);
+        match set {
+            ControllerSettings::ProstarMppt(set) => ctl.settings.update(batch, set),
+        }
     }
 
-    pub(crate) fn update_control(&self, batch: &mut UpdateBatch, st: &Stats) {
-        let inner = self.0.lock();
-        info!("control stats updated");
-        inner.control.update(batch, st);
+    pub(crate) fn update_schedule(
+        &self,
+        batch: &mut UpdateBatch,
+        i: usize,
+        st: &ScheduleStatus,
+    ) -> Result<()> {
+        let mut inner = self.0.lock();
+        let inner = &mut *inner;
+        let ctl = &mut inner.controllers[i];
+        let pos = ctl.schedule.iter().position(|(sw, _)| *sw == st.switch);
+        let pos = match pos {
+            Some(pos) => pos,
+            None => {
+                let base = ctl.base.append("schedule").append(&st.switch.to_string());
+                let published = PublishedSchedule::new(&inner.publisher, &base)?;
+                ctl.schedule.push((st.switch, published));
+                ctl.schedule.len() - 1
+            }
+        };
+        ctl.schedule[pos].1.update(batch, st);
+        Ok(())
+    }
+
+    pub(crate) fn update_rule(
+        &self,
+        batch: &mut UpdateBatch,
+        i: usize,
+        st: &RuleStatus,
+    ) -> Result<()> {
+        let mut inner = self.0.lock();
+        let inner = &mut *inner;
+        let ctl = &mut inner.controllers[i];
+        let pos = match ctl.rules.iter().position(|(name, _)| name == &st.name) {
+            Some(pos) => pos,
+            None => {
+                let base = ctl.base.append("rules").append(&st.name);
+                let published = PublishedRule::new(&inner.publisher, &base)?;
+                ctl.rules.push((st.name.clone(), published));
+                ctl.rules.len() - 1
+            }
+        };
+        ctl.rules[pos].1.update(batch, st);
+        Ok(())
     }
 }
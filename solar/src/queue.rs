@@ -0,0 +1,439 @@
+use crate::{
+    controller::{Coil, Controller},
+    modbus_tcp::{Request, Response},
+    ToMainLoop,
+};
+use anyhow::Result;
+use chrono::prelude::*;
+use futures::{pin_mut, prelude::*, select_biased};
+use solar_client::{
+    journal, validate, BatteryConfig, ControllerConfig, ControllerInfo,
+    ControllerSettings, ControllerStats, Source,
+};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    task, time,
+};
+
+// how long a command has from being queued to finishing. The deadline
+// is checked before every operation on the controller, and an
+// operation still running when it passes is cancelled.
+static DEADLINE: Duration = Duration::from_secs(30);
+// how long a caller will wait for a command to complete, a little past
+// the deadline so the command's own error arrives first
+static REPLYTO: Duration = Duration::from_secs(35);
+static QUEUE_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub(crate) enum Command {
+    SetCharging(bool),
+    SetLoad(bool),
+    Reset,
+    ReadSettings,
+    WriteSettings(ControllerSettings, Source),
+    Raw(Request),
+    /// commands paired with the command that undoes them
+    Transaction(Vec<(Command, Option<Command>)>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Reply {
+    Ok,
+    Settings(ControllerSettings),
+    Raw(Response),
+}
+
+/// Things the controller task reports back to the main loop
+#[derive(Debug, Clone)]
+pub(crate) enum Event {
+    Stats(Option<ControllerStats>),
+    Settings(ControllerSettings),
+    Applied(journal::Entry),
+}
+
+struct Job {
+    command: Command,
+    deadline: Instant,
+    reply: oneshot::Sender<Result<Reply>>,
+}
+
+/// Error counts for one controller, shared by it's handle and task
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    /// stats reads that failed
+    pub(crate) stats_errors: AtomicU64,
+    /// commands that failed on the controller
+    pub(crate) command_errors: AtomicU64,
+    /// stats reads cancelled by a command
+    pub(crate) preempted: AtomicU64,
+    /// commands rejected because the queue was full
+    pub(crate) busy: AtomicU64,
+    /// commands that took too long
+    pub(crate) timeouts: AtomicU64,
+}
+
+fn incr(c: &AtomicU64) {
+    c.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The handle to a controller task. Commands are queued ahead of
+/// periodic stats reads, and a command arriving while stats are being
+/// read cancels the read. Raw requests from the modbus gateway are
+/// queued behind stats reads and never cancel them, so a client
+/// polling the gateway can't starve the stats.
+#[derive(Clone)]
+pub(crate) struct Handle {
+    pub(crate) info: ControllerInfo,
+    pub(crate) address: u8,
+    pub(crate) counters: Arc<Counters>,
+    commands: Sender<Job>,
+    raw: Sender<Job>,
+    poll: Sender<()>,
+}
+
+impl Handle {
+    pub(crate) fn start(
+        i: usize,
+        cfg: &ControllerConfig,
+        mb: Box<dyn Controller>,
+        to_main: Sender<ToMainLoop>,
+    ) -> Self {
+        let info = ControllerInfo {
+            name: cfg.name.clone(),
+            model: mb.model(),
+            capabilities: mb.capabilities(),
+        };
+        let (commands, commands_rx) = channel(QUEUE_DEPTH);
+        let (raw, raw_rx) = channel(QUEUE_DEPTH);
+        let (poll, poll_rx) = channel(1);
+        let counters = Arc::new(Counters::default());
+        let t = Task {
+            i,
+            info: info.clone(),
+            battery: cfg.battery.clone(),
+            mb,
+            to_main,
+            initsettings: false,
+            counters: counters.clone(),
+        };
+        task::spawn(t.run(commands_rx, raw_rx, poll_rx));
+        Handle { info, address: cfg.modbus_id, counters, commands, raw, poll }
+    }
+
+    /// ask the controller task to read stats, returns false if the
+    /// previous read hasn't finished yet
+    pub(crate) fn poll(&self) -> bool {
+        self.poll.try_send(()).is_ok()
+    }
+
+    pub(crate) async fn command(&self, command: Command) -> Result<Reply> {
+        let (tx, rx) = oneshot::channel();
+        let queue = match command {
+            Command::Raw(_) => &self.raw,
+            _ => &self.commands,
+        };
+        let job = Job { command, deadline: Instant::now() + DEADLINE, reply: tx };
+        if queue.try_send(job).is_err() {
+            incr(&self.counters.busy);
+            bail!("controller {} is busy", self.info.name)
+        }
+        match time::timeout(REPLYTO, rx).await {
+            Err(_) => {
+                incr(&self.counters.timeouts);
+                bail!("timed out waiting for controller {}", self.info.name)
+            }
+            Ok(Err(_)) => bail!("controller {} task has stopped", self.info.name),
+            Ok(Ok(r)) => r,
+        }
+    }
+}
+
+// Run one operation on the controller if the deadline hasn't passed,
+// cancelling it if the deadline passes while it runs
+macro_rules! op {
+    ($t:ident, $deadline:expr, $op:ident($($arg:expr),*)) => {{
+        match $t.within($deadline) {
+            Err(e) => Err(e),
+            Ok(()) => {
+                let op = $t.mb.$op($($arg),*);
+                match time::timeout_at($deadline.into(), op).await {
+                    Ok(r) => r,
+                    Err(_) => {
+                        $t.mb.reset();
+                        $t.out_of_time()
+                    }
+                }
+            }
+        }
+    }};
+}
+
+struct Task {
+    i: usize,
+    info: ControllerInfo,
+    battery: Option<BatteryConfig>,
+    mb: Box<dyn Controller>,
+    to_main: Sender<ToMainLoop>,
+    initsettings: bool,
+    counters: Arc<Counters>,
+}
+
+impl Task {
+    fn check(&self, supported: bool, what: &str) -> Result<()> {
+        if supported {
+            Ok(())
+        } else {
+            bail!("{} ({:?}) does not support {}", self.info.name, self.info.model, what)
+        }
+    }
+
+    fn out_of_time<T>(&self) -> Result<T> {
+        incr(&self.counters.timeouts);
+        bail!("command on {} ran out of time", self.info.name)
+    }
+
+    fn within(&self, deadline: Instant) -> Result<()> {
+        if Instant::now() < deadline {
+            Ok(())
+        } else {
+            self.out_of_time()
+        }
+    }
+
+    async fn event(&self, e: Event) {
+        let _ = self.to_main.send(ToMainLoop::Controller(self.i, e)).await;
+    }
+
+    async fn eval(&mut self, command: Command, deadline: Instant) -> Result<Reply> {
+        let caps = self.info.capabilities;
+        match command {
+            Command::SetCharging(b) => {
+                self.check(caps.charging_control, "charging control")?;
+                op!(self, deadline, write_coil(Coil::ChargeDisconnect, !b))?;
+                Ok(Reply::Ok)
+            }
+            Command::SetLoad(b) => {
+                self.check(caps.load_control, "load control")?;
+                op!(self, deadline, write_coil(Coil::LoadDisconnect, !b))?;
+                Ok(Reply::Ok)
+            }
+            Command::Reset => {
+                self.check(caps.reset, "reset")?;
+                op!(self, deadline, write_coil(Coil::ResetControl, true))?;
+                Ok(Reply::Ok)
+            }
+            Command::ReadSettings => {
+                self.check(caps.settings, "settings")?;
+                let s = op!(self, deadline, read_settings())?;
+                self.event(Event::Settings(s)).await;
+                Ok(Reply::Settings(s))
+            }
+            Command::WriteSettings(s, source) => {
+                self.check(caps.settings, "settings")?;
+                if s.model() != self.info.model {
+                    bail!(
+                        "{:?} settings can't be written to {}",
+                        s.model(),
+                        self.info.name
+                    )
+                }
+                validate::validate(&s, self.battery.as_ref())?;
+                self.write_settings(s, source, deadline).await
+            }
+            Command::Raw(req) => {
+                self.check(caps.register_access, "register access")?;
+                Ok(Reply::Raw(op!(self, deadline, raw(&req))?))
+            }
+            Command::Transaction(_) => bail!("transactions can't be nested"),
+        }
+    }
+
+    // The settings are read back after every write, the journal
+    // records what the controller actually holds, or the settings sent
+    // marked unverified if they can't be read back.
+    async fn write_settings(
+        &mut self,
+        s: ControllerSettings,
+        source: Source,
+        deadline: Instant,
+    ) -> Result<Reply> {
+        let old = match op!(self, deadline, read_settings()) {
+            Ok(old) => Some(old),
+            Err(e) => {
+                warn!(
+                    "reading settings from {} before write failed {}",
+                    self.info.name, e
+                );
+                None
+            }
+        };
+        op!(self, deadline, write_settings(&s))?;
+        let (new, verified, r) = match op!(self, deadline, read_settings()) {
+            Err(e) => {
+                let e = anyhow!("settings written, but reading them back failed {}", e);
+                (s, false, Err(e))
+            }
+            Ok(new) => match journal::mismatches(&s, &new)? {
+                d if d.is_empty() => (new, true, Ok(Reply::Ok)),
+                d => {
+                    let d = d
+                        .into_iter()
+                        .map(|(f, w, r)| format!("{} wrote {} read {}", f, w, r))
+                        .collect::<Vec<_>>();
+                    let e = anyhow!(
+                        "settings written, but the controller holds {}",
+                        d.join(", ")
+                    );
+                    (new, true, Err(e))
+                }
+            },
+        };
+        let controller = self.info.name.clone();
+        let timestamp = Local::now();
+        let entry = journal::Entry { timestamp, controller, source, old, new, verified };
+        // only publish settings the controller is known to hold
+        if verified {
+            self.event(Event::Settings(new)).await;
+        }
+        self.event(Event::Applied(entry)).await;
+        r
+    }
+
+    // A transaction runs as one job, so nothing else can reach the
+    // controller between it's steps. The rollback gets a deadline of
+    // it's own, it must run even when the steps ran out of time.
+    async fn transact(
+        &mut self,
+        steps: Vec<(Command, Option<Command>)>,
+        deadline: Instant,
+    ) -> Result<Reply> {
+        let mut undo = Vec::new();
+        for (i, (cmd, u)) in steps.into_iter().enumerate() {
+            if let Err(e) = self.eval(cmd, deadline).await {
+                warn!("{} transaction step {} failed, rolling back", self.info.name, i);
+                let mut failed = Vec::new();
+                let deadline = Instant::now() + DEADLINE;
+                for u in undo.into_iter().rev() {
+                    if let Err(e) = self.eval(u, deadline).await {
+                        failed.push(e.to_string());
+                    }
+                }
+                if failed.is_empty() {
+                    bail!("step {} failed: {}, rolled back", i, e)
+                } else {
+                    bail!(
+                        "step {} failed: {}, rollback failed: {}",
+                        i,
+                        e,
+                        failed.join(", ")
+                    )
+                }
+            }
+            undo.extend(u);
+        }
+        Ok(Reply::Ok)
+    }
+
+    async fn run_job(&mut self, job: Job) {
+        let r = if Instant::now() > job.deadline {
+            incr(&self.counters.timeouts);
+            Err(anyhow!("command expired waiting for controller {}", self.info.name))
+        } else {
+            match job.command {
+                Command::Transaction(steps) => self.transact(steps, job.deadline).await,
+                cmd => self.eval(cmd, job.deadline).await,
+            }
+        };
+        if r.is_err() {
+            incr(&self.counters.command_errors)
+        }
+        let _ = job.reply.send(r);
+    }
+
+    async fn read_stats(
+        name: &str,
+        mb: &mut dyn Controller,
+        initsettings: bool,
+    ) -> (Option<ControllerSettings>, Option<ControllerStats>) {
+        let settings = if initsettings {
+            None
+        } else {
+            debug!("reading initial settings {}", name);
+            mb.read_settings().await.ok()
+        };
+        debug!("reading stats {}", name);
+        match mb.read_stats().await {
+            Ok(s) => (settings, Some(s)),
+            Err(e) => {
+                error!("reading stats from {} failed: {}", name, e);
+                (settings, None)
+            }
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut commands: Receiver<Job>,
+        mut raw: Receiver<Job>,
+        mut poll: Receiver<()>,
+    ) {
+        loop {
+            select_biased! {
+                job = commands.recv().fuse() => match job {
+                    None => break,
+                    Some(job) => self.run_job(job).await,
+                },
+                p = poll.recv().fuse() => match p {
+                    None => break,
+                    Some(()) => {
+                        let preempted = {
+                            let caps = self.info.capabilities;
+                            let init = self.initsettings || !caps.settings;
+                            let name = &self.info.name;
+                            let read = Task::read_stats(name, &mut *self.mb, init).fuse();
+                            pin_mut!(read);
+                            select_biased! {
+                                job = commands.recv().fuse() => Err(job),
+                                r = read => Ok(r),
+                            }
+                        };
+                        match preempted {
+                            Ok((settings, stats)) => {
+                                if stats.is_none() {
+                                    incr(&self.counters.stats_errors)
+                                }
+                                if let Some(s) = settings {
+                                    self.initsettings = true;
+                                    self.event(Event::Settings(s)).await;
+                                }
+                                self.event(Event::Stats(stats)).await;
+                            }
+                            Err(None) => break,
+                            Err(Some(job)) => {
+                                info!("stats read on {} preempted", self.info.name);
+                                incr(&self.counters.preempted);
+                                self.mb.reset();
+                                self.run_job(job).await
+                            }
+                        }
+                    }
+                },
+                job = raw.recv().fuse() => match job {
+                    None => break,
+                    Some(job) => self.run_job(job).await,
+                },
+            }
+        }
+        info!("controller task {} stopped", self.info.name);
+    }
+}
@@ -0,0 +1,247 @@
+use crate::{find_controller, queue::Handle};
+use anyhow::Result;
+use chrono::{prelude::*, Duration};
+use solar_client::{
+    rules::{self, Rule, RuleState, RuleStatus},
+    ControllerStats, FromClient,
+};
+
+struct Watched {
+    rule: Rule,
+    controller: usize,
+    name: String,
+    value: Option<f32>,
+    state: RuleState,
+    since: DateTime<Local>,
+    // the last action failed, run it again without waiting out the dwell
+    retry: bool,
+}
+
+impl Watched {
+    fn set(&mut self, state: RuleState, now: DateTime<Local>) {
+        if self.state != state {
+            info!("rules: {} on {} is now {}", self.rule.name, self.name, state);
+            self.state = state;
+            self.since = now;
+            self.retry = false;
+        }
+    }
+
+    fn elapsed(&self, now: DateTime<Local>, secs: u64) -> bool {
+        self.retry || now - self.since >= Duration::seconds(secs as i64)
+    }
+}
+
+/// Evaluates the configured rules against each controller's stats as
+/// they arrive.
+pub(crate) struct Rules(Vec<Watched>);
+
+impl Rules {
+    pub(crate) fn new(cfg: &[Rule], controllers: &[Handle]) -> Result<Self> {
+        rules::check(cfg)?;
+        let now = Local::now();
+        let mut watched = Vec::new();
+        for r in cfg {
+            let controller = find_controller(controllers, r.controller.as_deref())?;
+            watched.push(Watched {
+                rule: r.clone(),
+                controller,
+                name: controllers[controller].info.name.clone(),
+                value: None,
+                state: RuleState::Idle,
+                since: now,
+                retry: false,
+            })
+        }
+        Ok(Rules(watched))
+    }
+
+    /// Update the rules watching controller with it's latest stats, and
+    /// return the actions to run as (rule id, command). Nothing changes
+    /// while the controller can't be read.
+    pub(crate) fn eval(
+        &mut self,
+        controller: usize,
+        st: Option<&ControllerStats>,
+        now: DateTime<Local>,
+    ) -> Vec<(usize, FromClient)> {
+        let mut actions = Vec::new();
+        let st = match st {
+            None => return actions,
+            Some(st) => st,
+        };
+        for (id, w) in self.0.iter_mut().enumerate() {
+            if w.controller != controller {
+                continue;
+            }
+            w.value = rules::field(st, &w.rule.field);
+            let v = match w.value {
+                None => continue,
+                Some(v) => v,
+            };
+            let (trigger, release) = (w.rule.trigger.holds(v), w.rule.release().holds(v));
+            match w.state {
+                RuleState::Idle if trigger => w.set(RuleState::Triggering, now),
+                RuleState::Triggering if !trigger => w.set(RuleState::Idle, now),
+                RuleState::Active if release => w.set(RuleState::Releasing, now),
+                RuleState::Releasing if !release => w.set(RuleState::Active, now),
+                RuleState::Idle
+                | RuleState::Triggering
+                | RuleState::Active
+                | RuleState::Releasing => (),
+            }
+            // a dwell of 0 fires on the same stats that started it
+            match w.state {
+                RuleState::Triggering if w.elapsed(now, w.rule.dwell) => {
+                    w.set(RuleState::Active, now);
+                    actions.push((id, w.rule.action()))
+                }
+                RuleState::Releasing if w.elapsed(now, w.rule.release_dwell()) => {
+                    w.set(RuleState::Idle, now);
+                    if let Some(a) = w.rule.release_action() {
+                        actions.push((id, a))
+                    }
+                }
+                RuleState::Idle
+                | RuleState::Triggering
+                | RuleState::Active
+                | RuleState::Releasing => (),
+            }
+        }
+        actions
+    }
+
+    /// A rule's action failed, back the rule up so it's tried again
+    /// when the next stats arrive
+    pub(crate) fn failed(&mut self, id: usize) {
+        if let Some(w) = self.0.get_mut(id) {
+            let now = Local::now();
+            match w.state {
+                RuleState::Active => w.set(RuleState::Triggering, now),
+                RuleState::Idle => w.set(RuleState::Releasing, now),
+                RuleState::Triggering | RuleState::Releasing => return,
+            }
+            w.retry = true;
+        }
+    }
+
+    pub(crate) fn status(&self) -> Vec<(usize, RuleStatus)> {
+        self.0
+            .iter()
+            .map(|w| {
+                let status = RuleStatus {
+                    name: w.rule.name.clone(),
+                    controller: w.name.clone(),
+                    field: w.rule.field.clone(),
+                    value: w.value,
+                    state: w.state,
+                    since: w.since,
+                };
+                (w.controller, status)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use morningstar::prostar_mppt as ps;
+    use solar_client::rules::Condition;
+    use uom::si::{electric_potential::volt, f32::ElectricPotential};
+
+    fn rules(dwell: u64) -> Rules {
+        let rule = Rule {
+            name: String::from("lvd"),
+            controller: None,
+            field: String::from("battery_terminal_voltage"),
+            trigger: Condition::Below(11.9),
+            release: Some(Condition::Above(12.8)),
+            dwell,
+            release_dwell: None,
+            action: FromClient::SetLoad(false),
+            release_action: Some(FromClient::SetLoad(true)),
+        };
+        Rules(vec![Watched {
+            rule,
+            controller: 0,
+            name: String::from("east"),
+            value: None,
+            state: RuleState::Idle,
+            since: at(0),
+            retry: false,
+        }])
+    }
+
+    fn stats(v: f32) -> ControllerStats {
+        let mut st = ps::Stats::default();
+        st.battery_terminal_voltage = ElectricPotential::new::<volt>(v);
+        ControllerStats::ProstarMppt(st)
+    }
+
+    fn at(secs: i64) -> DateTime<Local> {
+        Local.timestamp(1_600_000_000 + secs, 0)
+    }
+
+    fn eval(r: &mut Rules, v: f32, secs: i64) -> usize {
+        r.eval(0, Some(&stats(v)), at(secs)).len()
+    }
+
+    fn state(r: &Rules) -> RuleState {
+        r.status()[0].1.state
+    }
+
+    #[test]
+    fn hysteresis() {
+        let mut r = rules(0);
+        assert_eq!(eval(&mut r, 12.5, 0), 0);
+        assert_eq!(eval(&mut r, 11.8, 1), 1);
+        assert_eq!(state(&r), RuleState::Active);
+        // between the thresholds nothing changes
+        assert_eq!(eval(&mut r, 12.5, 2), 0);
+        assert_eq!(eval(&mut r, 11.8, 3), 0);
+        assert_eq!(state(&r), RuleState::Active);
+        assert_eq!(eval(&mut r, 12.9, 4), 1);
+        assert_eq!(state(&r), RuleState::Idle);
+        assert_eq!(eval(&mut r, 12.5, 5), 0);
+        assert_eq!(state(&r), RuleState::Idle);
+    }
+
+    #[test]
+    fn dwell() {
+        let mut r = rules(60);
+        assert_eq!(eval(&mut r, 11.8, 0), 0);
+        assert_eq!(state(&r), RuleState::Triggering);
+        assert_eq!(eval(&mut r, 11.8, 59), 0);
+        assert_eq!(eval(&mut r, 11.8, 60), 1);
+        assert_eq!(state(&r), RuleState::Active);
+        // the release condition must hold for the whole release dwell
+        assert_eq!(eval(&mut r, 12.9, 100), 0);
+        assert_eq!(state(&r), RuleState::Releasing);
+        assert_eq!(eval(&mut r, 12.5, 130), 0);
+        assert_eq!(state(&r), RuleState::Active);
+        assert_eq!(eval(&mut r, 12.9, 140), 0);
+        assert_eq!(eval(&mut r, 12.9, 200), 1);
+        assert_eq!(state(&r), RuleState::Idle);
+    }
+
+    #[test]
+    fn failed_action_retries() {
+        let mut r = rules(60);
+        assert_eq!(eval(&mut r, 11.8, 0), 0);
+        assert_eq!(eval(&mut r, 11.8, 60), 1);
+        r.failed(0);
+        assert_eq!(state(&r), RuleState::Triggering);
+        // without waiting out the dwell again
+        assert_eq!(eval(&mut r, 11.8, 61), 1);
+        assert_eq!(state(&r), RuleState::Active);
+    }
+
+    #[test]
+    fn other_controllers_and_missing_stats() {
+        let mut r = rules(0);
+        assert!(r.eval(1, Some(&stats(11.8)), at(0)).is_empty());
+        assert!(r.eval(0, None, at(0)).is_empty());
+        assert_eq!(state(&r), RuleState::Idle);
+    }
+}
@@ -0,0 +1,299 @@
+use crate::{find_controller, queue::Handle};
+use anyhow::Result;
+use chrono::{prelude::*, Duration};
+use solar_client::{
+    schedule::{ScheduleConfig, ScheduleStatus, Switch, Window},
+    FromClient,
+};
+
+// how many days ahead to look for the next transition
+static HORIZON: i64 = 8;
+// how long a manual override lasts when the schedule has no next
+// transition
+static MAX_OVERRIDE: i64 = 24;
+
+// is any window open at t
+fn state(cfg: &ScheduleConfig, windows: &[Window], t: DateTime<Local>) -> bool {
+    let day = t.date().naive_local();
+    windows.iter().any(|w| {
+        (-1..=0).any(|d| match cfg.interval(w, day + Duration::days(d)) {
+            Some((start, end)) => start <= t && t < end,
+            None => false,
+        })
+    })
+}
+
+// the first time after now when the state changes, and the new state
+fn next(
+    cfg: &ScheduleConfig,
+    windows: &[Window],
+    now: DateTime<Local>,
+) -> Option<(DateTime<Local>, bool)> {
+    let cur = state(cfg, windows, now);
+    let day = now.date().naive_local();
+    let mut edges = Vec::new();
+    for w in windows {
+        for d in -1..HORIZON {
+            if let Some((start, end)) = cfg.interval(w, day + Duration::days(d)) {
+                edges.extend([start, end].iter().copied().filter(|t| *t > now));
+            }
+        }
+    }
+    edges.sort();
+    edges.dedup();
+    edges.into_iter().find_map(|t| {
+        let st = state(cfg, windows, t);
+        if st != cur {
+            Some((t, st))
+        } else {
+            None
+        }
+    })
+}
+
+struct Scheduled {
+    controller: usize,
+    name: String,
+    switch: Switch,
+    windows: Vec<Window>,
+    // the state last sent to the controller
+    applied: Option<bool>,
+    // a manual override and when it expires
+    overridden: Option<(bool, DateTime<Local>)>,
+}
+
+/// Drives the load and charging switches from the configured
+/// windows. A switch set by hand stays where it was put until the next
+/// scheduled transition, or for a day if the schedule never changes
+/// it.
+pub(crate) struct Scheduler {
+    cfg: ScheduleConfig,
+    switches: Vec<Scheduled>,
+}
+
+impl Scheduler {
+    pub(crate) fn new(cfg: &ScheduleConfig, controllers: &[Handle]) -> Result<Self> {
+        cfg.check()?;
+        let mut switches: Vec<Scheduled> = Vec::new();
+        for w in &cfg.windows {
+            let controller = find_controller(controllers, w.controller.as_deref())?;
+            let info = &controllers[controller].info;
+            let supported = match w.switch {
+                Switch::Load => info.capabilities.load_control,
+                Switch::Charging => info.capabilities.charging_control,
+            };
+            if !supported {
+                bail!("schedule: {} does not support {} control", info.name, w.switch)
+            }
+            match switches
+                .iter_mut()
+                .find(|s| s.controller == controller && s.switch == w.switch)
+            {
+                Some(s) => s.windows.push(w.clone()),
+                None => switches.push(Scheduled {
+                    controller,
+                    name: info.name.clone(),
+                    switch: w.switch,
+                    windows: vec![w.clone()],
+                    applied: None,
+                    overridden: None,
+                }),
+            }
+        }
+        Ok(Scheduler { cfg: cfg.clone(), switches })
+    }
+
+    /// The commands needed to bring the switches into line with the
+    /// schedule as (switch id, controller, command).
+    pub(crate) fn poll(
+        &mut self,
+        now: DateTime<Local>,
+    ) -> Vec<(usize, usize, FromClient)> {
+        let mut commands = Vec::new();
+        for (id, s) in self.switches.iter_mut().enumerate() {
+            match s.overridden {
+                None => (),
+                Some((_, until)) if now < until => continue,
+                Some((_, _)) => {
+                    info!("schedule: manual override of {} {} expired", s.name, s.switch);
+                    s.overridden = None;
+                    s.applied = None;
+                }
+            }
+            let want = state(&self.cfg, &s.windows, now);
+            if s.applied != Some(want) {
+                info!("schedule: setting {} {} to {}", s.name, s.switch, want);
+                s.applied = Some(want);
+                commands.push((id, s.controller, s.switch.command(want)))
+            }
+        }
+        commands
+    }
+
+    /// A scheduled command failed, try again on the next poll
+    pub(crate) fn failed(&mut self, id: usize) {
+        if let Some(s) = self.switches.get_mut(id) {
+            s.applied = None;
+        }
+    }
+
+    /// Record a command a client sent to a controller, the switch it
+    /// sets is overridden until it's next transition, or for a day if
+    /// it has none. Commands sent by the rules are not overrides.
+    pub(crate) fn manual(
+        &mut self,
+        controller: usize,
+        cmd: &FromClient,
+        now: DateTime<Local>,
+    ) {
+        let (switch, on) = match Switch::of(cmd) {
+            Some(s) => s,
+            None => return,
+        };
+        let cfg = &self.cfg;
+        for s in self.switches.iter_mut() {
+            if s.controller == controller && s.switch == switch {
+                let until = match next(cfg, &s.windows, now) {
+                    Some((t, _)) => t,
+                    None => now + Duration::hours(MAX_OVERRIDE),
+                };
+                s.overridden = Some((on, until));
+            }
+        }
+    }
+
+    pub(crate) fn status(&self, now: DateTime<Local>) -> Vec<(usize, ScheduleStatus)> {
+        self.switches
+            .iter()
+            .map(|s| {
+                let status = ScheduleStatus {
+                    controller: s.name.clone(),
+                    switch: s.switch,
+                    scheduled: state(&self.cfg, &s.windows, now),
+                    next: next(&self.cfg, &s.windows, now),
+                    overridden: s.overridden.map(|(on, _)| on),
+                };
+                (s.controller, status)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(start: &str, end: &str) -> Window {
+        Window {
+            controller: None,
+            switch: Switch::Load,
+            start: start.parse().unwrap(),
+            end: end.parse().unwrap(),
+            weekdays: vec![],
+        }
+    }
+
+    fn config(windows: &[Window]) -> ScheduleConfig {
+        ScheduleConfig { latitude: None, longitude: None, windows: windows.to_vec() }
+    }
+
+    fn scheduler(windows: &[Window]) -> Scheduler {
+        Scheduler {
+            cfg: config(windows),
+            switches: vec![Scheduled {
+                controller: 0,
+                name: String::from("east"),
+                switch: Switch::Load,
+                windows: windows.to_vec(),
+                applied: None,
+                overridden: None,
+            }],
+        }
+    }
+
+    fn at(day: u32, h: u32, m: u32) -> DateTime<Local> {
+        let t = NaiveDate::from_ymd(2021, 6, day).and_hms(h, m, 0);
+        Local.from_local_datetime(&t).unwrap()
+    }
+
+    #[test]
+    fn next_transition() {
+        let w = [window("08:00", "18:00")];
+        let cfg = config(&w);
+        assert!(state(&cfg, &w, at(15, 12, 0)));
+        assert!(!state(&cfg, &w, at(15, 18, 0)));
+        assert_eq!(next(&cfg, &w, at(15, 12, 0)), Some((at(15, 18, 0), false)));
+        assert_eq!(next(&cfg, &w, at(15, 20, 0)), Some((at(16, 8, 0), true)));
+    }
+
+    #[test]
+    fn overnight_window() {
+        let w = [window("22:00", "06:00")];
+        let cfg = config(&w);
+        assert!(state(&cfg, &w, at(15, 23, 0)));
+        assert!(state(&cfg, &w, at(16, 5, 59)));
+        assert_eq!(next(&cfg, &w, at(15, 23, 0)), Some((at(16, 6, 0), false)));
+    }
+
+    #[test]
+    fn always_open_has_no_transition() {
+        let w = [window("00:00", "00:00")];
+        let cfg = config(&w);
+        assert!(state(&cfg, &w, at(15, 12, 0)));
+        assert_eq!(next(&cfg, &w, at(15, 12, 0)), None);
+    }
+
+    #[test]
+    fn override_lasts_until_next_transition() {
+        let mut s = scheduler(&[window("08:00", "18:00")]);
+        assert_eq!(s.poll(at(15, 12, 0)).len(), 1);
+        s.manual(0, &FromClient::set_load(false), at(15, 12, 0));
+        assert_eq!(s.status(at(15, 12, 0))[0].1.overridden, Some(false));
+        assert!(s.poll(at(15, 17, 59)).is_empty());
+        // the override expires at 18:00, when the schedule wants off
+        assert_eq!(s.poll(at(15, 18, 0)).len(), 1);
+        assert_eq!(s.status(at(15, 18, 0))[0].1.overridden, None);
+    }
+
+    #[test]
+    fn override_without_transition_expires() {
+        let mut s = scheduler(&[window("00:00", "00:00")]);
+        assert_eq!(s.poll(at(15, 12, 0)).len(), 1);
+        s.manual(0, &FromClient::SetLoad(false), at(15, 12, 0));
+        assert!(s.poll(at(16, 11, 59)).is_empty());
+        assert_eq!(s.poll(at(16, 12, 0)).len(), 1);
+    }
+
+    #[test]
+    fn other_commands_are_not_overrides() {
+        let mut s = scheduler(&[window("08:00", "18:00")]);
+        s.manual(0, &FromClient::SetCharging(false), at(15, 12, 0));
+        s.manual(1, &FromClient::SetLoad(false), at(15, 12, 0));
+        assert_eq!(s.status(at(15, 12, 0))[0].1.overridden, None);
+    }
+
+    #[test]
+    fn solar_load_only_overrides_the_load() {
+        let w = [window("08:00", "18:00")];
+        let mut s = scheduler(&w);
+        s.switches.push(Scheduled {
+            controller: 0,
+            name: String::from("east"),
+            switch: Switch::Charging,
+            windows: w.to_vec(),
+            applied: None,
+            overridden: None,
+        });
+        assert_eq!(s.poll(at(15, 12, 0)).len(), 2);
+        s.manual(0, &FromClient::set_load(false), at(15, 12, 0));
+        let overridden = s.status(at(15, 12, 0)).into_iter().map(|(_, st)| st.overridden);
+        assert_eq!(overridden.collect::<Vec<_>>(), vec![Some(false), None]);
+    }
+
+    #[test]
+    fn scheduled_load_leaves_charging_alone() {
+        let mut s = scheduler(&[window("08:00", "18:00")]);
+        let cmds = s.poll(at(15, 12, 0));
+        assert!(matches!(cmds[0].2, FromClient::SetLoad(true)));
+    }
+}
@@ -0,0 +1,359 @@
+use crate::{
+    controller::{Coil, Controller},
+    modbus::prostar_coil,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use morningstar::prostar_mppt as ps;
+use solar_client::{Capabilities, ControllerSettings, ControllerStats, Model};
+use std::f32::consts::PI;
+use uom::si::{
+    electric_charge::ampere_hour,
+    electric_current::ampere,
+    electric_potential::volt,
+    energy::kilowatt_hour,
+    f32::*,
+    power::watt,
+    thermodynamic_temperature::degree_celsius,
+    time::{day, hour, minute, second},
+};
+
+// a 12V battery bank with a 400W array and a small constant DC load
+static BATTERY_CAPACITY: f32 = 200.; // Ah
+static ARRAY_WATTS: f32 = 400.;
+static ARRAY_VMP: f32 = 34.;
+static ARRAY_VOC: f32 = 41.;
+static LOAD_AMPS: f32 = 3.;
+static SUNRISE: f32 = 6.;
+static SUNSET: f32 = 20.;
+
+fn default_settings() -> ps::Settings {
+    let mut s = ps::Settings::default();
+    s.regulation_voltage = ElectricPotential::new::<volt>(14.4);
+    s.float_voltage = ElectricPotential::new::<volt>(13.6);
+    s.time_before_float = Time::new::<second>(7200.);
+    s.time_before_float_low_battery = Time::new::<second>(10800.);
+    s.float_low_battery_voltage_trigger = ElectricPotential::new::<volt>(12.3);
+    s.float_cancel_voltage = ElectricPotential::new::<volt>(12.5);
+    s.exit_float_time = Time::new::<minute>(60.);
+    s.equalize_voltage = ElectricPotential::new::<volt>(15.1);
+    s.days_between_equalize_cycles = Time::new::<day>(28.);
+    s.equalize_time_limit_above_regulation_voltage = Time::new::<minute>(180.);
+    s.equalize_time_limit_at_regulation_voltage = Time::new::<minute>(120.);
+    s.reference_charge_voltage_limit = ElectricPotential::new::<volt>(15.5);
+    s.battery_charge_current_limit = ElectricCurrent::new::<ampere>(30.);
+    s.temperature_compensation_coefficent = ElectricPotential::new::<volt>(0.03);
+    s.high_voltage_disconnect = ElectricPotential::new::<volt>(15.5);
+    s.high_voltage_reconnect = ElectricPotential::new::<volt>(15.0);
+    s.maximum_charge_voltage_reference = ElectricPotential::new::<volt>(15.5);
+    s.max_battery_temp_compensation_limit =
+        ThermodynamicTemperature::new::<degree_celsius>(60.);
+    s.min_battery_temp_compensation_limit =
+        ThermodynamicTemperature::new::<degree_celsius>(-30.);
+    s.load_low_voltage_disconnect = ElectricPotential::new::<volt>(11.5);
+    s.load_low_voltage_reconnect = ElectricPotential::new::<volt>(12.6);
+    s.load_high_voltage_disconnect = ElectricPotential::new::<volt>(15.5);
+    s.load_high_voltage_reconnect = ElectricPotential::new::<volt>(14.5);
+    s.lvd_warning_timeout = Time::new::<second>(10.);
+    s.led_green_to_green_and_yellow_limit = ElectricPotential::new::<volt>(14.0);
+    s.led_green_and_yellow_to_yellow_limit = ElectricPotential::new::<volt>(13.35);
+    s.led_yellow_to_yellow_and_red_limit = ElectricPotential::new::<volt>(12.7);
+    s.led_yellow_and_red_to_red_flashing_limit = ElectricPotential::new::<volt>(12.0);
+    s.modbus_id = 1;
+    s.meterbus_id = 1;
+    s.mppt_fixed_vmp_percent = 0.8;
+    s.charge_current_limit = ElectricCurrent::new::<ampere>(30.);
+    s
+}
+
+/// A simulated ProStar MPPT. The array follows the sun on a fixed daily
+/// schedule in local time, the battery state of charge is integrated
+/// between reads, and the charge and load disconnect coils behave like
+/// they do on the real controller.
+pub struct Connection {
+    settings: ps::Settings,
+    soc: f32,
+    charging: bool,
+    load: bool,
+    lvd: bool,
+    last: Option<DateTime<Local>>,
+    ah_charge_resettable: f32,
+    ah_charge_total: f32,
+    kwh_charge_resettable: f32,
+    kwh_charge_total: f32,
+    ah_load_resettable: f32,
+    ah_load_total: f32,
+    ah_charge_daily: f32,
+    ah_load_daily: f32,
+    battery_v_min_daily: f32,
+    battery_v_max_daily: f32,
+    array_voltage_max_daily: f32,
+    hours: f32,
+}
+
+impl Connection {
+    pub async fn new() -> Self {
+        Connection {
+            settings: default_settings(),
+            soc: 0.7,
+            charging: true,
+            load: true,
+            lvd: false,
+            last: None,
+            ah_charge_resettable: 0.,
+            ah_charge_total: 0.,
+            kwh_charge_resettable: 0.,
+            kwh_charge_total: 0.,
+            ah_load_resettable: 0.,
+            ah_load_total: 0.,
+            ah_charge_daily: 0.,
+            ah_load_daily: 0.,
+            battery_v_min_daily: f32::MAX,
+            battery_v_max_daily: 0.,
+            array_voltage_max_daily: 0.,
+            hours: 0.,
+        }
+    }
+
+    fn reset_daily(&mut self) {
+        self.ah_charge_daily = 0.;
+        self.ah_load_daily = 0.;
+        self.battery_v_min_daily = f32::MAX;
+        self.battery_v_max_daily = 0.;
+        self.array_voltage_max_daily = 0.;
+    }
+
+    // fraction of full sun at the given local time
+    fn sun(now: &DateTime<Local>) -> f32 {
+        let h =
+            now.hour() as f32 + now.minute() as f32 / 60. + now.second() as f32 / 3600.;
+        if h <= SUNRISE || h >= SUNSET {
+            0.
+        } else {
+            (PI * (h - SUNRISE) / (SUNSET - SUNRISE)).sin()
+        }
+    }
+
+    // roughly lead acid, flat through the middle and falling off
+    // steeply when nearly empty, below the load disconnect under 15%
+    fn resting_voltage(&self) -> f32 {
+        if self.soc < 0.2 {
+            10.5 + self.soc * 6.5
+        } else {
+            11.8 + (self.soc - 0.2) * 1.25
+        }
+    }
+
+    fn charge_state(&self, sun: f32) -> ps::ChargeState {
+        if !self.charging {
+            ps::ChargeState::Disconnect
+        } else if sun <= 0. {
+            ps::ChargeState::Night
+        } else if self.soc < 0.9 {
+            ps::ChargeState::BulkMPPT
+        } else if self.soc < 0.99 {
+            ps::ChargeState::Absorption
+        } else {
+            ps::ChargeState::Float
+        }
+    }
+
+    pub async fn write_coil(&mut self, coil: ps::Coil, bit: bool) -> Result<()> {
+        match coil {
+            ps::Coil::ChargeDisconnect => self.charging = !bit,
+            ps::Coil::LoadDisconnect => self.load = !bit,
+            ps::Coil::ResetControl => {
+                if bit {
+                    self.reset_daily();
+                    self.lvd = false;
+                    self.last = None;
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    pub async fn read_stats(&mut self) -> Result<ps::Stats> {
+        let now = Local::now();
+        let dt = match self.last {
+            None => 0.,
+            Some(last) => {
+                if last.date() != now.date() {
+                    self.reset_daily();
+                }
+                ((now - last).num_milliseconds() as f32 / 3600000.).max(0.)
+            }
+        };
+        self.last = Some(now);
+        self.hours += dt;
+        let sun = Self::sun(&now);
+        let charge_state = self.charge_state(sun);
+        let target = match charge_state {
+            ps::ChargeState::Float => self.settings.float_voltage.get::<volt>(),
+            _ => self.settings.regulation_voltage.get::<volt>(),
+        };
+        let resting = self.resting_voltage();
+        let limit = self.settings.charge_current_limit.get::<ampere>();
+        let charge_current = match charge_state {
+            ps::ChargeState::Disconnect | ps::ChargeState::Night => 0.,
+            // taper as the battery approaches full
+            _ => (sun * ARRAY_WATTS / target)
+                .min(limit)
+                .min((1. - self.soc) * BATTERY_CAPACITY / 2. + 0.5),
+        };
+        let battery_voltage = if charge_current > 0. {
+            (resting + charge_current * 0.02).min(target)
+        } else {
+            resting
+        };
+        if battery_voltage <= self.settings.load_low_voltage_disconnect.get::<volt>() {
+            self.lvd = true;
+        } else if battery_voltage
+            >= self.settings.load_low_voltage_reconnect.get::<volt>()
+        {
+            self.lvd = false;
+        }
+        let load_on = self.load && !self.lvd;
+        let load_current = if load_on { LOAD_AMPS } else { 0. };
+        let net = charge_current - load_current;
+        self.soc = (self.soc + net * dt / BATTERY_CAPACITY).max(0.).min(1.);
+        let array_voltage = if sun <= 0. {
+            0.5
+        } else if charge_current > 0. {
+            ARRAY_VMP
+        } else {
+            ARRAY_VOC
+        };
+        let array_power = charge_current * battery_voltage;
+        let array_current =
+            if array_voltage > 1. { array_power / array_voltage } else { 0. };
+        let ah_in = charge_current * dt;
+        let ah_out = load_current * dt;
+        let kwh_in = array_power * dt / 1000.;
+        self.ah_charge_resettable += ah_in;
+        self.ah_charge_total += ah_in;
+        self.ah_charge_daily += ah_in;
+        self.kwh_charge_resettable += kwh_in;
+        self.kwh_charge_total += kwh_in;
+        self.ah_load_resettable += ah_out;
+        self.ah_load_total += ah_out;
+        self.ah_load_daily += ah_out;
+        self.battery_v_min_daily = self.battery_v_min_daily.min(battery_voltage);
+        self.battery_v_max_daily = self.battery_v_max_daily.max(battery_voltage);
+        self.array_voltage_max_daily = self.array_voltage_max_daily.max(array_voltage);
+        let ambient = 15. + 10. * sun;
+        let heatsink = ambient + array_power / 40.;
+        let mut st = ps::Stats::default();
+        st.timestamp = now;
+        st.software_version = 1;
+        st.battery_voltage_settings_multiplier = 1;
+        st.supply_3v3 = ElectricPotential::new::<volt>(3.3);
+        st.supply_12v = ElectricPotential::new::<volt>(12.0);
+        st.supply_5v = ElectricPotential::new::<volt>(5.0);
+        st.gate_drive_voltage = ElectricPotential::new::<volt>(12.0);
+        st.battery_terminal_voltage = ElectricPotential::new::<volt>(battery_voltage);
+        st.array_voltage = ElectricPotential::new::<volt>(array_voltage);
+        st.load_voltage =
+            ElectricPotential::new::<volt>(if load_on { battery_voltage } else { 0. });
+        st.charge_current = ElectricCurrent::new::<ampere>(charge_current);
+        st.array_current = ElectricCurrent::new::<ampere>(array_current);
+        st.load_current = ElectricCurrent::new::<ampere>(load_current);
+        st.battery_current_net = ElectricCurrent::new::<ampere>(net);
+        st.battery_sense_voltage = ElectricPotential::new::<volt>(battery_voltage);
+        st.meterbus_voltage = ElectricPotential::new::<volt>(12.0);
+        st.heatsink_temperature =
+            ThermodynamicTemperature::new::<degree_celsius>(heatsink);
+        st.battery_temperature =
+            ThermodynamicTemperature::new::<degree_celsius>(ambient - 2.);
+        st.ambient_temperature = ThermodynamicTemperature::new::<degree_celsius>(ambient);
+        st.rts_temperature = None;
+        st.u_inductor_temperature =
+            ThermodynamicTemperature::new::<degree_celsius>(heatsink + 2.);
+        st.v_inductor_temperature =
+            ThermodynamicTemperature::new::<degree_celsius>(heatsink + 2.);
+        st.w_inductor_temperature =
+            ThermodynamicTemperature::new::<degree_celsius>(heatsink + 2.);
+        st.charge_state = charge_state;
+        st.battery_voltage_slow = ElectricPotential::new::<volt>(battery_voltage);
+        st.target_voltage = ElectricPotential::new::<volt>(target);
+        st.ah_charge_resettable =
+            ElectricCharge::new::<ampere_hour>(self.ah_charge_resettable);
+        st.ah_charge_total = ElectricCharge::new::<ampere_hour>(self.ah_charge_total);
+        st.kwh_charge_resettable =
+            Energy::new::<kilowatt_hour>(self.kwh_charge_resettable);
+        st.kwh_charge_total = Energy::new::<kilowatt_hour>(self.kwh_charge_total);
+        st.load_state = if self.lvd {
+            ps::LoadState::LVD
+        } else if self.load {
+            ps::LoadState::Normal
+        } else {
+            ps::LoadState::Disconnect
+        };
+        st.lvd_setpoint = self.settings.load_low_voltage_disconnect;
+        st.ah_load_resettable =
+            ElectricCharge::new::<ampere_hour>(self.ah_load_resettable);
+        st.ah_load_total = ElectricCharge::new::<ampere_hour>(self.ah_load_total);
+        st.hourmeter = Time::new::<hour>(self.hours);
+        st.array_power = Power::new::<watt>(array_power);
+        st.array_vmp = ElectricPotential::new::<volt>(ARRAY_VMP);
+        st.array_max_power_sweep = Power::new::<watt>(sun * ARRAY_WATTS);
+        st.array_voc = ElectricPotential::new::<volt>(ARRAY_VOC);
+        st.battery_v_min_daily = ElectricPotential::new::<volt>(self.battery_v_min_daily);
+        st.battery_v_max_daily = ElectricPotential::new::<volt>(self.battery_v_max_daily);
+        st.ah_charge_daily = ElectricCharge::new::<ampere_hour>(self.ah_charge_daily);
+        st.ah_load_daily = ElectricCharge::new::<ampere_hour>(self.ah_load_daily);
+        st.array_voltage_max_daily =
+            ElectricPotential::new::<volt>(self.array_voltage_max_daily);
+        st.array_voltage_fixed = self.settings.mppt_fixed_vmp;
+        st.array_voc_percent_fixed = self.settings.mppt_fixed_vmp_percent;
+        Ok(st)
+    }
+
+    pub async fn read_settings(&mut self) -> Result<ps::Settings> {
+        Ok(self.settings)
+    }
+
+    pub async fn write_settings(&mut self, settings: &ps::Settings) -> Result<()> {
+        self.settings = *settings;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Controller for Connection {
+    fn model(&self) -> Model {
+        Model::ProstarMppt
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            charging_control: true,
+            load_control: true,
+            reset: true,
+            settings: true,
+            register_access: false,
+        }
+    }
+
+    async fn read_stats(&mut self) -> Result<ControllerStats> {
+        Ok(ControllerStats::ProstarMppt(Connection::read_stats(self).await?))
+    }
+
+    async fn read_settings(&mut self) -> Result<ControllerSettings> {
+        Ok(ControllerSettings::ProstarMppt(Connection::read_settings(self).await?))
+    }
+
+    async fn write_settings(&mut self, settings: &ControllerSettings) -> Result<()> {
+        match settings {
+            ControllerSettings::ProstarMppt(s) => {
+                Connection::write_settings(self, s).await
+            }
+        }
+    }
+
+    async fn write_coil(&mut self, coil: Coil, bit: bool) -> Result<()> {
+        Connection::write_coil(self, prostar_coil(coil), bit).await
+    }
+}
@@ -0,0 +1,125 @@
+//! Start the daemon against the simulated controller, read its stats
+//! and command it over the control socket.
+use morningstar::prostar_mppt as ps;
+use solar_client::{
+    Config, ControllerStats, FromClient, Stats, ToClient, DEFAULT_CONTROLLER,
+};
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use uom::si::electric_potential::volt;
+
+static STARTUP: Duration = Duration::from_secs(10);
+
+struct Daemon {
+    child: Child,
+    dir: PathBuf,
+    config: Config,
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Daemon {
+    fn start(name: &str) -> Daemon {
+        let dir = env::temp_dir().join(format!("solar-sim-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let cfg = serde_json::json!({
+            "device": "sim:",
+            "run_directory": dir,
+            "archive_directory": dir.join("archive"),
+            "stats_interval": 1,
+            "log_level": "Info",
+            "netidx_base": format!("/solar-test-{}", process::id()),
+            "netidx_bind": "local",
+        });
+        let path = dir.join("solar.conf");
+        fs::write(&path, serde_json::to_vec_pretty(&cfg).unwrap()).unwrap();
+        let config = serde_json::from_value(cfg).unwrap();
+        let child = Command::new(env!("CARGO_BIN_EXE_solar"))
+            .arg("-c")
+            .arg(&path)
+            .arg("start")
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start the daemon");
+        let mut daemon = Daemon { child, dir, config };
+        daemon.wait_for_socket();
+        daemon
+    }
+
+    fn wait_for_socket(&mut self) {
+        let start = Instant::now();
+        while !self.config.control_socket().exists() {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("the daemon exited during startup {}", status)
+            }
+            if start.elapsed() > STARTUP {
+                panic!("the daemon didn't open its control socket")
+            }
+            thread::sleep(Duration::from_millis(100))
+        }
+    }
+
+    fn tail(&self) -> impl Iterator<Item = Stats> {
+        solar_client::send_query(&self.config, FromClient::TailStats)
+            .expect("failed to tail stats")
+            .map(|m| match m {
+                ToClient::Stats(s) => s,
+                m => panic!("unexpected response {:?}", m),
+            })
+    }
+}
+
+// the daemon publishes to netidx, without a resolver it can't start
+fn have_resolver() -> bool {
+    let ok = netidx::config::Config::load_default().is_ok();
+    if !ok {
+        eprintln!("no netidx resolver is configured, skipping")
+    }
+    ok
+}
+
+fn prostar(s: &Stats) -> ps::Stats {
+    match s.controller() {
+        Some(ControllerStats::ProstarMppt(s)) => s,
+        None => panic!("the simulated controller wasn't read"),
+    }
+}
+
+#[test]
+fn stats_and_commands() {
+    if !have_resolver() {
+        return;
+    }
+    let mut daemon = Daemon::start("commands");
+    let s = daemon.tail().next().expect("no stats");
+    assert_eq!(s.name(), DEFAULT_CONTROLLER);
+    let v = prostar(&s).battery_terminal_voltage.get::<volt>();
+    assert!(v > 10. && v < 16., "implausible battery voltage {}", v);
+    solar_client::send_command(&daemon.config, Some(FromClient::set_load(false)))
+        .expect("failed to turn off the load");
+    // the command is applied before the reply, give it a few ticks anyway
+    let off = daemon
+        .tail()
+        .take(3)
+        .any(|s| matches!(prostar(&s).load_state, ps::LoadState::Disconnect));
+    assert!(off, "the load didn't turn off");
+    solar_client::send_command(&daemon.config, Some(FromClient::Stop))
+        .expect("failed to stop the daemon");
+    let start = Instant::now();
+    while daemon.child.try_wait().unwrap().is_none() {
+        assert!(start.elapsed() < STARTUP, "the daemon didn't stop");
+        thread::sleep(Duration::from_millis(100))
+    }
+}